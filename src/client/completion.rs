@@ -0,0 +1,229 @@
+use crate::common::message::Presence;
+
+use super::{
+    editor::Editor,
+    parser::{self, COMMAND_PREFIX, ROOM_PREFIX, SENDER_DELIMITER},
+};
+
+/// What kind of word is being completed, decided by its position in the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Context {
+    Command,
+    Receiver,
+    User,
+    Room
+}
+
+fn context(before: &str, token: &str) -> Context {
+    let position = before
+        .split_whitespace()
+        .filter(|word| *word != parser::UDP_MODIFIER)
+        .count();
+
+    let command = before
+        .split_whitespace()
+        .next()
+        .and_then(|word| word.strip_prefix(COMMAND_PREFIX));
+
+    match (command, position) {
+        (None, 0) if token.starts_with(COMMAND_PREFIX) => Context::Command,
+        (None, 0) => Context::Receiver,
+        _ if token.starts_with(ROOM_PREFIX) => Context::Room,
+        (Some(parser::JOIN_COMMAND | parser::LEAVE_COMMAND), 1) => Context::Room,
+        _ => Context::User
+    }
+}
+
+fn is_subsequence(needle: &str, haystack: &str) -> bool {
+    let mut haystack = haystack.chars();
+    needle.chars().all(|ch| haystack.any(|other| other == ch))
+}
+
+/// Prefix matches first; when there are none, falls back to
+/// subsequence matches so that small typos still find a candidate.
+fn matching(token: &str, options: Vec<String>) -> Vec<String> {
+    let token = token.to_lowercase();
+
+    let mut prefixed = options
+        .iter()
+        .filter(|option| option.to_lowercase().starts_with(&token))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if prefixed.is_empty() {
+        prefixed = options
+            .into_iter()
+            .filter(|option| is_subsequence(&token, &option.to_lowercase()))
+            .collect();
+    }
+
+    prefixed.sort();
+    prefixed.dedup();
+    prefixed
+}
+
+fn candidates(context: Context, token: &str, presence: &Presence, name: &str) -> Vec<String> {
+    let users = || presence.users
+        .iter()
+        .filter(|user| user.name != name)
+        .map(|user| user.name.clone());
+
+    let rooms = || presence.rooms
+        .iter()
+        .map(|room| room.name.clone());
+
+    let options = match context {
+        Context::Command => parser::SLASH_COMMANDS
+            .iter()
+            .map(|command| format!("{COMMAND_PREFIX}{command}"))
+            .collect(),
+        Context::Receiver => users()
+            .chain(rooms())
+            .chain([parser::BROADCAST_NAME.to_owned()])
+            .collect(),
+        Context::User => users().collect(),
+        Context::Room => rooms().collect()
+    };
+
+    matching(token, options)
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    start: usize,
+    end: usize,
+    candidates: Vec<String>,
+    index: usize,
+    suffix: String
+}
+
+impl Completion {
+    /// Starts a completion for the word under the cursor, or `None` if nothing matches.
+    pub fn start(editor: &mut Editor, presence: &Presence, name: &str) -> Option<Self> {
+        let (start, token) = editor.word_before_cursor();
+        let context = context(&editor.text()[..start], token);
+        let candidates = candidates(context, token, presence, name);
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let suffix = match (context, editor.text()[editor.cursor()..].is_empty()) {
+            (Context::Receiver, _) => SENDER_DELIMITER.to_string(),
+            (_, true) => String::from(" "),
+            (_, false) => String::new()
+        };
+
+        let end = editor.cursor();
+        let mut completion = Completion { start, end, candidates, index: 0, suffix };
+        completion.apply(editor);
+
+        Some(completion)
+    }
+
+    fn apply(&mut self, editor: &mut Editor) {
+        let replacement = format!("{}{}", self.candidates[self.index], self.suffix);
+        editor.replace(self.start, self.end, &replacement);
+        self.end = self.start + replacement.len();
+    }
+
+    /// Whether the editor still shows what this completion last inserted.
+    pub fn is_current(&self, editor: &Editor) -> bool {
+        editor.cursor() == self.end
+    }
+
+    pub fn cycle(&mut self, editor: &mut Editor, forward: bool) {
+        let count = self.candidates.len();
+
+        self.index = match forward {
+            true => (self.index + 1) % count,
+            false => (self.index + count - 1) % count
+        };
+
+        self.apply(editor);
+    }
+
+    pub fn hint(&self) -> String {
+        match self.candidates.len() {
+            1 => String::new(),
+            _ => self.candidates.join(" ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::{Room, Status, User};
+
+    use super::*;
+
+    fn presence() -> Presence {
+        let user = |name: &str| User { name: name.to_owned(), status: Status::default() };
+        let room = |name: &str| Room { name: name.to_owned(), members: vec![] };
+
+        Presence {
+            users: vec![user("alice"), user("bob"), user("bobby"), user("carol")],
+            rooms: vec![room("#dev"), room("#ops")]
+        }
+    }
+
+    fn complete(text: &str) -> Option<(Editor, Completion)> {
+        let mut editor = Editor::new(None);
+        editor.set_text(text);
+
+        let completion = Completion::start(&mut editor, &presence(), "alice")?;
+        Some((editor, completion))
+    }
+
+    #[test]
+    fn receivers_cycle_through_the_matches() {
+        let (mut editor, mut completion) = complete("b").unwrap();
+
+        assert_eq!(editor.text(), "bob:");
+        assert_eq!(completion.hint(), "bob bobby");
+        assert!(completion.is_current(&editor));
+
+        completion.cycle(&mut editor, true);
+        assert_eq!(editor.text(), "bobby:");
+        completion.cycle(&mut editor, true);
+        assert_eq!(editor.text(), "bob:");
+        completion.cycle(&mut editor, false);
+        assert_eq!(editor.text(), "bobby:");
+
+        editor.insert(' ');
+        assert!(!completion.is_current(&editor));
+    }
+
+    #[test]
+    fn the_position_decides_what_is_completed() {
+        let text = |input: &str| complete(input).map(|(editor, _)| editor.text().to_owned());
+
+        assert_eq!(text("/jo").as_deref(), Some("/join "));
+        assert_eq!(text("/join d").as_deref(), Some("/join #dev "));
+        assert_eq!(text("/send udp bobb").as_deref(), Some("/send udp bobby "));
+        assert_eq!(text("bob: ask #o").as_deref(), Some("bob: ask #ops "));
+        assert_eq!(text("a").as_deref(), Some("all:"));
+    }
+
+    #[test]
+    fn typos_fall_back_to_subsequences_but_not_to_yourself() {
+        let (editor, completion) = complete("bob: ask crl").unwrap();
+
+        assert_eq!(editor.text(), "bob: ask carol ");
+        assert_eq!(completion.hint(), "");
+        assert!(complete("/mute ali").is_none());
+    }
+
+    #[test]
+    fn nothing_is_appended_in_the_middle_of_the_line() {
+        let mut editor = Editor::new(None);
+        editor.set_text("bob: hi car how are you");
+        editor.word_left();
+        editor.word_left();
+        editor.word_left();
+        editor.left();
+
+        Completion::start(&mut editor, &presence(), "alice").unwrap();
+        assert_eq!(editor.text(), "bob: hi carol how are you");
+    }
+}
@@ -1,105 +1,449 @@
-use std::io;
+use std::{io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}, path::Path, time::Duration};
 
 use tokio::{
-    io::BufReader,
     net::{
         TcpStream,
-        UdpSocket
+        UdpSocket,
+        UnixStream
     },
     sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    time::interval,
 };
 
 use crate::common::{
+    codec::Encoding,
     config::Config,
     message::{Protocol, Request, Response},
     communication::*
 };
 
+use super::{
+    parser::Command,
+    transfer::{Outcome, Progress, Transfers}
+};
+
+const TRANSFER_TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    Response(Response),
+    Progress(Progress),
+    Notice(String),
+    Failure(String)
+}
+
+/// The client's UDP side: a socket connected to the server, and the token its datagrams start with.
+struct Udp {
+    socket: UdpSocket,
+    token: String
+}
+
 async fn login<'a>(
-    mut reader: Reader<'a>, 
+    mut reader: Reader<'a>,
     mut writer: Writer<'a>,
     udp: UdpSocket,
-    name: &str
-) -> io::Result<(Reader<'a>, Writer<'a>, UdpSocket)> {
+    name: &str,
+    token: Option<&str>,
+    codec: Encoding
+) -> io::Result<(Reader<'a>, Writer<'a>, Udp)> {
+    offer_encoding(&mut reader, &mut writer, codec).await?;
+
     let local_udp = udp.local_addr()?;
-    let request = Request::SignIn { name: name.to_owned(), udp: local_udp };
+    let name = name.to_owned();
+    let request = match token {
+        Some(token) => Request::SignInBot { name, udp: local_udp, token: token.to_owned() },
+        None => Request::SignIn { name, udp: local_udp }
+    };
 
     send_tcp(&mut writer, request).await?;
 
     let response = receive_tcp(&mut reader).await?;
 
     match response {
-        Response::Ok(server_udp) => {
-            udp.connect(server_udp).await?;
-            Ok((reader, writer, udp))
+        Response::Session { udp: server_udp, token } if token.len() == SESSION_TOKEN_LENGTH => {
+            // Over a Unix socket the family of the server's UDP address is not known beforehand.
+            let socket = match udp.local_addr()?.is_ipv4() == server_udp.is_ipv4() {
+                true => udp,
+                false => UdpSocket::bind((unspecified(server_udp.ip()), 0)).await?
+            };
+
+            socket.connect(server_udp).await?;
+            open_udp(&socket, &token).await?;
+
+            Ok((reader, writer, Udp { socket, token }))
         },
         Response::Error(err) => Err(io::Error::new(
-            io::ErrorKind::ConnectionAborted, 
+            io::ErrorKind::ConnectionAborted,
             err
         )),
-        Response::Message(_) => Err(io::Error::new(
+        _ => Err(io::Error::new(
             io::ErrorKind::ConnectionAborted,
             "Invalid server response"
         ))
     }
 }
 
-async fn setup_communication<'a>(
-    stream: &'a mut TcpStream
-) -> io::Result<(Reader<'a>, Writer<'a>, UdpSocket)> {
-    let (reader, writer) = stream.split();
-    let reader = BufReader::new(reader);
+fn unspecified(like: IpAddr) -> IpAddr {
+    match like {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    }
+}
+
+/// Connects over the Unix socket when there is one, and over TCP otherwise. The UDP
+/// socket is bound to the local address of the connection, so that it has the same
+/// address family and the address the server is told about is one it can reach.
+async fn setup_communication(
+    tcp: SocketAddr,
+    unix: Option<&Path>
+) -> io::Result<(Reader<'static>, Writer<'static>, UdpSocket)> {
+    let (reader, writer, local) = match unix {
+        Some(path) => {
+            let (reader, writer) = UnixStream::connect(path).await?.into_split();
+            (Reader::new(reader), Writer::new(writer), IpAddr::V4(Ipv4Addr::LOCALHOST))
+        }
+        None => {
+            let stream = TcpStream::connect(tcp).await?;
+            let local = stream.local_addr()?.ip().to_canonical();
+            let (reader, writer) = stream.into_split();
+
+            (Reader::new(reader), Writer::new(writer), local)
+        }
+    };
 
-    let udp = UdpSocket::bind("0.0.0.0:0").await?;
+    let udp = UdpSocket::bind((local, 0)).await?;
 
     Ok((reader, writer, udp))
 }
 
-type Source = UnboundedReceiver<Request>;
-type Sink = UnboundedSender<Response>;
+async fn send(writer: &mut Writer<'_>, udp: &Udp, request: Request) -> io::Result<()> {
+    match request.get_protocol() {
+        Some(Protocol::Udp) if fits_datagram(writer.encoding(), &request) => {
+            send_udp(&udp.socket, &udp.token, writer.encoding(), request).await
+        }
+        _ => send_tcp(writer, request).await
+    }
+}
+
+fn notify(sink: &Sink, update: Update) -> io::Result<()> {
+    sink.send(update).map_err(|reason| io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        reason
+    ))
+}
+
+async fn apply(
+    writer: &mut Writer<'_>,
+    udp: &Udp,
+    sink: &Sink,
+    outcome: Outcome
+) -> io::Result<()> {
+    for request in outcome.requests {
+        send(writer, udp, request).await?;
+    }
+
+    for progress in outcome.progress {
+        notify(sink, Update::Progress(progress))?;
+    }
+
+    Ok(())
+}
+
+async fn execute(
+    writer: &mut Writer<'_>,
+    udp: &Udp,
+    sink: &Sink,
+    transfers: &mut Transfers,
+    command: Command
+) -> io::Result<()> {
+    let answer = match command {
+        // The offer goes out once the file is hashed.
+        Command::Offer { receiver, path, protocol } => match transfers.offer(&receiver, &path, protocol).await {
+            Ok(()) => return Ok(()),
+            Err(reason) => Err(reason)
+        },
+        Command::Accept { sender, id } => transfers
+            .accept(&sender, id)
+            .await
+            .map(Some),
+        Command::Reject { sender, id } => Ok(transfers.reject(&sender, id)),
+        command => {
+            if let Some(request) = command.into_request() {
+                send(writer, udp, request).await?;
+            }
+
+            return Ok(());
+        }
+    };
+
+    match answer {
+        Ok(Some((request, progress))) => {
+            send(writer, udp, request).await?;
+            notify(sink, Update::Progress(progress))
+        }
+        Ok(None) => notify(sink, Update::Notice(String::from("No such file offer"))),
+        Err(reason) => notify(sink, Update::Notice(format!("File transfer failed: {reason}")))
+    }
+}
+
+async fn receive(
+    writer: &mut Writer<'_>,
+    udp: &Udp,
+    sink: &Sink,
+    transfers: &mut Transfers,
+    response: Response
+) -> io::Result<()> {
+    match response {
+        Response::Transfer { peer, transfer, .. } => match transfers.handle(&peer, transfer).await {
+            Ok(outcome) => apply(writer, udp, sink, outcome).await,
+            Err(reason) => notify(sink, Update::Notice(format!("File transfer failed: {reason}")))
+        },
+        response => notify(sink, Update::Response(response))
+    }
+}
+
+type Source = UnboundedReceiver<Command>;
+type Sink = UnboundedSender<Update>;
 
+/// Runs the connection until the interface closes its channel.
+/// Any failure is also reported to the interface before being returned.
 pub async fn run(
     config: Config,
-    mut source: Source,
+    source: Source,
     sink: Sink
 ) -> io::Result<()> {
-    let Config { tcp, name, .. } = config;
+    let result = serve(config, source, &sink).await;
+
+    if let Err(reason) = &result {
+        let _ignore = sink.send(Update::Failure(reason.to_string()));
+    }
+
+    result
+}
 
-    let mut stream = TcpStream::connect(tcp).await?;
-    let (reader, writer, udp) = setup_communication(&mut stream).await?;
+async fn serve(
+    config: Config,
+    mut source: Source,
+    sink: &Sink
+) -> io::Result<()> {
+    let Config { tcp, unix, name, token, max_file_size, downloads, codec, .. } = config;
+
+    let (reader, writer, udp) = setup_communication(tcp, unix.as_deref()).await?;
     let (mut reader, mut writer, udp) = login(
-        reader, 
-        writer, 
-        udp, 
-        &name
+        reader,
+        writer,
+        udp,
+        &name,
+        token.as_deref(),
+        codec
     ).await?;
 
+    let encoding = reader.encoding();
+
+    let mut transfers = Transfers::new(downloads, max_file_size);
+    let mut tick = interval(TRANSFER_TICK);
+
     loop {
         tokio::select! {
-            request = source.recv() => match request {
+            command = source.recv() => match command {
                 None => break Ok(()),
-                Some(request) => match request {
-                    Request::Send { protocol: Protocol::Udp, .. } => send_udp(&udp, request).await?,
-                    Request::SendAll { protocol: Protocol::Udp, .. } => { /* TODO */ },
-                    other => send_tcp(&mut writer, other).await?
+                Some(command) => execute(&mut writer, &udp, sink, &mut transfers, command).await?
+            },
+
+            response = receive_tcp(&mut reader) => match response {
+                Ok(response) => receive(&mut writer, &udp, sink, &mut transfers, response).await?,
+                Err(reason) if reason.kind() == io::ErrorKind::InvalidData => {
+                    notify(sink, Update::Notice(format!("Malformed server response: {reason}")))?
                 }
+                Err(reason) => break Err(reason)
             },
 
-            Ok(response) = receive_tcp(&mut reader) => {
-                sink.send(response).map_err(|reason| io::Error::new(
-                    io::ErrorKind::BrokenPipe, 
-                    reason
-                ))?;
+            Ok(response) = receive_udp(&udp.socket, encoding) => {
+                receive(&mut writer, &udp, sink, &mut transfers, response).await?;
             },
 
-            Ok(response) = receive_udp(&udp) => {
-                sink.send(response).map_err(|reason| io::Error::new(
-                    io::ErrorKind::BrokenPipe, 
-                    reason
-                ))?;
+            hashed = transfers.hashed() => {
+                match transfers.on_hashed(hashed).await {
+                    Ok(outcome) => apply(&mut writer, &udp, sink, outcome).await?,
+                    Err(reason) => notify(sink, Update::Notice(format!("File transfer failed: {reason}")))?
+                }
+            },
+
+            _ = tick.tick() => {
+                match transfers.pump().await {
+                    Ok(outcome) => apply(&mut writer, &udp, sink, outcome).await?,
+                    Err(reason) => notify(sink, Update::Notice(format!("File transfer failed: {reason}")))?
+                }
             },
 
             else => { }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv6Addr, TcpListener};
+
+    use tokio::{
+        sync::mpsc,
+        time::{sleep, timeout},
+    };
+
+    use crate::{
+        common::{config::Mode, message::{Message, SERVER_NAME}},
+        server
+    };
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Starts a server on a free port of `ip` and returns the address clients connect to.
+    async fn start_server(ip: IpAddr) -> SocketAddr {
+        let port = TcpListener::bind((ip, 0)).unwrap().local_addr().unwrap().port();
+        let config = Config::new(Mode::Server, SocketAddr::new(ip, port), String::from("server"));
+
+        tokio::spawn(server::run(config));
+
+        SocketAddr::new(ip, port)
+    }
+
+    async fn listening(server: SocketAddr) {
+        for _ in 0..50 {
+            if TcpStream::connect(server).await.is_ok() {
+                return;
+            }
+
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn connect(server: SocketAddr, name: &str) -> (mpsc::UnboundedSender<Command>, mpsc::UnboundedReceiver<Update>) {
+        listening(server).await;
+
+        let (commands, source) = mpsc::unbounded_channel();
+        let (sink, updates) = mpsc::unbounded_channel();
+
+        tokio::spawn(run(Config::new(Mode::Client, server, name.to_owned()), source, sink));
+
+        (commands, updates)
+    }
+
+    async fn expect<T>(updates: &mut mpsc::UnboundedReceiver<Update>, mut matches: impl FnMut(Update) -> Option<T>) -> T {
+        let found = timeout(TIMEOUT, async {
+            loop {
+                match updates.recv().await.expect("driver stopped") {
+                    Update::Failure(reason) => panic!("connection failed: {reason}"),
+                    update => if let Some(found) = matches(update) {
+                        return found;
+                    }
+                }
+            }
+        });
+
+        found.await.expect("timed out")
+    }
+
+    async fn online(updates: &mut mpsc::UnboundedReceiver<Update>, name: &str) {
+        expect(updates, |update| match update {
+            Update::Response(Response::Presence(presence)) if presence.users.iter().any(|user| user.name == name) => Some(()),
+            _ => None
+        }).await
+    }
+
+    async fn message(updates: &mut mpsc::UnboundedReceiver<Update>, sender: &str) -> Message {
+        expect(updates, |update| match update {
+            Update::Response(Response::Message(message)) if message.get_sender() == sender => Some(message),
+            _ => None
+        }).await
+    }
+
+    fn send(commands: &mpsc::UnboundedSender<Command>, receiver: &str, message: &str, protocol: Protocol) {
+        let command = Command::Send { message: message.to_owned(), receiver: receiver.to_owned(), protocol, reply_to: None };
+        commands.send(command).unwrap();
+    }
+
+    /// Sends over TCP and UDP between two clients, in both directions.
+    async fn exchange(first: SocketAddr, second: SocketAddr) {
+        let (ana, mut ana_updates) = connect(first, "ana").await;
+        let (bob, mut bob_updates) = connect(second, "bob").await;
+
+        online(&mut ana_updates, "bob").await;
+        online(&mut bob_updates, "ana").await;
+
+        send(&ana, "bob", "over tcp", Protocol::Tcp);
+        let received = message(&mut bob_updates, "ana").await;
+        assert_eq!((received.get_message(), received.protocol), ("over tcp", Protocol::Tcp));
+
+        send(&bob, "all", "over udp", Protocol::Udp);
+        let received = message(&mut ana_updates, "bob").await;
+        assert_eq!((received.get_message(), received.protocol), ("over udp", Protocol::Udp));
+
+        send(&ana, "bob", "back over udp", Protocol::Udp);
+        let received = message(&mut bob_updates, "ana").await;
+        assert_eq!((received.get_message(), received.protocol), ("back over udp", Protocol::Udp));
+    }
+
+    #[tokio::test]
+    async fn messages_flow_over_ipv6_loopback() {
+        let server = start_server(IpAddr::V6(Ipv6Addr::LOCALHOST)).await;
+
+        exchange(server, server).await;
+    }
+
+    /// On the same host UDP would get through over IPv4 even for an IPv6 connection,
+    /// so the messages alone do not show which family was used.
+    #[tokio::test]
+    async fn udp_uses_the_address_family_of_the_connection() {
+        let server = start_server(IpAddr::V6(Ipv6Addr::UNSPECIFIED)).await;
+        listening(server).await;
+
+        for (ip, name) in [(IpAddr::V4(Ipv4Addr::LOCALHOST), "ana"), (IpAddr::V6(Ipv6Addr::LOCALHOST), "bob")] {
+            let (reader, writer, udp) = setup_communication(SocketAddr::new(ip, server.port()), None).await.unwrap();
+            let (_reader, _writer, udp) = login(reader, writer, udp, name, None, Encoding::default()).await.unwrap();
+
+            assert_eq!(udp.socket.local_addr().unwrap().ip(), ip);
+            assert_eq!(udp.socket.peer_addr().unwrap(), SocketAddr::new(ip, server.port()));
+        }
+    }
+
+    /// Datagrams without a valid session token are dropped, wherever they come from.
+    #[tokio::test]
+    async fn udp_without_a_session_token_is_dropped() {
+        let server = start_server(IpAddr::V6(Ipv6Addr::LOCALHOST)).await;
+
+        let (ana, mut ana_updates) = connect(server, "ana").await;
+        let (_bob, mut bob_updates) = connect(server, "bob").await;
+
+        online(&mut ana_updates, "bob").await;
+        online(&mut bob_updates, "ana").await;
+
+        let spoofer = UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        let request = Request::SendAll { message: String::from("spoofed"), protocol: Protocol::Udp, reply_to: None };
+        let frame = Encoding::default().encode(&request).unwrap();
+
+        for token in [String::new(), "0".repeat(SESSION_TOKEN_LENGTH)] {
+            spoofer.send_to(&[token.as_bytes(), &frame].concat(), server).await.unwrap();
+        }
+
+        send(&ana, "all", "genuine", Protocol::Udp);
+
+        let received = expect(&mut bob_updates, |update| match update {
+            Update::Response(Response::Message(message)) if message.get_sender() != SERVER_NAME => Some(message),
+            _ => None
+        }).await;
+
+        assert_eq!((received.get_sender(), received.get_message()), ("ana", "genuine"));
+    }
+
+    #[tokio::test]
+    async fn dual_stack_server_takes_ipv4_and_ipv6_clients() {
+        let server = start_server(IpAddr::V6(Ipv6Addr::UNSPECIFIED)).await;
+
+        let ipv4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), server.port());
+        let ipv6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), server.port());
+
+        exchange(ipv4, ipv6).await;
+    }
+}
@@ -0,0 +1,459 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const MAX_HISTORY: usize = 500;
+
+fn escape(line: &str) -> String {
+    line.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            result.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\')
+        }
+    }
+
+    result
+}
+
+/// Single input line with a grapheme-aware cursor and recall of previously submitted lines.
+/// History is appended to `history_file` so that it survives between sessions.
+#[derive(Debug, Default)]
+pub struct Editor {
+    text: String,
+    cursor: usize,
+    history: Vec<String>,
+    recall: Option<usize>,
+    draft: String,
+    history_file: Option<PathBuf>,
+}
+
+impl Editor {
+    pub fn new(history_file: Option<PathBuf>) -> Self {
+        let history = history_file
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| content
+                .lines()
+                .map(unescape)
+                .collect::<Vec<_>>()
+            )
+            .unwrap_or_default();
+
+        let skip = history.len().saturating_sub(MAX_HISTORY);
+        let history = history.into_iter().skip(skip).collect::<Vec<_>>();
+
+        if skip > 0 {
+            if let Some(path) = &history_file {
+                let content = history
+                    .iter()
+                    .map(|line| escape(line) + "\n")
+                    .collect::<String>();
+
+                let _ignore = fs::write(path, content);
+            }
+        }
+
+        Editor { history, history_file, ..Default::default() }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Returns the start and contents of the word that ends at the cursor.
+    pub fn word_before_cursor(&self) -> (usize, &str) {
+        let before = &self.text[..self.cursor];
+        let start = before
+            .rfind(char::is_whitespace)
+            .map(|index| index + before[index..].chars().next().unwrap().len_utf8())
+            .unwrap_or(0);
+
+        (start, &self.text[start..self.cursor])
+    }
+
+    pub fn replace(&mut self, start: usize, end: usize, text: &str) {
+        self.text.replace_range(start..end, text);
+        self.cursor = start + text.len();
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.to_owned();
+        self.cursor = self.text.len();
+    }
+
+    pub fn insert(&mut self, ch: char) {
+        self.text.insert(self.cursor, ch);
+        self.cursor += ch.len_utf8();
+    }
+
+    pub fn insert_str(&mut self, text: &str) {
+        self.text.insert_str(self.cursor, text);
+        self.cursor += text.len();
+    }
+
+    fn previous_boundary(&self) -> usize {
+        self.text[..self.cursor]
+            .grapheme_indices(true)
+            .next_back()
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+
+    fn next_boundary(&self) -> usize {
+        self.text[self.cursor..]
+            .graphemes(true)
+            .next()
+            .map(|grapheme| self.cursor + grapheme.len())
+            .unwrap_or(self.text.len())
+    }
+
+    fn previous_word(&self) -> usize {
+        let mut boundary = self.cursor;
+        let mut seen_word = false;
+
+        for (index, grapheme) in self.text[..self.cursor].grapheme_indices(true).rev() {
+            let blank = grapheme.trim().is_empty();
+
+            if blank && seen_word {
+                break;
+            }
+
+            seen_word |= !blank;
+            boundary = index;
+        }
+
+        boundary
+    }
+
+    fn next_word(&self) -> usize {
+        let mut seen_blank = false;
+
+        for (index, grapheme) in self.text[self.cursor..].grapheme_indices(true) {
+            let blank = grapheme.trim().is_empty();
+
+            if !blank && seen_blank {
+                return self.cursor + index;
+            }
+
+            seen_blank |= blank;
+        }
+
+        self.text.len()
+    }
+
+    pub fn backspace(&mut self) {
+        let start = self.previous_boundary();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete(&mut self) {
+        let end = self.next_boundary();
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    pub fn delete_word(&mut self) {
+        let start = self.previous_word();
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.previous_boundary();
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = self.next_boundary();
+    }
+
+    pub fn word_left(&mut self) {
+        self.cursor = self.previous_word();
+    }
+
+    pub fn word_right(&mut self) {
+        self.cursor = self.next_word();
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+
+    pub fn previous(&mut self) {
+        let index = match self.recall {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.text.clone();
+                self.history.len() - 1
+            }
+            Some(index) => index.saturating_sub(1)
+        };
+
+        self.recall = Some(index);
+        let line = self.history[index].clone();
+        self.set_text(&line);
+    }
+
+    pub fn next(&mut self) {
+        let Some(index) = self.recall else {
+            return;
+        };
+
+        if index + 1 < self.history.len() {
+            self.recall = Some(index + 1);
+            let line = self.history[index + 1].clone();
+            self.set_text(&line);
+        } else {
+            self.recall = None;
+            let draft = std::mem::take(&mut self.draft);
+            self.set_text(&draft);
+        }
+    }
+
+    /// Takes the current line out of the editor and records it in the history.
+    pub fn submit(&mut self) -> String {
+        let line = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.recall = None;
+        self.draft.clear();
+
+        if line.trim().is_empty() || self.history.last() == Some(&line) {
+            return line;
+        }
+
+        self.history.push(line.clone());
+
+        if self.history.len() > MAX_HISTORY {
+            self.history.remove(0);
+        }
+
+        if let Some(path) = &self.history_file {
+            let _ignore = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", escape(&line)));
+        }
+
+        line
+    }
+
+    /// Breaks the text into rows no wider than `width` columns
+    /// and returns them together with the (column, row) of the cursor.
+    pub fn layout(&self, width: u16) -> (Vec<String>, (u16, u16)) {
+        let width = width.max(1) as usize;
+
+        let mut rows = vec![String::new()];
+        let mut row_width = 0;
+        let mut cursor = None;
+
+        for (index, grapheme) in self.text.grapheme_indices(true) {
+            if index == self.cursor {
+                cursor = Some((row_width, rows.len() - 1));
+            }
+
+            if grapheme == "\n" || grapheme == "\r\n" {
+                rows.push(String::new());
+                row_width = 0;
+                continue;
+            }
+
+            let grapheme_width = grapheme.width();
+
+            if row_width + grapheme_width > width && row_width > 0 {
+                rows.push(String::new());
+                row_width = 0;
+
+                if index == self.cursor {
+                    cursor = Some((0, rows.len() - 1));
+                }
+            }
+
+            rows.last_mut().unwrap().push_str(grapheme);
+            row_width += grapheme_width;
+        }
+
+        let (column, row) = cursor.unwrap_or_else(|| match row_width >= width {
+            true => {
+                rows.push(String::new());
+                (0, rows.len() - 1)
+            }
+            false => (row_width, rows.len() - 1)
+        });
+
+        (rows, (column as u16, row as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_file(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chat-history-{test}-{}", std::process::id()))
+    }
+
+    fn submit(editor: &mut Editor, line: &str) -> String {
+        editor.set_text(line);
+        editor.submit()
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        for line in ["plain", "two\nlines", "back\\slash", "ends with \\", "\\n"] {
+            assert_eq!(unescape(&escape(line)), line);
+        }
+
+        assert_eq!(escape("a\nb"), "a\\nb");
+        assert_eq!(escape("a\nb").lines().count(), 1);
+        assert_eq!(unescape("trailing\\"), "trailing\\");
+    }
+
+    #[test]
+    fn history_is_loaded_from_the_file() {
+        let path = history_file("load");
+        fs::write(&path, "first\nsecond\\nline\n").unwrap();
+
+        let mut editor = Editor::new(Some(path.clone()));
+        editor.previous();
+        assert_eq!(editor.text, "second\nline");
+        editor.previous();
+        assert_eq!(editor.text, "first");
+        editor.previous();
+        assert_eq!(editor.text, "first");
+
+        let _ignore = fs::remove_file(path);
+    }
+
+    #[test]
+    fn submitted_lines_are_appended_to_the_file() {
+        let path = history_file("save");
+        let _ignore = fs::remove_file(&path);
+
+        let mut editor = Editor::new(Some(path.clone()));
+        submit(&mut editor, "hello");
+        submit(&mut editor, "two\nlines");
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "hello\ntwo\\nlines\n");
+
+        let mut editor = Editor::new(Some(path.clone()));
+        editor.previous();
+        assert_eq!(editor.text, "two\nlines");
+
+        let _ignore = fs::remove_file(path);
+    }
+
+    #[test]
+    fn repeated_and_blank_lines_are_not_recorded() {
+        let mut editor = Editor::new(None);
+
+        for line in ["hello", "hello", "  ", "", "world", "hello"] {
+            assert_eq!(submit(&mut editor, line), line);
+        }
+
+        assert_eq!(editor.history, ["hello", "world", "hello"]);
+    }
+
+    #[test]
+    fn long_history_files_are_compacted() {
+        let path = history_file("compact");
+        let content = (0..=MAX_HISTORY).map(|index| format!("line {index}\n")).collect::<String>();
+        fs::write(&path, content).unwrap();
+
+        let editor = Editor::new(Some(path.clone()));
+        let lines = fs::read_to_string(&path).unwrap();
+
+        assert_eq!(editor.history.len(), MAX_HISTORY);
+        assert_eq!(editor.history[0], "line 1");
+        assert_eq!(lines.lines().count(), MAX_HISTORY);
+        assert_eq!(lines.lines().next(), Some("line 1"));
+
+        let _ignore = fs::remove_file(path);
+    }
+
+    #[test]
+    fn recall_keeps_the_draft() {
+        let mut editor = Editor::new(None);
+        submit(&mut editor, "old");
+
+        editor.set_text("draft");
+        editor.previous();
+        assert_eq!(editor.text, "old");
+        editor.next();
+        assert_eq!(editor.text, "draft");
+        assert_eq!(editor.cursor, "draft".len());
+    }
+
+    #[test]
+    fn the_cursor_moves_over_whole_graphemes() {
+        let mut editor = Editor::new(None);
+        editor.set_text("ne\u{301}e 👍🏽");
+
+        editor.backspace();
+        assert_eq!(editor.text, "ne\u{301}e ");
+        editor.left();
+        editor.left();
+        editor.backspace();
+        assert_eq!(editor.text, "ne ");
+        assert_eq!(editor.cursor, 1);
+        editor.delete();
+        assert_eq!(editor.text, "n ");
+    }
+
+    #[test]
+    fn words_are_separated_by_blanks() {
+        let mut editor = Editor::new(None);
+        editor.set_text("say hello  world");
+
+        editor.word_left();
+        assert_eq!(editor.cursor, 11);
+        editor.word_left();
+        assert_eq!(editor.cursor, 4);
+        editor.word_right();
+        assert_eq!(editor.cursor, 11);
+
+        editor.end();
+        editor.delete_word();
+        assert_eq!(editor.text, "say hello  ");
+    }
+
+    #[test]
+    fn layout_wraps_at_the_width() {
+        let mut editor = Editor::new(None);
+        editor.set_text("abcdef\ngh");
+
+        assert_eq!(editor.layout(4), (vec![String::from("abcd"), String::from("ef"), String::from("gh")], (2, 2)));
+
+        editor.set_text("abcd");
+        assert_eq!(editor.layout(4), (vec![String::from("abcd"), String::new()], (0, 1)));
+
+        editor.home();
+        editor.right();
+        assert_eq!(editor.layout(4).1, (1, 0));
+    }
+}
@@ -0,0 +1,365 @@
+use ratatui::{
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+const FENCE: &str = "```";
+const TAB: &str = "    ";
+const URL_SCHEMES: &[&str] = &["https://", "http://"];
+const URL_TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']', '\'', '"'];
+
+const SENDER_PALETTE: &[Color] = &[
+    Color::Red,
+    Color::Green,
+    Color::Yellow,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::LightBlue,
+    Color::LightMagenta,
+    Color::LightCyan,
+];
+
+pub fn code_style() -> Style {
+    Style::default().fg(Color::LightGreen).bg(Color::Black)
+}
+
+pub fn link_style() -> Style {
+    Style::default().fg(Color::LightBlue).add_modifier(Modifier::UNDERLINED)
+}
+
+/// Picks a color from the name alone (FNV-1a), so a sender looks the same in every session.
+pub fn sender_color(name: &str) -> Color {
+    let hash = name.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+
+    SENDER_PALETTE[(hash % SENDER_PALETTE.len() as u64) as usize]
+}
+
+/// Length of the URL at the start of `text`, without trailing punctuation.
+pub fn url_length(text: &str) -> Option<usize> {
+    if !URL_SCHEMES.iter().any(|scheme| text.starts_with(scheme)) {
+        return None;
+    }
+
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    let url = text[..end].trim_end_matches(URL_TRAILING_PUNCTUATION);
+
+    match URL_SCHEMES.iter().any(|scheme| url.len() > scheme.len() && url.starts_with(scheme)) {
+        true => Some(url.len()),
+        false => None
+    }
+}
+
+fn is_word_char(ch: Option<char>) -> bool {
+    ch.is_some_and(char::is_alphanumeric)
+}
+
+/// Builds spans for a single line of `**bold**`, `_italic_`, `` `code` `` and URLs.
+/// Markers without a closing counterpart are shown as typed.
+fn inline<'a>(line: &str, base: Style) -> Vec<Span<'a>> {
+    let mut spans = Vec::new();
+    let mut plain = String::new();
+    let mut bold = false;
+    let mut italic = false;
+    let mut previous = None;
+    let mut index = 0;
+
+    let style = |bold: bool, italic: bool| {
+        let mut style = base;
+
+        if bold {
+            style = style.add_modifier(Modifier::BOLD);
+        }
+
+        if italic {
+            style = style.add_modifier(Modifier::ITALIC);
+        }
+
+        style
+    };
+
+    let flush = |plain: &mut String, spans: &mut Vec<Span<'a>>, style: Style| {
+        if !plain.is_empty() {
+            spans.push(Span::styled(std::mem::take(plain), style));
+        }
+    };
+
+    while index < line.len() {
+        let rest = &line[index..];
+        let next = rest.chars().nth(1);
+
+        if let Some(end) = rest.strip_prefix('`').and_then(|code| code.find('`')) {
+            flush(&mut plain, &mut spans, style(bold, italic));
+            spans.push(Span::styled(rest[1..end + 1].to_owned(), code_style()));
+            index += end + 2;
+            previous = Some('`');
+            continue;
+        }
+
+        if rest.starts_with("**") && (bold || rest[2..].contains("**")) {
+            flush(&mut plain, &mut spans, style(bold, italic));
+            bold = !bold;
+            index += 2;
+            continue;
+        }
+
+        if let Some(after) = rest.strip_prefix('_') {
+            let opens = !italic
+                && !is_word_char(previous)
+                && next.is_some_and(|ch| !ch.is_whitespace())
+                && after.contains('_');
+            let closes = italic && !is_word_char(next);
+
+            if opens || closes {
+                flush(&mut plain, &mut spans, style(bold, italic));
+                italic = !italic;
+                index += 1;
+                continue;
+            }
+        }
+
+        if let Some(length) = url_length(rest).filter(|_| !is_word_char(previous)) {
+            flush(&mut plain, &mut spans, style(bold, italic));
+            spans.push(Span::styled(rest[..length].to_owned(), link_style()));
+            index += length;
+            previous = rest[..length].chars().next_back();
+            continue;
+        }
+
+        let ch = rest.chars().next().unwrap_or_default();
+        plain.push(ch);
+        index += ch.len_utf8();
+        previous = Some(ch);
+    }
+
+    flush(&mut plain, &mut spans, style(bold, italic));
+    spans
+}
+
+/// Splits message text into rendered rows. Lines between ``` fences are shown verbatim as code.
+/// Tabs are expanded, as the terminal would otherwise give them no width at all.
+pub fn body<'a>(text: &str, base: Style) -> Vec<Vec<Span<'a>>> {
+    let mut rows = Vec::new();
+    let mut fenced = false;
+
+    for line in text.replace('\t', TAB).split('\n') {
+        if line.trim_start().starts_with(FENCE) {
+            fenced = !fenced;
+            continue;
+        }
+
+        match fenced {
+            true => rows.push(vec![Span::styled(line.to_owned(), code_style())]),
+            false => rows.push(inline(line, base))
+        }
+    }
+
+    if rows.is_empty() {
+        rows.push(Vec::new());
+    }
+
+    rows
+}
+
+/// Rows built up by `wrap`, with the width and indentation of the last one.
+struct Rows<'a> {
+    rows: Vec<Vec<Span<'a>>>,
+    width: usize,
+    start: usize,
+}
+
+impl<'a> Rows<'a> {
+    fn break_row(&mut self, indent: usize) {
+        let row = match indent {
+            0 => Vec::new(),
+            indent => vec![Span::raw(" ".repeat(indent))]
+        };
+
+        self.rows.push(row);
+        self.width = indent;
+        self.start = indent;
+    }
+
+    fn push(&mut self, grapheme: &str, style: Style) {
+        let row = self.rows.last_mut().expect("there is always a row");
+
+        match row.last_mut() {
+            Some(span) if span.style == style => span.content.to_mut().push_str(grapheme),
+            _ => row.push(Span::styled(grapheme.to_owned(), style))
+        }
+
+        self.width += grapheme.width();
+    }
+
+    /// Whether anything besides the indentation was put on the last row.
+    fn is_started(&self) -> bool {
+        self.width > self.start
+    }
+}
+
+/// Wraps a line to `width` columns, breaking at spaces where possible.
+/// Continuation rows start `indent` columns in, so that wrapped text stays
+/// aligned with where it began instead of running back to the left edge.
+pub fn wrap<'a>(line: &Line<'_>, width: usize, indent: usize) -> Vec<Line<'a>> {
+    let width = width.max(1);
+    let indent = if indent * 2 > width { 0 } else { indent };
+
+    let mut words: Vec<(bool, Vec<(&str, Style)>)> = Vec::new();
+
+    for span in &line.spans {
+        for grapheme in span.content.graphemes(true) {
+            let blank = grapheme.chars().all(char::is_whitespace);
+
+            match words.last_mut() {
+                Some((kind, word)) if *kind == blank => word.push((grapheme, span.style)),
+                _ => words.push((blank, vec![(grapheme, span.style)]))
+            }
+        }
+    }
+
+    let mut rows = Rows { rows: vec![Vec::new()], width: 0, start: 0 };
+
+    for (blank, word) in words {
+        let word_width = word.iter().map(|(grapheme, _)| grapheme.width()).sum::<usize>();
+
+        if rows.width + word_width > width {
+            if blank {
+                rows.break_row(indent);
+                continue;
+            }
+
+            if rows.is_started() && word_width <= width - indent {
+                rows.break_row(indent);
+            }
+        }
+
+        for (grapheme, style) in word {
+            if rows.width + grapheme.width() > width && rows.is_started() {
+                rows.break_row(indent);
+            }
+
+            rows.push(grapheme, style);
+        }
+    }
+
+    rows.rows.into_iter().map(Line::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text of each span with whether it is bold and whether it is italic.
+    fn styled(line: &str) -> Vec<(String, bool, bool)> {
+        inline(line, Style::default())
+            .into_iter()
+            .map(|span| (
+                span.content.into_owned(),
+                span.style.add_modifier.contains(Modifier::BOLD),
+                span.style.add_modifier.contains(Modifier::ITALIC)
+            ))
+            .collect()
+    }
+
+    fn plain(text: &str) -> (String, bool, bool) {
+        (text.to_owned(), false, false)
+    }
+
+    #[test]
+    fn non_ascii_text_is_kept_as_typed() {
+        for line in ["👍👍", "«quote»", "—hello", "a—_b", "👍_", "_—_"] {
+            let text: String = inline(line, Style::default()).iter().map(|span| span.content.as_ref()).collect();
+            let expected = match line {
+                "_—_" => "—",
+                line => line
+            };
+
+            assert_eq!(text, expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn markers_around_non_ascii_text() {
+        assert_eq!(styled("_«quote»_"), [(String::from("«quote»"), false, true)]);
+        assert_eq!(
+            styled("—**👍**—"),
+            [plain("—"), (String::from("👍"), true, false), plain("—")]
+        );
+    }
+
+    #[test]
+    fn unclosed_markers_are_shown_as_typed() {
+        assert_eq!(styled("**bold"), [plain("**bold")]);
+        assert_eq!(styled("snake_case_name"), [plain("snake_case_name")]);
+        assert_eq!(styled("`code"), [plain("`code")]);
+    }
+
+    #[test]
+    fn code_and_links_are_styled() {
+        let spans = inline("see `a_b` at https://example.com/x.", Style::default());
+
+        assert_eq!(spans[1].content, "a_b");
+        assert_eq!(spans[1].style, code_style());
+        assert_eq!(spans[3].content, "https://example.com/x");
+        assert_eq!(spans[3].style, link_style());
+        assert_eq!(spans[4].content, ".");
+    }
+
+    #[test]
+    fn url_length_drops_trailing_punctuation() {
+        assert_eq!(url_length("https://example.com)."), Some(19));
+        assert_eq!(url_length("https://"), None);
+        assert_eq!(url_length("ftp://example.com"), None);
+    }
+
+    #[test]
+    fn fenced_lines_are_verbatim() {
+        let rows = body("```\n**not bold**\n```\n**bold**", Style::default());
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0][0].content, "**not bold**");
+        assert_eq!(rows[0][0].style, code_style());
+        assert!(rows[1][0].style.add_modifier.contains(Modifier::BOLD));
+    }
+
+    fn wrapped(line: &Line<'_>, width: usize, indent: usize) -> Vec<String> {
+        wrap(line, width, indent)
+            .iter()
+            .map(|row| row.spans.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn wrap_breaks_at_spaces_and_indents() {
+        assert_eq!(wrapped(&Line::from("one two three"), 8, 2), ["one two ", "  three"]);
+        assert_eq!(wrapped(&Line::from("one two three"), 20, 2), ["one two three"]);
+    }
+
+    #[test]
+    fn wrap_splits_words_longer_than_a_row() {
+        assert_eq!(wrapped(&Line::from("a abcdefghij"), 6, 2), ["a abcd", "  efgh", "  ij"]);
+        assert_eq!(wrapped(&Line::from("👍👍👍"), 4, 0), ["👍👍", "👍"]);
+    }
+
+    #[test]
+    fn wrap_drops_an_indent_wider_than_half_the_row() {
+        assert_eq!(wrapped(&Line::from("one two three"), 8, 5), ["one two ", "three"]);
+    }
+
+    #[test]
+    fn wrap_keeps_the_styles() {
+        let line = Line::from(vec![Span::raw("plain "), Span::styled("bold text", Style::default().add_modifier(Modifier::BOLD))]);
+        let rows = wrap(&line, 10, 0);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].spans[1].content, "bold");
+        assert_eq!(rows[1].spans[0].content, "text");
+        assert!(rows[1].spans[0].style.add_modifier.contains(Modifier::BOLD));
+    }
+}
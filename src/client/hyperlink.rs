@@ -0,0 +1,101 @@
+use std::io::{self, Write};
+
+use crossterm::{
+    cursor::MoveTo,
+    queue,
+    style::{Attribute, Print, SetAttribute, SetForegroundColor},
+};
+
+use ratatui::{buffer::Buffer, layout::Rect};
+
+use super::format::link_style;
+
+/// A URL as drawn on screen, possibly wrapped over several rows.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Link {
+    url: String,
+    segments: Vec<(u16, u16, String)>,
+}
+
+fn is_link(buffer: &Buffer, x: u16, y: u16) -> bool {
+    let cell = buffer.get(x, y);
+    let style = link_style();
+
+    style.fg == Some(cell.fg) && cell.modifier.contains(style.add_modifier)
+}
+
+/// Collects the runs of cells drawn with `link_style` inside `area`. A run that reaches
+/// the right edge and continues after the indentation of the next row is the same link.
+pub fn find(buffer: &Buffer, area: Rect) -> Vec<Link> {
+    let mut links = Vec::new();
+    let mut current: Option<Link> = None;
+
+    for y in area.top()..area.bottom() {
+        let mut x = area.left();
+        let mut indentation = true;
+
+        while x < area.right() {
+            if !is_link(buffer, x, y) {
+                indentation &= buffer.get(x, y).symbol() == " ";
+
+                if !indentation {
+                    links.extend(current.take());
+                }
+
+                x += 1;
+                continue;
+            }
+
+            indentation = false;
+
+            let start = x;
+            let mut text = String::new();
+
+            while x < area.right() && is_link(buffer, x, y) {
+                text.push_str(buffer.get(x, y).symbol());
+                x += 1;
+            }
+
+            let link = current.get_or_insert_with(Link::default);
+            link.url.push_str(&text);
+            link.segments.push((start, y, text));
+
+            if x < area.right() {
+                links.extend(current.take());
+            }
+        }
+    }
+
+    links.extend(current);
+    links
+}
+
+/// Redraws the links on top of what ratatui has drawn, wrapped in OSC 8 sequences
+/// so that terminals which support them make the text clickable. The visible
+/// characters are the same, so ratatui's idea of the screen stays correct.
+pub fn emit(out: &mut impl Write, links: &[Link], cursor: Option<(u16, u16)>) -> io::Result<()> {
+    if links.is_empty() {
+        return Ok(());
+    }
+
+    let style = link_style();
+
+    for link in links {
+        for (x, y, text) in &link.segments {
+            queue!(
+                out,
+                MoveTo(*x, *y),
+                SetForegroundColor(style.fg.unwrap_or_default().into()),
+                SetAttribute(Attribute::Underlined),
+                Print(format!("\x1b]8;;{}\x1b\\{text}\x1b]8;;\x1b\\", link.url)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        }
+    }
+
+    if let Some((x, y)) = cursor {
+        queue!(out, MoveTo(x, y))?;
+    }
+
+    out.flush()
+}
@@ -1,19 +1,25 @@
 use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
     io::{self, stdout},
-    time::Duration,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
 
-use tokio::{sync::mpsc::{
-    error::TryRecvError,
-    UnboundedReceiver, UnboundedSender,
-}, time::sleep};
-
-use crossterm::{
-    event::{self, KeyCode},
-    terminal::{
-        disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen
+use tokio::{
+    sync::mpsc::{
+        error::TryRecvError,
+        UnboundedReceiver, UnboundedSender,
     },
-    ExecutableCommand,
+    time::interval,
+};
+
+use futures::{FutureExt, StreamExt};
+
+use chrono::{DateTime, Local};
+
+use crossterm::event::{
+    Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseEventKind
 };
 
 use ratatui::{
@@ -25,181 +31,1166 @@ use ratatui::{
         Rect, 
         Terminal
     },
+    style::{Color, Modifier, Style},
     symbols::border,
-    text::Line,
+    text::{Line, Span},
     widgets::{block::*, *},
 };
 
-use super::parser::Command;
-use crate::common::message::{Request, Response};
+use super::{
+    completion::Completion,
+    driver::Update,
+    editor::Editor,
+    format,
+    hyperlink::{self, Link},
+    notification::{Attention, Notifier},
+    parser::{self, Command, COMMAND_PREFIX, SEARCH_COMMAND},
+    search::Search,
+    terminal,
+    transcript::{Record, Transcripts},
+    transfer::{Direction, Progress, Stage},
+};
+use crate::common::message::{
+    Hit, Message, Presence, Protocol, Query, Reaction, Response, Status, RESULTS_PER_PAGE, SERVER_NAME
+};
+
+type Source = UnboundedReceiver<Update>;
+type Sink = UnboundedSender<Command>;
+
+/// A line of the chat history. Messages keep their identity
+/// so that edits and deletions can be applied in place.
+#[derive(Debug)]
+enum Entry {
+    Message {
+        message: Message,
+        attention: Attention,
+        edited: bool,
+        deleted: bool,
+        reactions: Vec<Reaction>,
+        time: DateTime<Local>
+    },
+    Notice { text: String, time: DateTime<Local> }
+}
+
+impl Entry {
+    /// Deleted messages are never found, as their text is no longer shown.
+    fn matches(&self, search: &Search) -> bool {
+        match self {
+            Entry::Message { deleted: true, .. } => false,
+            Entry::Message { message, .. } => search.matches_message(message),
+            Entry::Notice { text, .. } => search.matches_notice(text)
+        }
+    }
 
-type Source = UnboundedReceiver<Response>;
-type Sink = UnboundedSender<Request>;
+    fn record(&self, name: &str) -> Record {
+        match self {
+            Entry::Message { message, time, .. } => Record::message(message, name, *time),
+            Entry::Notice { text, time } => Record::notice(text, *time)
+        }
+    }
+}
 
 #[derive(Debug)]
 struct App {
-    messages: Vec<String>,
-    input: String,
+    name: String,
+    messages: Vec<Entry>,
+    transfers: BTreeMap<(String, u64), Progress>,
+    presence: Presence,
+    input: Editor,
+    notifier: Notifier,
+    transcripts: Transcripts,
+    search: Option<Search>,
+    last_query: Option<Query>,
+    completion: Option<Completion>,
+    hint: Option<String>,
+    scroll: usize,
+    max_scroll: Cell<usize>,
+    cursor: Cell<Option<(u16, u16)>>,
+    links: RefCell<Vec<Link>>,
+    failure: Option<String>,
+    typing: BTreeMap<String, Instant>,
+    last_typing: Option<(String, Instant)>,
+    last_activity: Instant,
+    auto_away: bool,
     quit: bool,
     source: Source,
     sink: Sink,
 }
 
-const EVENT_TIMEOUT: Duration = Duration::from_millis(10);
+const SCROLL_PAGE: usize = 10;
+const WHEEL_STEP: usize = 3;
+const SIDEBAR_WIDTH: u16 = 24;
+const MAX_INPUT_HEIGHT: u16 = 8;
+/// Entries kept for the history pane, search and export. Transcripts keep everything.
+const MAX_ENTRIES: usize = 5_000;
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+const HOUSEKEEPING_TICK: Duration = Duration::from_secs(1);
+
+fn disconnected() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Unexpected disconnect",
+    )
+}
 
 impl App {
-    fn new(source: Source, sink: Sink) -> Self {
-        let messages = Vec::<String>::new();
-        let input = String::new();
+    fn new(
+        name: String,
+        history: Option<PathBuf>,
+        notifier: Notifier,
+        transcripts: Transcripts,
+        source: Source,
+        sink: Sink
+    ) -> Self {
+        let messages = Vec::<Entry>::new();
+        let transfers = BTreeMap::new();
+        let presence = Presence::default();
+        let input = Editor::new(history);
         let quit = false;
 
         App {
+            name,
             messages,
+            transfers,
+            presence,
             input,
+            notifier,
+            transcripts,
+            search: None,
+            last_query: None,
+            completion: None,
+            hint: None,
+            scroll: 0,
+            max_scroll: Cell::new(0),
+            cursor: Cell::new(None),
+            links: RefCell::new(Vec::new()),
+            failure: None,
+            typing: BTreeMap::new(),
+            last_typing: None,
+            last_activity: Instant::now(),
+            auto_away: false,
             quit,
             source,
             sink,
         }
     }
 
+    fn scroll_up(&mut self, lines: usize) {
+        self.scroll = (self.scroll + lines).min(self.max_scroll.get());
+    }
+
+    fn scroll_down(&mut self, lines: usize) {
+        self.scroll = self.scroll.saturating_sub(lines);
+    }
+
+    fn draw<B: Backend>(&self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        terminal.draw(|frame| {
+            frame.render_widget(self, frame.size());
+
+            if let Some((x, y)) = self.cursor.get() {
+                frame.set_cursor(x, y);
+            }
+        })?;
+
+        hyperlink::emit(&mut stdout(), &self.links.take(), self.cursor.get())?;
+
+        Ok(())
+    }
+
+    /// Redraws only after a terminal event or a batch of updates from the driver.
     async fn run<B: Backend>(&mut self, terminal: &mut Terminal<B>) -> io::Result<()> {
+        let mut events = EventStream::new();
+        let mut housekeeping = interval(HOUSEKEEPING_TICK);
+        let mut dirty = true;
+
         while !self.quit {
-            self.handle_events().await?;
-            self.check_for_messages()?;
+            if dirty {
+                self.draw(terminal)?;
+            }
 
-            sleep(Duration::from_millis(10)).await;
+            dirty = tokio::select! {
+                event = events.next() => match event {
+                    Some(Ok(event)) => self.handle_event(event)? | self.drain_events(&mut events)?,
+                    Some(Err(reason)) => return Err(reason),
+                    None => break
+                },
+
+                update = self.source.recv(), if self.failure.is_none() => match update {
+                    Some(update) => {
+                        self.apply(update);
+                        self.drain_updates()?;
+                        true
+                    }
+                    None => return Err(disconnected())
+                },
 
-            terminal.draw(|frame| frame.render_widget(
-                &*self, 
-                frame.size()
-            ))?;
+                _ = housekeeping.tick() => self.housekeeping()?
+            };
         }
 
         Ok(())
     }
 
-    async fn handle_events(&mut self) -> io::Result<()> {
-        if !async { event::poll(EVENT_TIMEOUT) }.await? {
+    fn own_status(&self) -> Status {
+        self.presence.users
+            .iter()
+            .find(|user| user.name == self.name)
+            .map(|user| user.status)
+            .unwrap_or_default()
+    }
+
+    /// Expires stale typing notifications and switches to away after a period of inactivity.
+    /// Returns whether anything on screen changed.
+    fn housekeeping(&mut self) -> io::Result<bool> {
+        let now = Instant::now();
+        let typing = self.typing.len();
+
+        self.typing.retain(|_, expiry| *expiry > now);
+
+        let idle = now.duration_since(self.last_activity) >= AWAY_AFTER;
+
+        if idle && !self.auto_away && self.own_status() == Status::Online {
+            self.auto_away = true;
+            self.send(Command::Status { status: Status::Away })?;
+        }
+
+        Ok(typing != self.typing.len())
+    }
+
+    fn record_activity(&mut self) -> io::Result<()> {
+        self.last_activity = Instant::now();
+
+        if self.auto_away {
+            self.auto_away = false;
+            self.send(Command::Status { status: Status::Online })?;
+        }
+
+        Ok(())
+    }
+
+    /// Tells the receiver of the message being typed, at most once per `TYPING_INTERVAL`.
+    fn notify_typing(&mut self) -> io::Result<()> {
+        let Some(receiver) = Command::typing_receiver(self.input.text()) else {
+            return Ok(());
+        };
+
+        let recent = match &self.last_typing {
+            Some((last, sent)) => *last == receiver && sent.elapsed() < TYPING_INTERVAL,
+            None => false
+        };
+
+        if recent {
             return Ok(());
         }
 
-        if let event::Event::Key(key) = event::read()? {
-            if key.kind == event::KeyEventKind::Release {
-                return Ok(());
-            }
+        self.last_typing = Some((receiver.clone(), Instant::now()));
+        self.send(Command::Typing { receiver })
+    }
 
-            match key.code {
-                KeyCode::Char(ch) => {
-                    self.input.push(ch);
-                }
-                KeyCode::Backspace => {
-                    self.input.pop();
+    /// Handles events that are already buffered, so that fast typing
+    /// or a paste does not trigger a redraw per keystroke.
+    fn drain_events(&mut self, events: &mut EventStream) -> io::Result<bool> {
+        let mut dirty = false;
+
+        while let Some(Some(event)) = events.next().now_or_never() {
+            dirty |= self.handle_event(event?)?;
+        }
+
+        Ok(dirty)
+    }
+
+    /// Returns whether the event changed anything on screen.
+    fn handle_event(&mut self, event: Event) -> io::Result<bool> {
+        let key = match event {
+            Event::Key(key) => key,
+            Event::Resize(_, _) => return Ok(true),
+            Event::Paste(text) => {
+                self.record_activity()?;
+                self.input.insert_str(&text.replace("\r\n", "\n").replace('\r', "\n"));
+                self.notify_typing()?;
+                return Ok(true);
+            }
+            Event::Mouse(mouse) => {
+                match mouse.kind {
+                    MouseEventKind::ScrollUp => self.scroll_up(WHEEL_STEP),
+                    MouseEventKind::ScrollDown => self.scroll_down(WHEEL_STEP),
+                    _ => return Ok(false)
                 }
-                KeyCode::Enter => {
-                    let input = self.input.clone();
-                    self.input.clear();
 
-                    if let Some(command) = Command::from(&input) {
-                        if command == Command::Quit {
-                            self.quit = true;
+                return Ok(true);
+            }
+            _ => return Ok(false)
+        };
+
+        if key.kind == KeyEventKind::Release {
+            return Ok(false);
+        }
+
+        if self.failure.is_some() {
+            self.quit = true;
+            return Ok(false);
+        }
+
+        self.record_activity()?;
+
+        let before = self.input.text().to_owned();
+        self.handle_key(key)?;
+
+        if self.input.text() != before && !self.input.text().is_empty() {
+            self.notify_typing()?;
+        }
+
+        Ok(true)
+    }
+
+    fn complete(&mut self, forward: bool) {
+        match self.completion.as_mut() {
+            Some(completion) if completion.is_current(&self.input) => {
+                completion.cycle(&mut self.input, forward);
+            }
+            _ => {
+                self.completion = Completion::start(&mut self.input, &self.presence, &self.name);
+            }
+        }
+
+        self.hint = match &self.completion {
+            Some(completion) => Some(completion.hint()).filter(|hint| !hint.is_empty()),
+            None => Some(format!("no match for '{}'", self.input.word_before_cursor().1))
+        };
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> io::Result<()> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+
+        if !matches!(key.code, KeyCode::Tab | KeyCode::BackTab) {
+            self.completion = None;
+            self.hint = None;
+        }
+
+        match key.code {
+            KeyCode::Tab => self.complete(true),
+            KeyCode::BackTab => self.complete(false),
+            KeyCode::PageUp => self.scroll_up(SCROLL_PAGE),
+            KeyCode::PageDown => self.scroll_down(SCROLL_PAGE),
+            KeyCode::Char('a') if control => self.input.home(),
+            KeyCode::Char('e') if control => self.input.end(),
+            KeyCode::Char('w') if control => self.input.delete_word(),
+            KeyCode::Char('b') if alt => self.input.word_left(),
+            KeyCode::Char('f') if alt => self.input.word_right(),
+            KeyCode::Char('f') if control => self.input.set_text(&format!("{COMMAND_PREFIX}{SEARCH_COMMAND} ")),
+            KeyCode::Esc if self.search.is_some() => self.search_for(String::new()),
+            KeyCode::Char(ch) => self.input.insert(ch),
+            KeyCode::Backspace if control || alt => self.input.delete_word(),
+            KeyCode::Backspace => self.input.backspace(),
+            KeyCode::Delete => self.input.delete(),
+            KeyCode::Left if control || alt => self.input.word_left(),
+            KeyCode::Right if control || alt => self.input.word_right(),
+            KeyCode::Left => self.input.left(),
+            KeyCode::Right => self.input.right(),
+            KeyCode::Home => self.input.home(),
+            KeyCode::End => self.input.end(),
+            KeyCode::Up => self.input.previous(),
+            KeyCode::Down => self.input.next(),
+            KeyCode::Enter if alt || shift => self.input.insert('\n'),
+            KeyCode::Enter => {
+                let input = self.input.submit();
+                self.last_typing = None;
+
+                let command = match Command::from(&input) {
+                    Some(Command::Mute { target }) => {
+                        self.notifier.mute(&target);
+                        self.notice(format!("Muted {target}"));
+                        None
+                    }
+                    Some(Command::Unmute { target }) => {
+                        match self.notifier.unmute(&target) {
+                            true => self.notice(format!("Unmuted {target}")),
+                            false => self.notice(format!("{target} was not muted"))
+                        }
+                        None
+                    }
+                    Some(Command::Search { query }) => {
+                        self.search_for(query);
+                        None
+                    }
+                    Some(Command::More) => self.next_page(),
+                    Some(Command::Export { path }) => {
+                        self.export(path);
+                        None
+                    }
+                    Some(Command::Reply { id, message }) => match self.reply(id, message) {
+                        Some(command) => Some(command),
+                        None => {
+                            self.notice(format!("No message {id:x} to reply to"));
+                            None
                         }
+                    },
+                    command => command
+                };
 
-                        self.send(command.to_request())?;
+                if let Some(command) = command {
+                    if command == Command::Quit {
+                        self.quit = true;
                     }
-                }
-                _ => {
-                    return Ok(());
+
+                    self.send(command)?;
                 }
             }
+            _ => { }
         }
 
         Ok(())
     }
 
-    fn send(&mut self, request: Request) -> io::Result<()> {
+    fn send(&mut self, command: Command) -> io::Result<()> {
         self.sink
-            .send(request)
+            .send(command)
             .map_err(|reason| io::Error::new(
                 io::ErrorKind::BrokenPipe,
                 reason
             ))
     }
 
-    fn check_for_messages(&mut self) -> io::Result<()> {
-        match self.source.try_recv() {
-            Ok(message) => {
-                self.messages.push(message.to_string());
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Response(Response::Presence(presence)) => {
+                self.presence = presence;
             }
-            Err(TryRecvError::Disconnected) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::ConnectionAborted,
-                    "Unexpected disconnect",
-                ))
+            Update::Response(Response::Typing { sender, .. }) => {
+                self.typing.insert(sender, Instant::now() + TYPING_TIMEOUT);
+            }
+            Update::Response(Response::Message(message)) => {
+                self.typing.remove(message.get_sender());
+
+                let attention = self.notifier.attention(&message);
+
+                if attention >= Attention::Mention {
+                    let _ignore = self.notifier.alert(&message);
+                }
+
+                let time = Local::now();
+
+                if let Err(error) = self.transcripts.record(&Record::message(&message, &self.name, time)) {
+                    self.notice(format!("Could not write transcript: {error}"));
+                }
+
+                self.push(Entry::Message {
+                    message,
+                    attention,
+                    edited: false,
+                    deleted: false,
+                    reactions: Vec::new(),
+                    time
+                });
+            }
+            Update::Response(Response::Results { query, total, hits }) => {
+                self.show_results(query, total, hits);
+            }
+            Update::Response(Response::Edited { id, message: text }) => {
+                if let Some(Entry::Message { message, edited, .. }) = self.entry_mut(id) {
+                    message.set_message(&text);
+                    *edited = true;
+                }
+            }
+            Update::Response(Response::Reactions { id, reactions: totals }) => {
+                if let Some(Entry::Message { reactions, .. }) = self.entry_mut(id) {
+                    *reactions = totals;
+                }
+            }
+            Update::Response(Response::Deleted { id }) => {
+                if let Some(Entry::Message { deleted, .. }) = self.entry_mut(id) {
+                    *deleted = true;
+                }
+            }
+            Update::Response(response) => {
+                self.push(Entry::Notice { text: response.to_string(), time: Local::now() });
+            }
+            Update::Notice(notice) => {
+                self.notice(notice);
+            }
+            Update::Progress(progress) => {
+                self.update_transfer(progress);
+            }
+            Update::Failure(reason) => {
+                self.failure = Some(reason);
             }
-            Err(TryRecvError::Empty) => { }
         }
+    }
 
-        Ok(())
+    /// Adds to the history, dropping the oldest entry once there are `MAX_ENTRIES`.
+    fn push(&mut self, entry: Entry) {
+        self.messages.push(entry);
+
+        if self.messages.len() > MAX_ENTRIES {
+            self.messages.remove(0);
+        }
+    }
+
+    fn notice(&mut self, notice: String) {
+        self.push(Entry::Notice { text: format!("[client] {notice}"), time: Local::now() });
+    }
+
+    /// Shows only the entries matching `query`, or everything again if it is empty.
+    /// Errors go to the hint, as a notice could be hidden by the search still in effect.
+    fn search_for(&mut self, query: String) {
+        self.scroll = 0;
+
+        if query.is_empty() {
+            self.search = None;
+            return;
+        }
+
+        match Search::parse(&query) {
+            Ok(search) => self.search = Some(search),
+            Err(reason) => self.hint = Some(format!("invalid search: {reason}"))
+        }
+    }
+
+    fn show_results(&mut self, query: Query, total: u64, hits: Vec<Hit>) {
+        let first = query.page as u64 * RESULTS_PER_PAGE as u64;
+
+        let summary = match hits.is_empty() {
+            true if total == 0 => String::from("No messages found"),
+            true => format!("No more results, {total} in all"),
+            false => format!("Results {}-{} of {total}", first + 1, first + hits.len() as u64)
+        };
+
+        let more = match first + (hits.len() as u64) < total {
+            true => ", /more for the next page",
+            false => ""
+        };
+
+        self.notice(format!("{summary}{more}"));
+
+        for hit in hits {
+            let text = search_result(&hit, &self.name);
+            self.push(Entry::Notice { text, time: Local::now() });
+        }
+
+        self.last_query = Some(query);
+    }
+
+    /// Asks for the page after the last search results that came in.
+    fn next_page(&mut self) -> Option<Command> {
+        match self.last_query.clone() {
+            Some(mut query) => {
+                query.page += 1;
+                Some(Command::Find { query })
+            }
+            None => {
+                self.notice(String::from("Nothing to continue, search the history with /find first"));
+                None
+            }
+        }
+    }
+
+    /// Writes the chat history still held in this session, notices included, to a file.
+    fn export(&mut self, path: Option<PathBuf>) {
+        let records = self.messages
+            .iter()
+            .map(|entry| entry.record(&self.name))
+            .collect::<Vec<_>>();
+
+        match self.transcripts.export(&records, path) {
+            Ok(path) => self.notice(format!("Exported {} entries to {}", records.len(), path.display())),
+            Err(error) => self.notice(format!("Could not export: {error}"))
+        }
+    }
+
+    fn entry_mut(&mut self, id: u64) -> Option<&mut Entry> {
+        self.messages
+            .iter_mut()
+            .rev()
+            .find(|entry| matches!(entry, Entry::Message { message, .. } if message.id == id))
+    }
+
+    /// Replies go back to where the original message was sent:
+    /// the same room or broadcast, or the other side of a direct conversation.
+    fn reply(&self, id: u64, text: String) -> Option<Command> {
+        let original = find_message(&self.messages, id)?;
+
+        let receiver = match original.is_broadcast() || original.is_room() {
+            true => original.get_receiver(),
+            false if original.get_sender() == self.name => original.get_receiver(),
+            false => original.get_sender()
+        };
+
+        Some(Command::Send {
+            message: text,
+            receiver: receiver.to_owned(),
+            protocol: Protocol::Tcp,
+            reply_to: Some(id)
+        })
+    }
+
+    fn drain_updates(&mut self) -> io::Result<()> {
+        loop {
+            match self.source.try_recv() {
+                Ok(update) => self.apply(update),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) if self.failure.is_some() => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(disconnected())
+            }
+        }
+    }
+
+    fn update_transfer(&mut self, progress: Progress) {
+        let key = (progress.peer.clone(), progress.id);
+        let Progress { peer, id, name, direction, total, .. } = &progress;
+
+        let notice = match (&progress.stage, direction) {
+            (Stage::Pending, Direction::Download) => Some(format!(
+                "{peer} offers {name} ({total} bytes): /accept {peer} {id:x} or /reject {peer} {id:x}"
+            )),
+            (Stage::Finished, Direction::Download) => Some(format!("Received {name} from {peer}")),
+            (Stage::Finished, Direction::Upload) => Some(format!("Sent {name} to {peer}")),
+            (Stage::Failed(reason), _) => Some(format!("Transfer of {name} with {peer} failed: {reason}")),
+            _ => None
+        };
+
+        if let Some(notice) = notice {
+            self.notice(notice);
+        }
+
+        match progress.stage {
+            Stage::Finished | Stage::Failed(_) => { self.transfers.remove(&key); }
+            _ => { self.transfers.insert(key, progress); }
+        }
     }
 }
 
-fn chat_history<'a>(messages: &Vec<String>) -> Paragraph<'a> {
-    let messages = messages
+const REPLY_SNIPPET_LENGTH: usize = 40;
+const NOTICE_INDENT: usize = 2;
+
+fn find_message(entries: &[Entry], id: u64) -> Option<&Message> {
+    entries.iter().rev().find_map(|entry| match entry {
+        Entry::Message { message, .. } if message.id == id => Some(message),
+        _ => None
+    })
+}
+
+fn reply_context<'a>(entries: &[Entry], id: u64) -> Line<'a> {
+    let context = match find_message(entries, id) {
+        Some(original) => {
+            let mut snippet = original
+                .get_message()
+                .chars()
+                .take(REPLY_SNIPPET_LENGTH)
+                .collect::<String>();
+
+            if original.get_message().chars().count() > REPLY_SNIPPET_LENGTH {
+                snippet.push('…');
+            }
+
+            format!("↳ {} {}: {snippet}", id_label(id), original.get_sender())
+        }
+        None => format!("↳ {}", id_label(id))
+    };
+
+    Line::from(Span::styled(context, Style::default().fg(Color::DarkGray)))
+}
+
+/// One search result on a single row: when, where, who and the matching part of the text.
+fn search_result(hit: &Hit, name: &str) -> String {
+    let time = DateTime::from_timestamp(hit.time, 0)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default();
+
+    let scope = match hit.receiver.as_str() {
+        parser::BROADCAST_NAME => String::from("(all)"),
+        room if room.starts_with(parser::ROOM_PREFIX) => format!("({room})"),
+        receiver if hit.sender == name => format!("(to {receiver})"),
+        _ => String::from("(private)")
+    };
+
+    let snippet = hit.snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    format!("  {time} {} {scope} [{}]: {snippet}", id_label(hit.id), hit.sender)
+}
+
+fn id_label(id: u64) -> String {
+    format!("{id:x}")
+}
+
+/// Reaction counts, with the ones the user took part in highlighted.
+fn reaction_line<'a>(reactions: &[Reaction], name: &str) -> Line<'a> {
+    let spans = reactions
         .iter()
-        .map(|msg| Line::from(msg.to_owned()))
+        .map(|reaction| {
+            let mine = reaction.users.iter().any(|user| user == name);
+            let label = format!("{} {}  ", reaction.reaction, reaction.users.len());
+
+            match mine {
+                true => Span::styled(label, Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)),
+                false => Span::styled(label, Style::default().fg(Color::DarkGray))
+            }
+        })
         .collect::<Vec<_>>();
 
-    Paragraph::new(messages)
-        .wrap(Wrap { trim: true })
-        .left_aligned()
+    Line::from(spans)
 }
 
-fn input_view<'a>(text: &str) -> Title<'a> {
-    let prompt = format!("Message: {text}");
+fn attention_style(attention: Attention) -> Style {
+    match attention {
+        Attention::None => Style::default(),
+        Attention::Keyword => Style::default().fg(Color::Yellow),
+        Attention::Mention => Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+        Attention::Direct => Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD)
+    }
+}
 
-    Title::from(Line::from(prompt))
-        .alignment(Alignment::Center)
-        .position(block::Position::Bottom)
+fn server_style() -> Style {
+    Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC)
 }
 
-fn compose<'a>(title: Title<'a>, input: Title<'a>, history: Paragraph<'a>) -> Paragraph<'a> {
-    let block = Block::default()
-        .title(title)
-        .title(input)
+/// Where the message went and who sent it. Broadcast, room, private
+/// and server messages each get their own look.
+fn message_header<'a>(message: &Message, name: &str) -> Vec<Span<'a>> {
+    let sender = message.get_sender();
+
+    if message.from_server {
+        return vec![Span::styled(format!("[{SERVER_NAME}] "), server_style())];
+    }
+
+    let private = Style::default().fg(Color::Magenta);
+
+    let scope = match message.get_receiver() {
+        _ if message.is_broadcast() => Span::styled("(all) ", Style::default().fg(Color::DarkGray)),
+        room if message.is_room() => Span::styled(format!("({room}) "), Style::default().fg(Color::Cyan)),
+        receiver if sender == name => Span::styled(format!("(to {receiver}) "), private),
+        _ => Span::styled("(private) ", private)
+    };
+
+    let sender_style = Style::default()
+        .fg(format::sender_color(sender))
+        .add_modifier(Modifier::BOLD);
+
+    vec![scope, Span::styled(format!("[{sender}]"), sender_style), Span::raw(": ")]
+}
+
+/// Rows of one history entry, each with the indentation its wrapped continuation gets.
+/// Everything after the first row of a message is aligned past the message id.
+/// Matches of an active search are highlighted in the message text.
+fn entry_lines<'a>(entries: &[Entry], entry: &Entry, name: &str, search: Option<&Search>) -> Vec<(Line<'a>, usize)> {
+    let highlight = |row: Vec<Span<'a>>| match search {
+        Some(search) => search.highlight(row),
+        None => row
+    };
+
+    let (message, attention, edited, deleted, reactions) = match entry {
+        Entry::Notice { text, .. } => {
+            return vec![(Line::from(highlight(vec![Span::raw(text.clone())])), NOTICE_INDENT)];
+        }
+        Entry::Message { message, attention, edited, deleted, reactions, .. } => {
+            (message, *attention, *edited, *deleted, reactions)
+        }
+    };
+
+    let dim = Style::default().fg(Color::DarkGray);
+    let mut lines = Vec::new();
+
+    if let Some(reply_to) = message.reply_to {
+        lines.push((reply_context(entries, reply_to), NOTICE_INDENT));
+    }
+
+    let label = format!("{} ", id_label(message.id));
+    let indent = label.len();
+    let margin = || Span::raw(" ".repeat(indent));
+    let mut spans = vec![Span::styled(label, dim)];
+
+    if deleted {
+        spans.push(Span::styled(
+            format!("[{}] message deleted", message.get_sender()),
+            dim.add_modifier(Modifier::ITALIC)
+        ));
+
+        lines.push((Line::from(spans), indent));
+        return lines;
+    }
+
+    let base = match message.from_server {
+        true => server_style(),
+        false => attention_style(attention)
+    };
+
+    let mut rows = format::body(message.get_message(), base).into_iter().map(highlight);
+
+    spans.extend(message_header(message, name));
+    spans.extend(rows.next().unwrap_or_default());
+
+    if edited {
+        spans.push(Span::styled(" (edited)", dim));
+    }
+
+    lines.push((Line::from(spans), indent));
+
+    for row in rows {
+        let text = row.iter().map(|span| span.content.as_ref()).collect::<String>();
+        let leading = text.len() - text.trim_start().len();
+
+        let mut spans = vec![margin()];
+        spans.extend(row);
+
+        lines.push((Line::from(spans), indent + leading));
+    }
+
+    if !reactions.is_empty() {
+        let mut line = reaction_line(reactions, name);
+        line.spans.insert(0, margin());
+
+        lines.push((line, indent));
+    }
+
+    lines
+}
+
+/// Wraps the history itself, so that continuation rows keep their indentation.
+/// While searching, only the matching entries are shown.
+fn chat_history<'a>(entries: &[Entry], name: &str, width: u16, search: Option<&Search>) -> Vec<Line<'a>> {
+    entries
+        .iter()
+        .filter(|entry| search.is_none_or(|search| entry.matches(search)))
+        .flat_map(|entry| entry_lines(entries, entry, name, search))
+        .flat_map(|(line, indent)| format::wrap(&line, width as usize, indent))
+        .collect()
+}
+
+fn pane<'a>(title: &'a str) -> Block<'a> {
+    Block::default()
+        .title(Title::from(title).alignment(Alignment::Center))
         .borders(Borders::ALL)
-        .border_set(border::ROUNDED);
+        .border_set(border::ROUNDED)
+}
+
+fn input_view<'a>(rows: Vec<String>) -> Paragraph<'a> {
+    let rows = rows
+        .into_iter()
+        .map(Line::from)
+        .collect::<Vec<_>>();
 
-    history.block(block)
+    Paragraph::new(rows)
 }
 
-impl Widget for &App {
-    fn render(self, area: Rect, buffer: &mut Buffer) {
-        let title = Title::from("Chat")
-            .alignment(Alignment::Center)
-            .position(block::Position::Top);
+fn status_color(status: Status) -> Color {
+    match status {
+        Status::Online => Color::Green,
+        Status::Away => Color::Yellow,
+        Status::Busy => Color::Red
+    }
+}
+
+fn user_list<'a>(presence: &Presence, name: &str) -> List<'a> {
+    let users = presence.users
+        .iter()
+        .map(|user| {
+            let marker = Span::styled("● ", Style::default().fg(status_color(user.status)));
+
+            let label = match user.status {
+                Status::Online => user.name.clone(),
+                status => format!("{} ({status})", user.name)
+            };
+
+            match user.name == name {
+                true => ListItem::new(Line::from(vec![
+                    marker,
+                    Span::styled(format!("{label} (you)"), Style::default().add_modifier(Modifier::BOLD))
+                ])),
+                false => ListItem::new(Line::from(vec![marker, Span::raw(label)]))
+            }
+        });
+
+    List::new(users)
+}
+
+fn typing_view<'a>(typing: &BTreeMap<String, Instant>) -> Paragraph<'a> {
+    let names = typing.keys().cloned().collect::<Vec<_>>();
+
+    let text = match names.len() {
+        1 => format!(" {} is typing…", names[0]),
+        _ => format!(" {} are typing…", names.join(", "))
+    };
 
-        let input = input_view(&self.input);
-        let history = chat_history(&self.messages);
+    Paragraph::new(Line::from(Span::styled(
+        text,
+        Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC)
+    )))
+}
+
+fn room_list<'a>(presence: &Presence, name: &str) -> List<'a> {
+    let rooms = presence.rooms
+        .iter()
+        .map(|room| {
+            let joined = room.members.iter().any(|member| member == name);
+            let label = format!("{} ({})", room.name, room.members.len());
+
+            match joined {
+                true => ListItem::new(label)
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+                false => ListItem::new(label)
+                    .style(Style::default().fg(Color::DarkGray))
+            }
+        });
+
+    List::new(rooms)
+}
+
+fn status_bar<'a>(app: &App) -> Paragraph<'a> {
+    let scroll = match app.scroll {
+        0 => String::from("live"),
+        lines => format!("scrolled {lines} up")
+    };
+
+    let hint = app.hint
+        .clone()
+        .unwrap_or_else(|| String::from("PgUp/PgDn scroll, Ctrl+F search, Tab complete, Alt+Enter newline, /join #room, /status away, quit"));
+
+    let status = format!(
+        " {} ({}) | {} online | {} | {}",
+        app.name,
+        app.own_status(),
+        app.presence.users.len(),
+        scroll,
+        hint
+    );
+
+    Paragraph::new(Line::from(Span::styled(
+        status,
+        Style::default().fg(Color::Black).bg(Color::Gray)
+    )))
+}
+
+fn transfer_gauge<'a>(progress: &Progress) -> LineGauge<'a> {
+    let arrow = match progress.direction {
+        Direction::Upload => "->",
+        Direction::Download => "<-"
+    };
+
+    let label = format!("{arrow} {} {}", progress.peer, progress.name);
+
+    LineGauge::default()
+        .label(label)
+        .gauge_style(Style::default().fg(Color::Green))
+        .ratio(progress.ratio().clamp(0.0, 1.0))
+}
+
+impl App {
+    fn render_history(&self, area: Rect, buffer: &mut Buffer) {
+        let title = match &self.search {
+            Some(search) => format!(
+                "Chat: {} matching '{}' (Esc to clear)",
+                self.messages.iter().filter(|entry| entry.matches(search)).count(),
+                search.query()
+            ),
+            None => String::from("Chat")
+        };
+
+        let block = pane(&title);
+        let inner = block.inner(area);
+
+        let mut history = chat_history(&self.messages, &self.name, inner.width, self.search.as_ref());
+        let max_scroll = history.len().saturating_sub(inner.height as usize);
 
-        compose(title, input, history)
+        self.max_scroll.set(max_scroll);
+
+        // Only the rows in view are handed over, as the scroll offset of a paragraph
+        // cannot go past `u16::MAX` rows.
+        let offset = max_scroll - self.scroll.min(max_scroll);
+        history.truncate(offset + inner.height as usize);
+        history.drain(..offset);
+
+        Paragraph::new(history)
+            .left_aligned()
+            .block(block)
             .render(area, buffer);
+
+        self.links.replace(hyperlink::find(buffer, inner));
+    }
+
+    fn render_sidebar(&self, area: Rect, buffer: &mut Buffer) {
+        let [users_area, rooms_area] = Layout::vertical([
+            Constraint::Percentage(60),
+            Constraint::Percentage(40)
+        ]).areas(area);
+
+        Widget::render(
+            user_list(&self.presence, &self.name).block(pane("Users")),
+            users_area,
+            buffer
+        );
+
+        Widget::render(
+            room_list(&self.presence, &self.name).block(pane("Rooms")),
+            rooms_area,
+            buffer
+        );
+    }
+
+    fn render_transfers(&self, area: Rect, buffer: &mut Buffer) {
+        let rows = Layout::vertical(
+            vec![Constraint::Length(1); self.transfers.len()]
+        ).split(area);
+
+        for (progress, row) in self.transfers.values().zip(rows.iter()) {
+            transfer_gauge(progress).render(*row, buffer);
+        }
     }
 }
 
-pub async fn run(source: Source, sink: Sink) -> io::Result<()> {
-    enable_raw_mode()?;
+fn failure_view<'a>(reason: &str) -> Paragraph<'a> {
+    let block = pane("Disconnected")
+        .border_style(Style::default().fg(Color::Red));
+
+    let text = vec![
+        Line::from(format!("Connection to the server failed: {reason}")),
+        Line::from(""),
+        Line::from("Press any key to exit."),
+    ];
+
+    Paragraph::new(text)
+        .centered()
+        .wrap(Wrap { trim: true })
+        .block(block)
+}
 
-    stdout()
-        .execute(Clear(ClearType::All))?
-        .execute(EnterAlternateScreen)?;
+impl Widget for &App {
+    fn render(self, area: Rect, buffer: &mut Buffer) {
+        if let Some(reason) = &self.failure {
+            let [_, middle, _] = Layout::vertical([
+                Constraint::Fill(1),
+                Constraint::Length(5),
+                Constraint::Fill(1)
+            ]).areas(area);
+
+            self.cursor.set(None);
+            self.links.take();
+            Clear.render(middle, buffer);
+            failure_view(reason).render(middle, buffer);
+            return;
+        }
+
+        let (rows, (column, row)) = self.input.layout(area.width.saturating_sub(2));
+        let input_height = (rows.len() as u16 + 2).min(MAX_INPUT_HEIGHT);
+
+        let [main_area, transfers_area, typing_area, input_area, status_area] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(self.transfers.len() as u16),
+            Constraint::Length(!self.typing.is_empty() as u16),
+            Constraint::Length(input_height),
+            Constraint::Length(1)
+        ]).areas(area);
+
+        let [history_area, sidebar_area] = Layout::horizontal([
+            Constraint::Min(20),
+            Constraint::Length(SIDEBAR_WIDTH)
+        ]).areas(main_area);
+
+        self.render_history(history_area, buffer);
+        self.render_sidebar(sidebar_area, buffer);
+        self.render_transfers(transfers_area, buffer);
+
+        if !self.typing.is_empty() {
+            typing_view(&self.typing).render(typing_area, buffer);
+        }
+
+        let input_block = pane("Message");
+        let inner = input_block.inner(input_area);
+        let offset = (row + 1).saturating_sub(inner.height);
+
+        self.cursor.set(Some((inner.x + column, inner.y + row - offset)));
+
+        input_view(rows)
+            .scroll((offset, 0))
+            .block(input_block)
+            .render(input_area, buffer);
+
+        status_bar(self).render(status_area, buffer);
+    }
+}
+
+pub async fn run(
+    name: String,
+    history: Option<PathBuf>,
+    notifier: Notifier,
+    transcripts: Transcripts,
+    source: Source,
+    sink: Sink
+) -> io::Result<()> {
+    let _guard = terminal::Guard::enter()?;
 
     let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
-    let mut app = App::new(source, sink);
+    let mut app = App::new(name, history, notifier, transcripts, source, sink);
 
     app.run(&mut terminal).await?;
 
-    disable_raw_mode()?;
-    stdout().execute(LeaveAlternateScreen)?;
-
-    Ok(())
+    match app.failure {
+        Some(reason) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason)),
+        None => Ok(())
+    }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use tokio::sync::mpsc;
+
+    use crate::common::config::TranscriptFormat;
+
+    use super::*;
+
+    #[test]
+    fn only_the_server_itself_is_styled_as_the_server() {
+        let peer = Message::new("trust me", SERVER_NAME, "all", Protocol::Tcp);
+
+        let mut announcement = peer.clone();
+        announcement.from_server = true;
+
+        assert_eq!(message_header(&announcement, "ana")[0].style, server_style());
+        assert!(message_header(&peer, "ana").iter().all(|span| span.style != server_style()));
+    }
+
+    fn app() -> App {
+        let (_, source) = mpsc::unbounded_channel();
+        let (sink, _) = mpsc::unbounded_channel();
+        let notifier = Notifier::new(String::from("ana"), vec![], BTreeSet::new(), vec![]);
+        let transcripts = Transcripts::new(None, TranscriptFormat::Text, 0);
+
+        App::new(String::from("ana"), None, notifier, transcripts, source, sink)
+    }
+
+    fn rows(buffer: &Buffer, area: Rect) -> Vec<String> {
+        (area.top()..area.bottom())
+            .map(|y| (area.left()..area.right()).map(|x| buffer.get(x, y).symbol()).collect::<String>())
+            .collect()
+    }
+
+    #[test]
+    fn the_bottom_of_a_very_long_history_is_shown() {
+        let mut app = app();
+        let text = (0..70_000).map(|line| format!("line {line}")).collect::<Vec<_>>().join("\n");
+        app.apply(Update::Response(Response::Message(Message::new(&text, "bob", "all", Protocol::Tcp))));
+
+        let area = Rect::new(0, 0, 40, 5);
+        let mut buffer = Buffer::empty(area);
+        app.render_history(area, &mut buffer);
+
+        assert!(rows(&buffer, area)[3].contains("line 69999"), "{:?}", rows(&buffer, area));
+        assert!(app.max_scroll.get() > u16::MAX as usize);
+    }
+
+    #[test]
+    fn only_the_newest_entries_are_kept() {
+        let mut app = app();
+
+        for notice in 0..MAX_ENTRIES + 10 {
+            app.notice(notice.to_string());
+        }
+
+        assert_eq!(app.messages.len(), MAX_ENTRIES);
+        assert!(matches!(&app.messages[0], Entry::Notice { text, .. } if text == "[client] 10"));
+    }
+}
\ No newline at end of file
@@ -1,18 +1,64 @@
-use std::io;
+use std::{io, path::PathBuf};
 use tokio::sync::mpsc::unbounded_channel;
 
 use crate::common::config::Config;
 
+use notification::Notifier;
+use transcript::Transcripts;
+
+pub mod completion;
 pub mod driver;
+pub mod editor;
+pub mod format;
+pub mod hyperlink;
 pub mod interface;
+pub mod notification;
 pub mod parser;
+pub mod search;
+pub mod terminal;
+pub mod transcript;
+pub mod transfer;
+
+/// Prints every line of the local transcripts that contains the query.
+pub fn search(config: Config) -> io::Result<()> {
+    let directory = config.transcripts.unwrap_or_else(|| PathBuf::from(transcript::DEFAULT_DIRECTORY));
+    let query = config.query.unwrap_or_default();
+
+    for line in transcript::search(&directory, &query)? {
+        println!("{line}");
+    }
+
+    Ok(())
+}
 
 pub async fn run(config: Config) -> io::Result<()> {
     let (request_tx, request_rx) = unbounded_channel();
     let (response_tx, response_rx) = unbounded_channel();
 
+    let notifier = Notifier::new(
+        config.name.clone(),
+        config.highlights.clone(),
+        config.muted.clone(),
+        config.alerts.clone()
+    );
+
+    let transcripts = Transcripts::new(
+        config.transcripts.clone(),
+        config.transcript_format,
+        config.transcript_max_size
+    );
+
+    let cli = interface::run(
+        config.name.clone(), 
+        config.input_history.clone(), 
+        notifier,
+        transcripts,
+        response_rx, 
+        request_tx
+    );
     let driver = driver::run(config, request_rx, response_tx);
-    let cli = interface::run(response_rx, request_tx);
 
-    tokio::try_join!(driver, cli).map(|_| ())
+    let (driver, cli) = tokio::join!(driver, cli);
+
+    driver.and(cli)
 }
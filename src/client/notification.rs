@@ -0,0 +1,104 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, stdout, Write},
+};
+
+use crate::common::{config::Alert, message::Message};
+
+const MENTION_PREFIX: char = '@';
+const NOTIFICATION_TITLE: &str = "chat";
+
+/// Why a message deserves the user's attention, from least to most important.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Attention {
+    #[default]
+    None,
+    Keyword,
+    Mention,
+    Direct
+}
+
+/// Decides which incoming messages are highlighted and alerted on.
+/// Mute entries are user names or `#rooms`; `all` mutes broadcasts.
+#[derive(Debug)]
+pub struct Notifier {
+    name: String,
+    highlights: Vec<String>,
+    muted: BTreeSet<String>,
+    alerts: Vec<Alert>
+}
+
+fn is_name_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_' || ch == '-'
+}
+
+pub fn mentions(text: &str, name: &str) -> bool {
+    let mention = format!("{MENTION_PREFIX}{}", name.to_lowercase());
+    let text = text.to_lowercase();
+
+    text.match_indices(&mention).any(|(index, _)| !text[index + mention.len()..]
+        .starts_with(is_name_char)
+    )
+}
+
+/// Keeps terminal control sequences in message text from escaping the notification.
+fn sanitize(text: &str) -> String {
+    text.chars().filter(|ch| !ch.is_control()).collect()
+}
+
+impl Notifier {
+    pub fn new(name: String, highlights: Vec<String>, muted: BTreeSet<String>, alerts: Vec<Alert>) -> Self {
+        Notifier { name, highlights, muted, alerts }
+    }
+
+    pub fn is_muted(&self, message: &Message) -> bool {
+        self.muted.contains(message.get_sender()) || self.muted.contains(message.get_receiver())
+    }
+
+    pub fn mute(&mut self, target: &str) -> bool {
+        self.muted.insert(target.to_owned())
+    }
+
+    pub fn unmute(&mut self, target: &str) -> bool {
+        self.muted.remove(target)
+    }
+
+    pub fn attention(&self, message: &Message) -> Attention {
+        if message.get_sender() == self.name || self.is_muted(message) {
+            return Attention::None;
+        }
+
+        let text = message.get_message();
+
+        if message.get_receiver() == self.name {
+            Attention::Direct
+        } else if mentions(text, &self.name) {
+            Attention::Mention
+        } else if self.highlights.iter().any(|keyword| text.to_lowercase().contains(keyword)) {
+            Attention::Keyword
+        } else {
+            Attention::None
+        }
+    }
+
+    /// Rings the bell and/or raises a desktop notification through the terminal.
+    pub fn alert(&self, message: &Message) -> io::Result<()> {
+        if self.alerts.is_empty() {
+            return Ok(());
+        }
+
+        let title = format!("{NOTIFICATION_TITLE}: {}", sanitize(message.get_sender()));
+        let body = sanitize(message.get_message());
+        let mut out = stdout();
+
+        for alert in &self.alerts {
+            match alert {
+                Alert::Bell => write!(out, "\x07")?,
+                Alert::Osc9 => write!(out, "\x1b]9;{title}: {body}\x1b\\")?,
+                Alert::Osc777 => write!(out, "\x1b]777;notify;{};{body}\x1b\\", title.replace(';', ","))?
+            }
+        }
+
+        out.flush()
+    }
+}
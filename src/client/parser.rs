@@ -1,64 +1,391 @@
-use crate::common::message::{Protocol, Request};
+use std::path::PathBuf;
 
-pub const BROADCAST_NAME: &'static str = "all";
-pub const UDP_MODIFIER: &'static str = "udp";
-const SENDER_DELIMITER: char = ':';
-const QUIT_COMMAND: &'static str = "quit";
+use chrono::{Days, Local, NaiveDate, TimeZone};
+
+use crate::common::message::{Protocol, Query, Request, Status};
+
+use super::search::{SCOPE_FILTER, SENDER_FILTER};
+
+pub const BROADCAST_NAME: &str = "all";
+pub const UDP_MODIFIER: &str = "udp";
+pub const ROOM_PREFIX: char = '#';
+pub const SENDER_DELIMITER: char = ':';
+pub const COMMAND_PREFIX: char = '/';
+const QUIT_COMMAND: &str = "quit";
+const SEND_FILE_COMMAND: &str = "send";
+const ACCEPT_FILE_COMMAND: &str = "accept";
+const REJECT_FILE_COMMAND: &str = "reject";
+pub const JOIN_COMMAND: &str = "join";
+pub const LEAVE_COMMAND: &str = "leave";
+const STATUS_COMMAND: &str = "status";
+const EDIT_COMMAND: &str = "edit";
+const DELETE_COMMAND: &str = "delete";
+const REPLY_COMMAND: &str = "reply";
+const REACT_COMMAND: &str = "react";
+const MUTE_COMMAND: &str = "mute";
+const UNMUTE_COMMAND: &str = "unmute";
+const EXPORT_COMMAND: &str = "export";
+pub const SEARCH_COMMAND: &str = "search";
+const FIND_COMMAND: &str = "find";
+const MORE_COMMAND: &str = "more";
+const SINCE_FILTER: &str = "since:";
+const UNTIL_FILTER: &str = "until:";
+const PAGE_FILTER: &str = "page:";
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+/// Short names accepted by `/react` in place of the emoji itself.
+const REACTION_ALIASES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("ok", "✅"),
+    ("heart", "❤️"),
+    ("laugh", "😂"),
+    ("eyes", "👀"),
+    ("tada", "🎉"),
+];
+
+pub const SLASH_COMMANDS: &[&str] = &[
+    SEND_FILE_COMMAND,
+    ACCEPT_FILE_COMMAND,
+    REJECT_FILE_COMMAND,
+    JOIN_COMMAND,
+    LEAVE_COMMAND,
+    STATUS_COMMAND,
+    EDIT_COMMAND,
+    DELETE_COMMAND,
+    REPLY_COMMAND,
+    REACT_COMMAND,
+    MUTE_COMMAND,
+    UNMUTE_COMMAND,
+    EXPORT_COMMAND,
+    SEARCH_COMMAND,
+    FIND_COMMAND,
+    MORE_COMMAND,
+];
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Command {
-    Send { message: String, receiver: String, protocol: Protocol },
+    Send { message: String, receiver: String, protocol: Protocol, reply_to: Option<u64> },
+    Offer { receiver: String, path: PathBuf, protocol: Protocol },
+    Accept { sender: String, id: u64 },
+    Reject { sender: String, id: u64 },
+    Join { room: String },
+    Leave { room: String },
+    Status { status: Status },
+    Typing { receiver: String },
+    Edit { id: u64, message: String },
+    Delete { id: u64 },
+    Reply { id: u64, message: String },
+    React { id: u64, reaction: String },
+    Mute { target: String },
+    Unmute { target: String },
+    Export { path: Option<PathBuf> },
+    Search { query: String },
+    Find { query: Query },
+    More,
     Quit
 }
 
 fn partition(input: &str) -> Option<(String, String)> {
     let mid = input.find(SENDER_DELIMITER)?;
     Some((
-        (&input[..mid]).to_owned(), 
-        (&input[mid + 1..]).to_owned()
+        input[..mid].to_owned(),
+        input[mid + 1..].to_owned()
     ))
 }
 
+/// Line breaks are kept, but normalized to `\n`.
 fn cleanup(message: String) -> String {
-    message.replace('\n', "")
+    message.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+fn parse_transfer_answer(mut words: std::str::SplitWhitespace<'_>) -> Option<(String, u64)> {
+    let sender = words.next()?.to_owned();
+    let id = u64::from_str_radix(words.next()?, 16).ok()?;
+
+    Some((sender, id))
+}
+
+/// Parses `<id> <text>`, where the id is the hex number shown next to a message.
+fn parse_amendment(input: &str) -> Option<(u64, String)> {
+    let (id, message) = input.trim_start().split_once(' ')?;
+    let id = u64::from_str_radix(id, 16).ok()?;
+
+    match message.trim().is_empty() {
+        true => None,
+        false => Some((id, message.to_owned()))
+    }
+}
+
+fn parse_reaction(mut words: std::str::SplitWhitespace<'_>) -> Option<(u64, String)> {
+    let id = u64::from_str_radix(words.next()?, 16).ok()?;
+    let reaction = words.next()?;
+
+    let reaction = REACTION_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == reaction)
+        .map_or(reaction, |(_, emoji)| *emoji);
+
+    Some((id, reaction.to_owned()))
+}
+
+fn parse_room(mut words: std::str::SplitWhitespace<'_>) -> Option<String> {
+    let room = words.next()?.trim_start_matches(ROOM_PREFIX);
+
+    match room.is_empty() {
+        true => None,
+        false => Some(format!("{ROOM_PREFIX}{room}"))
+    }
+}
+
+/// Start of the given local day, as a Unix timestamp.
+fn parse_day(date: &str, days_after: u64) -> Option<i64> {
+    let day = NaiveDate::parse_from_str(date, DATE_FORMAT).ok()?.checked_add_days(Days::new(days_after))?;
+    let start = Local.from_local_datetime(&day.and_hms_opt(0, 0, 0)?).earliest()?;
+
+    Some(start.timestamp())
+}
+
+/// Parses `[from:<name>] [in:<#room|all>] [since:<date>] [until:<date>] [page:<n>] <words>`
+/// for a search of the server history. Dates are `YYYY-MM-DD`, `until` includes the day itself.
+fn parse_query(input: &str) -> Option<Query> {
+    let mut query = Query::default();
+    let mut words = Vec::new();
+
+    for word in input.split_whitespace() {
+        if let Some(sender) = word.strip_prefix(SENDER_FILTER) {
+            query.sender = Some(sender.to_owned());
+        } else if let Some(room) = word.strip_prefix(SCOPE_FILTER) {
+            query.room = Some(room.to_owned());
+        } else if let Some(date) = word.strip_prefix(SINCE_FILTER) {
+            query.since = Some(parse_day(date, 0)?);
+        } else if let Some(date) = word.strip_prefix(UNTIL_FILTER) {
+            query.until = Some(parse_day(date, 1)?);
+        } else if let Some(page) = word.strip_prefix(PAGE_FILTER) {
+            query.page = page.parse::<u32>().ok()?.checked_sub(1)?;
+        } else {
+            words.push(word);
+        }
+    }
+
+    query.text = words.join(" ");
+    Some(query)
 }
 
 impl Command {
+    fn from_slash(input: &str) -> Option<Self> {
+        let mut words = input.split_whitespace();
+
+        match words.next()? {
+            SEND_FILE_COMMAND => {
+                let mut receiver = words.next()?;
+                let mut protocol = Protocol::Tcp;
+
+                if receiver == UDP_MODIFIER {
+                    receiver = words.next()?;
+                    protocol = Protocol::Udp;
+                }
+
+                let path = words.collect::<Vec<_>>().join(" ");
+
+                if path.is_empty() {
+                    return None;
+                }
+
+                Some(Self::Offer { receiver: receiver.to_owned(), path: PathBuf::from(path), protocol })
+            }
+            ACCEPT_FILE_COMMAND => {
+                let (sender, id) = parse_transfer_answer(words)?;
+                Some(Self::Accept { sender, id })
+            }
+            REJECT_FILE_COMMAND => {
+                let (sender, id) = parse_transfer_answer(words)?;
+                Some(Self::Reject { sender, id })
+            }
+            JOIN_COMMAND => Some(Self::Join { room: parse_room(words)? }),
+            LEAVE_COMMAND => Some(Self::Leave { room: parse_room(words)? }),
+            STATUS_COMMAND => Some(Self::Status { status: Status::from(words.next()?)? }),
+            DELETE_COMMAND => Some(Self::Delete { id: u64::from_str_radix(words.next()?, 16).ok()? }),
+            EDIT_COMMAND => {
+                let (id, message) = parse_amendment(input.strip_prefix(EDIT_COMMAND)?)?;
+                Some(Self::Edit { id, message })
+            }
+            REACT_COMMAND => {
+                let (id, reaction) = parse_reaction(words)?;
+                Some(Self::React { id, reaction })
+            }
+            MUTE_COMMAND => Some(Self::Mute { target: words.next()?.to_owned() }),
+            UNMUTE_COMMAND => Some(Self::Unmute { target: words.next()?.to_owned() }),
+            EXPORT_COMMAND => {
+                let path = words.collect::<Vec<_>>().join(" ");
+                Some(Self::Export { path: (!path.is_empty()).then(|| PathBuf::from(path)) })
+            }
+            SEARCH_COMMAND => Some(Self::Search { query: input.strip_prefix(SEARCH_COMMAND)?.trim().to_owned() }),
+            FIND_COMMAND => Some(Self::Find { query: parse_query(input.strip_prefix(FIND_COMMAND)?)? }),
+            MORE_COMMAND => Some(Self::More),
+            REPLY_COMMAND => {
+                let (id, message) = parse_amendment(input.strip_prefix(REPLY_COMMAND)?)?;
+                Some(Self::Reply { id, message })
+            }
+            _ => None
+        }
+    }
+
     pub fn from(input: &str) -> Option<Self> {
         if input == QUIT_COMMAND {
             return Some(Self::Quit);
         }
 
-        let (receiver, message) = partition(&input)?;
+        if let Some(command) = input.strip_prefix(COMMAND_PREFIX) {
+            return Self::from_slash(command);
+        }
+
+        let (receiver, message) = partition(input)?;
 
         if receiver.starts_with(UDP_MODIFIER) {
             let receiver = receiver
                 .replace(UDP_MODIFIER, "")
-                .strip_prefix(" ")?
+                .strip_prefix(' ')?
                 .to_owned();
 
-            Some(Self::Send { receiver, message, protocol: Protocol::Udp })
+            Some(Self::Send { receiver, message, protocol: Protocol::Udp, reply_to: None })
         } else {
-            Some(Self::Send { receiver, message, protocol: Protocol::Tcp })
+            Some(Self::Send { receiver, message, protocol: Protocol::Tcp, reply_to: None })
         }
     }
 
-    pub fn to_request(self) -> Request {
+    /// Receiver of a message that is still being typed, if the input already names one.
+    pub fn typing_receiver(input: &str) -> Option<String> {
+        match Self::from(input)? {
+            Self::Send { receiver, message, .. } if !message.trim().is_empty() => Some(receiver),
+            _ => None
+        }
+    }
+
+    /// Converts a chat command into its wire request.
+    /// File transfer commands are handled by the driver; replies, mutes, exports, local searches
+    /// and paging through search results by the interface, which knows where the original message
+    /// went, what to alert on, what was shown and what was searched for. All of them yield `None`.
+    pub fn into_request(self) -> Option<Request> {
         match self {
-            Self::Quit => Request::SignOut,
-            Self::Send { message, receiver, protocol } => {
+            Self::Quit => Some(Request::SignOut),
+            Self::Join { room } => Some(Request::Join { room }),
+            Self::Leave { room } => Some(Request::Leave { room }),
+            Self::Status { status } => Some(Request::Status { status }),
+            Self::Typing { receiver } => Some(Request::Typing { receiver }),
+            Self::Edit { id, message } => Some(Request::Edit { id, message: cleanup(message) }),
+            Self::Delete { id } => Some(Request::Delete { id }),
+            Self::React { id, reaction } => Some(Request::React { id, reaction }),
+            Self::Send { message, receiver, protocol, reply_to } => {
                 let message = cleanup(message);
 
-                if &receiver[..] == BROADCAST_NAME {
-                    Request::SendAll { message, protocol }
+                if receiver == BROADCAST_NAME {
+                    Some(Request::SendAll { message, protocol, reply_to })
                 } else {
-                    Request::Send { 
-                        receiver, 
-                        message, 
-                        protocol
-                    }
+                    Some(Request::Send {
+                        receiver,
+                        message,
+                        protocol,
+                        reply_to
+                    })
                 }
             }
+            Self::Offer { .. } | Self::Accept { .. } | Self::Reject { .. } => None,
+            Self::Reply { .. } | Self::Mute { .. } | Self::Unmute { .. } | Self::Export { .. } => None,
+            Self::Find { query } => Some(Request::Search { query }),
+            Self::Search { .. } | Self::More => None
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_name_their_receiver() {
+        assert_eq!(
+            Command::from("bob: hi: there"),
+            Some(Command::Send { message: String::from(" hi: there"), receiver: String::from("bob"), protocol: Protocol::Tcp, reply_to: None })
+        );
+        assert_eq!(
+            Command::from("udp bob:hi"),
+            Some(Command::Send { message: String::from("hi"), receiver: String::from("bob"), protocol: Protocol::Udp, reply_to: None })
+        );
+        assert_eq!(Command::from("no receiver"), None);
+        assert_eq!(Command::from(QUIT_COMMAND), Some(Command::Quit));
+    }
+
+    #[test]
+    fn file_commands_take_a_path_or_a_hex_id() {
+        assert_eq!(
+            Command::from("/send bob my notes.txt"),
+            Some(Command::Offer { receiver: String::from("bob"), path: PathBuf::from("my notes.txt"), protocol: Protocol::Tcp })
+        );
+        assert_eq!(
+            Command::from("/send udp bob notes.txt"),
+            Some(Command::Offer { receiver: String::from("bob"), path: PathBuf::from("notes.txt"), protocol: Protocol::Udp })
+        );
+        assert_eq!(Command::from("/accept bob ff"), Some(Command::Accept { sender: String::from("bob"), id: 255 }));
+        assert_eq!(Command::from("/reject bob 1a"), Some(Command::Reject { sender: String::from("bob"), id: 26 }));
+
+        for input in ["/send bob", "/send udp bob", "/accept bob", "/accept bob xyz", "/reject"] {
+            assert_eq!(Command::from(input), None, "{input}");
+        }
+
+        assert!(Command::from("/send bob notes.txt").unwrap().into_request().is_none());
+    }
+
+    #[test]
+    fn amendments_take_a_hex_id_and_text() {
+        assert_eq!(Command::from("/edit 1f  fixed text"), Some(Command::Edit { id: 31, message: String::from(" fixed text") }));
+        assert_eq!(Command::from("/reply a two\nlines"), Some(Command::Reply { id: 10, message: String::from("two\nlines") }));
+        assert_eq!(Command::from("/delete ff"), Some(Command::Delete { id: 255 }));
+
+        for input in ["/edit 1f", "/edit 1f   ", "/edit zz text", "/reply", "/delete", "/delete -1"] {
+            assert_eq!(Command::from(input), None, "{input}");
+        }
+
+        let request = Command::from("/edit 1 fixed").unwrap().into_request();
+        assert!(matches!(request, Some(Request::Edit { id: 1, message }) if message == "fixed"));
+        assert!(Command::from("/reply 1 hi").unwrap().into_request().is_none());
+    }
+
+    #[test]
+    fn reactions_accept_aliases() {
+        assert_eq!(Command::from("/react 2a +1"), Some(Command::React { id: 42, reaction: String::from("👍") }));
+        assert_eq!(Command::from("/react 2a 🦀"), Some(Command::React { id: 42, reaction: String::from("🦀") }));
+        assert_eq!(Command::from("/react 2a"), None);
+        assert_eq!(Command::from("/react +1"), None);
+    }
+
+    #[test]
+    fn line_breaks_are_normalized() {
+        let request = Command::from("/edit 1 a\r\nb\rc").unwrap().into_request();
+        assert!(matches!(request, Some(Request::Edit { id: 1, message }) if message == "a\nb\nc"));
+
+        let request = Command::from("bob:one\r\ntwo").unwrap().into_request();
+        assert!(matches!(request, Some(Request::Send { message, .. }) if message == "one\ntwo"));
+    }
+
+    #[test]
+    fn find_queries_take_filters_and_dates() {
+        let Some(Command::Find { query }) = Command::from("/find from:bob in:#dev since:2024-03-01 until:2024-03-01 page:2 release notes") else {
+            panic!("not a find command");
+        };
+
+        assert_eq!(query.text, "release notes");
+        assert_eq!(query.sender.as_deref(), Some("bob"));
+        assert_eq!(query.room.as_deref(), Some("#dev"));
+        assert_eq!(query.page, 1);
+        assert_eq!(query.since, parse_day("2024-03-01", 0));
+        assert_eq!(query.until, parse_day("2024-03-02", 0));
+        assert!(query.since < query.until);
+
+        for input in ["/find since:yesterday", "/find until:2024-13-01", "/find page:0", "/find page:x"] {
+            assert_eq!(Command::from(input), None, "{input}");
+        }
+
+        assert!(matches!(Command::from("/find hi").unwrap().into_request(), Some(Request::Search { .. })));
+    }
+}
@@ -0,0 +1,246 @@
+use ratatui::{
+    style::{Modifier, Style},
+    text::Span,
+};
+use regex::{Regex, RegexBuilder};
+
+use crate::common::message::Message;
+
+pub const SENDER_FILTER: &str = "from:";
+pub const SCOPE_FILTER: &str = "in:";
+const REGEX_DELIMITER: char = '/';
+
+/// Which kind of conversation a message has to belong to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    Direct,
+    Broadcast,
+    Room
+}
+
+impl Scope {
+    fn from(arg: &str) -> Option<Self> {
+        match arg {
+            "dm" => Some(Self::Direct),
+            "all" => Some(Self::Broadcast),
+            "room" | "rooms" => Some(Self::Room),
+            _ => None
+        }
+    }
+
+    fn contains(self, message: &Message) -> bool {
+        match self {
+            Self::Direct => !message.is_broadcast() && !message.is_room(),
+            Self::Broadcast => message.is_broadcast(),
+            Self::Room => message.is_room()
+        }
+    }
+}
+
+/// A filter over the chat history, parsed from `[from:<name>] [in:dm|all|room] <text>`.
+/// The text is matched as a case-insensitive substring, or as a regex when written as `/.../`.
+#[derive(Debug)]
+pub struct Search {
+    query: String,
+    pattern: Option<Regex>,
+    sender: Option<String>,
+    scope: Option<Scope>,
+}
+
+fn highlight_style() -> Style {
+    Style::default().add_modifier(Modifier::REVERSED)
+}
+
+/// Regex syntax errors span several lines, pointing at the offending spot; the last one says what is wrong.
+fn describe(error: &regex::Error) -> String {
+    let description = error.to_string();
+
+    description
+        .lines()
+        .last()
+        .unwrap_or_default()
+        .trim_start_matches("error: ")
+        .to_owned()
+}
+
+impl Search {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut sender = None;
+        let mut scope = None;
+        let mut words = Vec::new();
+
+        for word in query.split(' ') {
+            if let Some(name) = word.strip_prefix(SENDER_FILTER).filter(|name| !name.is_empty()) {
+                sender = Some(name.to_owned());
+            } else if let Some(arg) = word.strip_prefix(SCOPE_FILTER) {
+                scope = Some(Scope::from(arg).ok_or_else(|| format!("unknown scope '{arg}'"))?);
+            } else {
+                words.push(word);
+            }
+        }
+
+        let text = words.join(" ");
+        let text = text.trim();
+
+        let pattern = match text.strip_prefix(REGEX_DELIMITER).and_then(|text| text.strip_suffix(REGEX_DELIMITER)) {
+            Some(regex) => regex.to_owned(),
+            None => regex::escape(text)
+        };
+
+        let pattern = match pattern.is_empty() {
+            true => None,
+            false => Some(
+                RegexBuilder::new(&pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|error| describe(&error))?
+            )
+        };
+
+        if pattern.is_none() && sender.is_none() && scope.is_none() {
+            return Err(String::from("nothing to search for"));
+        }
+
+        Ok(Search { query: query.trim().to_owned(), pattern, sender, scope })
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    fn matches_text(&self, text: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(text))
+    }
+
+    pub fn matches_message(&self, message: &Message) -> bool {
+        self.sender.as_ref().is_none_or(|sender| sender == message.get_sender())
+            && self.scope.is_none_or(|scope| !message.from_server && scope.contains(message))
+            && self.matches_text(message.get_message())
+    }
+
+    /// Notices only ever match on their text.
+    pub fn matches_notice(&self, text: &str) -> bool {
+        self.sender.is_none() && self.scope.is_none() && self.matches_text(text)
+    }
+
+    /// Marks every match in a rendered row, splitting spans where a match starts or ends.
+    /// The highlight is laid over the existing style, so links and code keep their look.
+    pub fn highlight<'a>(&self, row: Vec<Span<'a>>) -> Vec<Span<'a>> {
+        let Some(pattern) = &self.pattern else {
+            return row;
+        };
+
+        let text = row.iter().map(|span| span.content.as_ref()).collect::<String>();
+        let matches = pattern
+            .find_iter(&text)
+            .filter(|found| !found.is_empty())
+            .map(|found| found.range())
+            .collect::<Vec<_>>();
+
+        if matches.is_empty() {
+            return row;
+        }
+
+        let mut spans = Vec::new();
+        let mut offset = 0;
+
+        for span in row {
+            let content = span.content.as_ref();
+            let end = offset + content.len();
+            let mut start = offset;
+
+            let mut cuts = matches
+                .iter()
+                .flat_map(|range| [range.start, range.end])
+                .filter(|cut| *cut > offset && *cut < end)
+                .collect::<Vec<_>>();
+
+            cuts.push(end);
+
+            for cut in cuts {
+                let piece = content[start - offset..cut - offset].to_owned();
+                let marked = matches.iter().any(|range| range.start <= start && start < range.end);
+
+                let style = match marked {
+                    true => span.style.patch(highlight_style()),
+                    false => span.style
+                };
+
+                spans.push(Span::styled(piece, style));
+                start = cut;
+            }
+
+            offset = end;
+        }
+
+        spans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::{Protocol, SERVER_NAME};
+
+    use super::*;
+
+    fn message(text: &str, sender: &str, receiver: &str) -> Message {
+        Message::new(text, sender, receiver, Protocol::Tcp)
+    }
+
+    #[test]
+    fn text_is_a_substring_unless_written_as_a_regex() {
+        let search = Search::parse("A.B").unwrap();
+        assert!(search.matches_notice("xa.bx"));
+        assert!(!search.matches_notice("axb"));
+
+        let search = Search::parse("/^h.llo$/").unwrap();
+        assert!(search.matches_notice("HELLO"));
+        assert!(!search.matches_notice("hello there"));
+    }
+
+    #[test]
+    fn bad_queries_say_what_is_wrong() {
+        assert_eq!(Search::parse("  ").unwrap_err(), "nothing to search for");
+        assert_eq!(Search::parse("in:nope hi").unwrap_err(), "unknown scope 'nope'");
+        assert!(Search::parse("/(/").unwrap_err().contains("unclosed group"));
+    }
+
+    #[test]
+    fn filters_narrow_down_messages() {
+        let direct = message("hi bob", "alice", "bob");
+        let broadcast = message("hi all", "alice", "all");
+        let room = message("hi dev", "carol", "#dev");
+        let mut server = message("alice joined", SERVER_NAME, "#dev");
+        server.from_server = true;
+
+        let matching = |query: &str| [&direct, &broadcast, &room, &server]
+            .into_iter()
+            .filter(|message| Search::parse(query).unwrap().matches_message(message))
+            .map(Message::get_message)
+            .collect::<Vec<_>>();
+
+        assert_eq!(matching("from:alice"), ["hi bob", "hi all"]);
+        assert_eq!(matching("in:dm"), ["hi bob"]);
+        assert_eq!(matching("in:all"), ["hi all"]);
+        assert_eq!(matching("in:rooms"), ["hi dev"]);
+        assert_eq!(matching("from:alice in:dm hi"), ["hi bob"]);
+        assert_eq!(matching("alice"), ["alice joined"]);
+        assert!(!Search::parse("from:alice hi").unwrap().matches_notice("hi"));
+    }
+
+    #[test]
+    fn highlights_split_spans_and_keep_their_style() {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let search = Search::parse("lo w").unwrap();
+        let spans = search.highlight(vec![Span::raw("hello "), Span::styled("world", bold)]);
+
+        let pieces = spans
+            .iter()
+            .map(|span| (span.content.as_ref(), span.style.add_modifier.contains(Modifier::REVERSED)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(pieces, [("hel", false), ("lo ", true), ("w", true), ("orld", false)]);
+        assert!(spans[2].style.add_modifier.contains(Modifier::BOLD));
+        assert_eq!(search.highlight(vec![Span::raw("nothing")]).len(), 1);
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    io::{self, stdout},
+    panic,
+};
+
+use crossterm::{
+    event::{DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture},
+    terminal::{
+        disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen
+    },
+    ExecutableCommand,
+};
+
+/// Puts the terminal into raw mode on creation and restores it when dropped,
+/// so that early returns, cancellation and panics all leave a usable shell.
+pub struct Guard;
+
+impl Guard {
+    pub fn enter() -> io::Result<Self> {
+        install_panic_hook();
+        enable_raw_mode()?;
+
+        let guard = Guard;
+
+        stdout()
+            .execute(Clear(ClearType::All))?
+            .execute(EnterAlternateScreen)?
+            .execute(EnableMouseCapture)?
+            .execute(EnableBracketedPaste)?;
+
+        Ok(guard)
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+/// Best effort: every step is attempted even if an earlier one fails.
+pub fn restore() {
+    let mut out = stdout();
+
+    let _ignore = out.execute(DisableBracketedPaste);
+    let _ignore = out.execute(DisableMouseCapture);
+    let _ignore = out.execute(LeaveAlternateScreen);
+    let _ignore = disable_raw_mode();
+}
+
+fn install_panic_hook() {
+    let previous = panic::take_hook();
+
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        previous(info);
+    }));
+}
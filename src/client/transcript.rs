@@ -0,0 +1,259 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::common::{config::TranscriptFormat, message::Message};
+
+use super::parser::{BROADCAST_NAME, ROOM_PREFIX};
+
+/// How many rotated files are kept next to the current one.
+const ROTATIONS: usize = 5;
+const EXPORT_PREFIX: &str = "export";
+pub const DEFAULT_DIRECTORY: &str = "transcripts";
+const CONTINUATION_INDENT: &str = "  ";
+
+/// One line of a transcript, independent of the file format it is stored in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub time: String,
+    pub id: Option<u64>,
+    pub conversation: String,
+    pub sender: Option<String>,
+    pub text: String,
+}
+
+/// Messages to and from the local user are filed under the other side of the conversation.
+pub fn conversation(message: &Message, name: &str) -> String {
+    match message.is_broadcast() || message.is_room() || message.get_sender() == name {
+        true => message.get_receiver().to_owned(),
+        false => message.get_sender().to_owned()
+    }
+}
+
+impl Record {
+    pub fn message(message: &Message, name: &str, time: DateTime<Local>) -> Self {
+        Record {
+            time: time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            id: Some(message.id),
+            conversation: conversation(message, name),
+            sender: Some(message.get_sender().to_owned()),
+            text: message.get_message().trim_start().to_owned(),
+        }
+    }
+
+    pub fn notice(text: &str, time: DateTime<Local>) -> Self {
+        Record {
+            time: time.to_rfc3339_opts(SecondsFormat::Secs, false),
+            id: None,
+            conversation: String::new(),
+            sender: None,
+            text: text.to_owned(),
+        }
+    }
+
+    fn display_time(&self) -> String {
+        DateTime::parse_from_rfc3339(&self.time)
+            .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|_| self.time.clone())
+    }
+
+    fn indented_text(&self) -> String {
+        self.text.replace('\n', &format!("\n{CONTINUATION_INDENT}"))
+    }
+
+    fn to_text(&self) -> String {
+        match &self.sender {
+            Some(sender) => format!(
+                "[{}] ({}) <{sender}> {}",
+                self.display_time(),
+                self.conversation,
+                self.indented_text()
+            ),
+            None => format!("[{}] * {}", self.display_time(), self.indented_text())
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        match &self.sender {
+            Some(sender) => format!(
+                "- _{}_ ({}) **{sender}**: {}",
+                self.display_time(),
+                self.conversation,
+                self.indented_text()
+            ),
+            None => format!("- _{}_ {}", self.display_time(), self.indented_text())
+        }
+    }
+
+    pub fn format(&self, format: TranscriptFormat) -> io::Result<String> {
+        match format {
+            TranscriptFormat::Text => Ok(self.to_text()),
+            TranscriptFormat::Markdown => Ok(self.to_markdown()),
+            TranscriptFormat::JsonLines => serde_json::to_string(self).map_err(io::Error::from)
+        }
+    }
+}
+
+fn extension(format: TranscriptFormat) -> &'static str {
+    match format {
+        TranscriptFormat::Text => "log",
+        TranscriptFormat::JsonLines => "jsonl",
+        TranscriptFormat::Markdown => "md"
+    }
+}
+
+fn file_stem(conversation: &str) -> String {
+    let stem = match conversation {
+        BROADCAST_NAME => conversation.to_owned(),
+        room if room.starts_with(ROOM_PREFIX) => format!("room-{}", room.trim_start_matches(ROOM_PREFIX)),
+        user => format!("dm-{user}")
+    };
+
+    stem
+        .chars()
+        .map(|ch| match ch.is_alphanumeric() || ch == '-' || ch == '_' {
+            true => ch,
+            false => '_'
+        })
+        .collect()
+}
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!("{stem}.{index}.{extension}"))
+}
+
+/// Shifts `name.ext` to `name.1.ext`, `name.1.ext` to `name.2.ext` and so on,
+/// dropping the oldest file once there are `ROTATIONS` of them.
+fn rotate(path: &Path) -> io::Result<()> {
+    for index in (1..ROTATIONS).rev() {
+        let from = rotated(path, index);
+
+        if from.exists() {
+            fs::rename(from, rotated(path, index + 1))?;
+        }
+    }
+
+    fs::rename(path, rotated(path, 1))
+}
+
+/// Appends received messages to one file per conversation.
+#[derive(Debug)]
+pub struct Transcripts {
+    directory: Option<PathBuf>,
+    format: TranscriptFormat,
+    max_size: u64,
+}
+
+impl Transcripts {
+    pub fn new(directory: Option<PathBuf>, format: TranscriptFormat, max_size: u64) -> Self {
+        Transcripts { directory, format, max_size }
+    }
+
+    /// Markdown files get `title` as a heading when they are started.
+    fn write(&self, mut file: File, title: Option<&str>, lines: &[String]) -> io::Result<()> {
+        if let Some(title) = title.filter(|_| self.format == TranscriptFormat::Markdown) {
+            writeln!(file, "# {title}\n")?;
+        }
+
+        for line in lines {
+            writeln!(file, "{line}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Does nothing unless a transcript directory was configured.
+    pub fn record(&self, record: &Record) -> io::Result<()> {
+        let Some(directory) = &self.directory else {
+            return Ok(());
+        };
+
+        fs::create_dir_all(directory)?;
+
+        let path = directory
+            .join(file_stem(&record.conversation))
+            .with_extension(extension(self.format));
+
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() >= self.max_size) {
+            rotate(&path)?;
+        }
+
+        let fresh = !path.exists();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let title = fresh.then_some(record.conversation.as_str());
+
+        self.write(file, title, &[record.format(self.format)?])
+    }
+
+    /// Writes the given records to `path`, or to a timestamped file
+    /// in the transcript directory (or the working directory) if none is given.
+    pub fn export(&self, records: &[Record], path: Option<PathBuf>) -> io::Result<PathBuf> {
+        let path = path.unwrap_or_else(|| {
+            let name = format!(
+                "{EXPORT_PREFIX}-{}.{}",
+                Local::now().format("%Y%m%d-%H%M%S"),
+                extension(self.format)
+            );
+
+            self.directory.clone().unwrap_or_default().join(name)
+        });
+
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let lines = records
+            .iter()
+            .map(|record| record.format(self.format))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        self.write(File::create(&path)?, Some("Chat export"), &lines)?;
+
+        Ok(path)
+    }
+}
+
+/// Case-insensitive search through every transcript in `directory`, current and rotated.
+/// JSON Lines records are matched on their text and shown in the plain text format.
+pub fn search(directory: &Path, query: &str) -> io::Result<Vec<String>> {
+    let query = query.to_lowercase();
+
+    let mut paths = fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+
+    paths.sort();
+
+    let mut matches = Vec::new();
+
+    for path in paths {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let jsonl = path.extension().is_some_and(|extension| extension == "jsonl");
+
+        for line in BufReader::new(File::open(&path)?).lines() {
+            let line = line?;
+
+            let shown = match jsonl {
+                true => match serde_json::from_str::<Record>(&line) {
+                    Ok(record) if record.text.to_lowercase().contains(&query) => record.to_text(),
+                    _ => continue
+                },
+                false if line.to_lowercase().contains(&query) => line,
+                false => continue
+            };
+
+            matches.push(format!("{file_name}: {shown}"));
+        }
+    }
+
+    Ok(matches)
+}
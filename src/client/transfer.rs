@@ -0,0 +1,834 @@
+use std::{
+    collections::HashMap,
+    future, io,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task,
+};
+
+use crate::common::message::{Protocol, Request, Transfer};
+
+const TCP_CHUNK_SIZE: usize = 8 * 1024;
+const UDP_CHUNK_SIZE: usize = 1024;
+const WINDOW: u64 = 8;
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u32 = 10;
+/// How many retransmit timeouts an offer waits to be accepted or rejected.
+const MAX_OFFER_RETRIES: u32 = 300;
+const PARTIAL_EXTENSION: &str = "part";
+const HASH_LENGTH: usize = 64;
+
+type Key = (String, u64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    Pending,
+    Active,
+    Finished,
+    Failed(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub peer: String,
+    pub id: u64,
+    pub name: String,
+    pub direction: Direction,
+    pub done: u64,
+    pub total: u64,
+    pub stage: Stage
+}
+
+impl Progress {
+    pub fn ratio(&self) -> f64 {
+        match self.total {
+            0 => 1.0,
+            total => self.done as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub requests: Vec<Request>,
+    pub progress: Vec<Progress>
+}
+
+struct Outgoing {
+    name: String,
+    path: PathBuf,
+    size: u64,
+    protocol: Protocol,
+    file: Option<File>,
+    sent: u64,
+    acked: u64,
+    deadline: Instant,
+    retries: u32
+}
+
+impl Outgoing {
+    fn chunk_size(&self) -> usize {
+        match self.protocol {
+            Protocol::Tcp => TCP_CHUNK_SIZE,
+            Protocol::Udp => UDP_CHUNK_SIZE
+        }
+    }
+
+    fn progress(&self, peer: &str, id: u64, stage: Stage) -> Progress {
+        Progress {
+            peer: peer.to_owned(),
+            id,
+            name: self.name.clone(),
+            direction: Direction::Upload,
+            done: self.acked,
+            total: self.size,
+            stage
+        }
+    }
+}
+
+struct Incoming {
+    name: String,
+    size: u64,
+    hash: String,
+    file: Option<File>,
+    received: u64
+}
+
+impl Incoming {
+    fn progress(&self, peer: &str, id: u64, stage: Stage) -> Progress {
+        Progress {
+            peer: peer.to_owned(),
+            id,
+            name: self.name.clone(),
+            direction: Direction::Download,
+            done: self.received,
+            total: self.size,
+            stage
+        }
+    }
+}
+
+/// What a file was hashed for.
+#[derive(Debug)]
+enum Job {
+    Offer { peer: String, path: PathBuf, size: u64, protocol: Protocol },
+    Download { peer: String, id: u64 }
+}
+
+/// A hash computed away from the driver loop, which hands it back to `on_hashed`.
+#[derive(Debug)]
+pub struct Hashed {
+    job: Job,
+    hash: io::Result<String>
+}
+
+pub struct Transfers {
+    outgoing: HashMap<Key, Outgoing>,
+    incoming: HashMap<Key, Incoming>,
+    downloads: PathBuf,
+    max_file_size: u64,
+    hashed_tx: UnboundedSender<Hashed>,
+    hashed_rx: UnboundedReceiver<Hashed>
+}
+
+fn control(peer: &str, transfer: Transfer) -> Request {
+    Request::Transfer {
+        peer: peer.to_owned(),
+        transfer,
+        protocol: Protocol::Tcp
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Blocks for as long as reading the whole file takes, so it runs on a blocking thread.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; TCP_CHUNK_SIZE];
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            length => hasher.update(&buffer[..length])
+        }
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Whether `hash` looks like what `hash_file` produces. Offered hashes name the partial
+/// download, so anything else could point outside the downloads directory.
+fn is_hash(hash: &str) -> bool {
+    hash.len() == HASH_LENGTH && hash.bytes().all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn sanitize(name: &str) -> String {
+    Path::new(name)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("download"))
+}
+
+fn unique_path(directory: &Path, name: &str) -> PathBuf {
+    let mut path = directory.join(name);
+    let mut counter = 1;
+
+    while path.exists() {
+        path = directory.join(format!("{name}.{counter}"));
+        counter += 1;
+    }
+
+    path
+}
+
+impl Transfers {
+    pub fn new(downloads: PathBuf, max_file_size: u64) -> Self {
+        let (hashed_tx, hashed_rx) = mpsc::unbounded_channel();
+
+        Transfers {
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            downloads,
+            max_file_size,
+            hashed_tx,
+            hashed_rx
+        }
+    }
+
+    fn hash_in_background(&self, path: PathBuf, job: Job) {
+        let hashed_tx = self.hashed_tx.clone();
+
+        task::spawn_blocking(move || {
+            let _ignore = hashed_tx.send(Hashed { job, hash: hash_file(&path) });
+        });
+    }
+
+    /// The next file that finished hashing. Cancel safe, like the channel underneath.
+    pub async fn hashed(&mut self) -> Hashed {
+        match self.hashed_rx.recv().await {
+            Some(hashed) => hashed,
+            // Never happens, as the sending end is kept in `self`.
+            None => future::pending().await
+        }
+    }
+
+    /// Where the download of one offer goes until it is complete. Offers of the same file
+    /// from another peer, or with another id, get a partial file of their own.
+    fn partial_path(&self, peer: &str, id: u64, hash: &str) -> io::Result<PathBuf> {
+        if !is_hash(hash) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid file hash"));
+        }
+
+        // Names may hold anything a path must not, so only a digest of them is used.
+        let offer = to_hex(&Sha256::new().chain_update(peer).chain_update(id.to_be_bytes()).finalize());
+
+        Ok(self.downloads.join(format!("{hash}-{}.{PARTIAL_EXTENSION}", &offer[..16])))
+    }
+
+    /// Starts hashing the file. The offer is made by `on_hashed` once the hash is known,
+    /// as its id comes from the hash.
+    pub async fn offer(&mut self, peer: &str, path: &Path, protocol: Protocol) -> io::Result<()> {
+        let size = fs::metadata(path).await?.len();
+
+        if size > self.max_file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "File exceeds the size limit"
+            ));
+        }
+
+        let job = Job::Offer { peer: peer.to_owned(), path: path.to_owned(), size, protocol };
+        self.hash_in_background(path.to_owned(), job);
+
+        Ok(())
+    }
+
+    pub async fn on_hashed(&mut self, hashed: Hashed) -> io::Result<Outcome> {
+        match hashed.job {
+            Job::Offer { peer, path, size, protocol } => Ok(self.make_offer(&peer, path, size, protocol, hashed.hash?)),
+            Job::Download { peer, id } => self.verify(&peer, id, hashed.hash).await
+        }
+    }
+
+    fn make_offer(&mut self, peer: &str, path: PathBuf, size: u64, protocol: Protocol, hash: String) -> Outcome {
+        let id = u64::from_str_radix(&hash[..16], 16).unwrap_or_default();
+        let name = sanitize(&path.to_string_lossy());
+
+        let outgoing = Outgoing {
+            name: name.clone(),
+            path,
+            size,
+            protocol,
+            file: None,
+            sent: 0,
+            acked: 0,
+            deadline: Instant::now() + RETRANSMIT_TIMEOUT,
+            retries: 0
+        };
+
+        let progress = outgoing.progress(peer, id, Stage::Pending);
+        self.outgoing.insert((peer.to_owned(), id), outgoing);
+
+        let request = control(peer, Transfer::Offer { id, name, size, hash });
+
+        Outcome { requests: vec![request], progress: vec![progress] }
+    }
+
+    pub async fn accept(&mut self, peer: &str, id: u64) -> io::Result<(Request, Progress)> {
+        fs::create_dir_all(&self.downloads).await?;
+
+        let key = (peer.to_owned(), id);
+        let hash = match self.incoming.get(&key) {
+            Some(incoming) => incoming.hash.clone(),
+            None => return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No such file offer"
+            ))
+        };
+
+        let partial = self.partial_path(peer, id, &hash)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&partial)
+            .await?;
+
+        let incoming = self.incoming.get_mut(&key).unwrap();
+        let mut offset = file.metadata().await?.len();
+
+        if offset >= incoming.size {
+            file.set_len(0).await?;
+            offset = 0;
+        }
+
+        incoming.file = Some(file);
+        incoming.received = offset;
+
+        let request = control(peer, Transfer::Accept { id, offset });
+        let progress = incoming.progress(peer, id, Stage::Active);
+
+        Ok((request, progress))
+    }
+
+    pub fn reject(&mut self, peer: &str, id: u64) -> Option<(Request, Progress)> {
+        let incoming = self.incoming.remove(&(peer.to_owned(), id))?;
+
+        let request = control(peer, Transfer::Reject { id });
+        let progress = incoming.progress(peer, id, Stage::Failed(String::from("rejected")));
+
+        Some((request, progress))
+    }
+
+    pub async fn handle(&mut self, peer: &str, transfer: Transfer) -> io::Result<Outcome> {
+        match transfer {
+            Transfer::Offer { id, name, size, hash } => Ok(self.on_offer(peer, id, name, size, hash)),
+            Transfer::Accept { id, offset } => self.on_accept(peer, id, offset).await,
+            Transfer::Reject { id } => Ok(self.on_reject(peer, id)),
+            Transfer::Chunk { id, offset, data } => self.on_chunk(peer, id, offset, data).await,
+            Transfer::Ack { id, offset } => self.on_ack(peer, id, offset).await
+        }
+    }
+
+    fn on_offer(&mut self, peer: &str, id: u64, name: String, size: u64, hash: String) -> Outcome {
+        let incoming = Incoming {
+            name: sanitize(&name),
+            size,
+            hash,
+            file: None,
+            received: 0
+        };
+
+        let failure = match (size > self.max_file_size, is_hash(&incoming.hash)) {
+            (true, _) => Some("too large"),
+            (false, false) => Some("invalid offer"),
+            (false, true) => None
+        };
+
+        if let Some(reason) = failure {
+            return Outcome {
+                requests: vec![control(peer, Transfer::Reject { id })],
+                progress: vec![incoming.progress(peer, id, Stage::Failed(String::from(reason)))]
+            };
+        }
+
+        let progress = incoming.progress(peer, id, Stage::Pending);
+        self.incoming.insert((peer.to_owned(), id), incoming);
+
+        Outcome { requests: vec![], progress: vec![progress] }
+    }
+
+    async fn on_accept(&mut self, peer: &str, id: u64, offset: u64) -> io::Result<Outcome> {
+        let key = (peer.to_owned(), id);
+        let Some(outgoing) = self.outgoing.get_mut(&key) else {
+            return Ok(Outcome::default());
+        };
+
+        if offset > outgoing.size {
+            let outgoing = self.outgoing.remove(&key).unwrap();
+            let failed = Stage::Failed(String::from("invalid resume offset"));
+
+            return Ok(Outcome {
+                requests: vec![control(peer, Transfer::Reject { id })],
+                progress: vec![outgoing.progress(peer, id, failed)]
+            });
+        }
+
+        let mut file = File::open(&outgoing.path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        outgoing.file = Some(file);
+        outgoing.sent = offset;
+        outgoing.acked = offset;
+        outgoing.deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+        outgoing.retries = 0;
+
+        let mut requests = vec![];
+
+        if offset == outgoing.size {
+            let chunk = Transfer::Chunk { id, offset, data: vec![] };
+            requests.push(control(peer, chunk));
+        }
+
+        let progress = outgoing.progress(peer, id, Stage::Active);
+        requests.extend(self.pump_one(&key).await?);
+
+        Ok(Outcome { requests, progress: vec![progress] })
+    }
+
+    fn on_reject(&mut self, peer: &str, id: u64) -> Outcome {
+        let key = (peer.to_owned(), id);
+        let failed = Stage::Failed(String::from("rejected"));
+
+        let progress = match self.outgoing.remove(&key) {
+            Some(outgoing) => Some(outgoing.progress(peer, id, failed)),
+            None => self
+                .incoming
+                .remove(&key)
+                .map(|incoming| incoming.progress(peer, id, failed))
+        };
+
+        Outcome { requests: vec![], progress: progress.into_iter().collect() }
+    }
+
+    async fn on_chunk(&mut self, peer: &str, id: u64, offset: u64, data: Vec<u8>) -> io::Result<Outcome> {
+        let key = (peer.to_owned(), id);
+        let Some(incoming) = self.incoming.get_mut(&key) else {
+            return Ok(Outcome::default());
+        };
+
+        let Some(file) = incoming.file.as_mut() else {
+            return Ok(Outcome::default());
+        };
+
+        let end = offset.checked_add(data.len() as u64);
+
+        if offset == incoming.received && end.is_some_and(|end| end <= incoming.size) {
+            file.write_all(&data).await?;
+            incoming.received += data.len() as u64;
+        }
+
+        let ack = control(peer, Transfer::Ack { id, offset: incoming.received });
+
+        if incoming.received < incoming.size {
+            let progress = incoming.progress(peer, id, Stage::Active);
+            return Ok(Outcome { requests: vec![ack], progress: vec![progress] });
+        }
+
+        // Without its file, the download takes no more chunks while it is verified. The
+        // sender learns right away that everything arrived, however long hashing takes.
+        file.flush().await?;
+        incoming.file = None;
+
+        let progress = incoming.progress(peer, id, Stage::Active);
+        let hash = incoming.hash.clone();
+        let partial = self.partial_path(peer, id, &hash)?;
+        self.hash_in_background(partial, Job::Download { peer: peer.to_owned(), id });
+
+        Ok(Outcome { requests: vec![ack], progress: vec![progress] })
+    }
+
+    /// Finishes a complete download once its hash is known.
+    async fn verify(&mut self, peer: &str, id: u64, hash: io::Result<String>) -> io::Result<Outcome> {
+        let Some(incoming) = self.incoming.remove(&(peer.to_owned(), id)) else {
+            return Ok(Outcome::default());
+        };
+
+        let partial = self.partial_path(peer, id, &incoming.hash)?;
+
+        if hash? != incoming.hash {
+            fs::remove_file(&partial).await?;
+
+            let failed = Stage::Failed(String::from("checksum mismatch"));
+            return Ok(Outcome {
+                requests: vec![control(peer, Transfer::Reject { id })],
+                progress: vec![incoming.progress(peer, id, failed)]
+            });
+        }
+
+        fs::rename(&partial, unique_path(&self.downloads, &incoming.name)).await?;
+
+        let progress = incoming.progress(peer, id, Stage::Finished);
+        Ok(Outcome { requests: vec![], progress: vec![progress] })
+    }
+
+    /// Acks past what was sent are ignored, so that `acked` never overtakes `sent`.
+    async fn on_ack(&mut self, peer: &str, id: u64, offset: u64) -> io::Result<Outcome> {
+        let key = (peer.to_owned(), id);
+        let Some(outgoing) = self.outgoing.get_mut(&key) else {
+            return Ok(Outcome::default());
+        };
+
+        if offset > outgoing.acked && offset <= outgoing.sent {
+            outgoing.acked = offset;
+            outgoing.deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+            outgoing.retries = 0;
+        }
+
+        if outgoing.acked >= outgoing.size {
+            let outgoing = self.outgoing.remove(&key).unwrap();
+            let progress = outgoing.progress(peer, id, Stage::Finished);
+            return Ok(Outcome { requests: vec![], progress: vec![progress] });
+        }
+
+        let progress = outgoing.progress(peer, id, Stage::Active);
+        let requests = self.pump_one(&key).await?;
+
+        Ok(Outcome { requests, progress: vec![progress] })
+    }
+
+    async fn pump_one(&mut self, key: &Key) -> io::Result<Vec<Request>> {
+        let Some(outgoing) = self.outgoing.get_mut(key) else {
+            return Ok(vec![]);
+        };
+
+        let expired = Instant::now() > outgoing.deadline;
+
+        if expired {
+            outgoing.deadline = Instant::now() + RETRANSMIT_TIMEOUT;
+            outgoing.retries += 1;
+        }
+
+        let chunk_size = outgoing.chunk_size();
+        let Some(file) = outgoing.file.as_mut() else {
+            return Ok(vec![]);
+        };
+
+        if expired && outgoing.acked < outgoing.size {
+            file.seek(SeekFrom::Start(outgoing.acked)).await?;
+            outgoing.sent = outgoing.acked;
+        }
+
+        let (peer, id) = key;
+        let mut requests = vec![];
+        let mut buffer = vec![0u8; chunk_size];
+
+        while outgoing.sent < outgoing.size
+            && outgoing.sent - outgoing.acked < WINDOW * chunk_size as u64 {
+            let length = file.read(&mut buffer).await?;
+
+            if length == 0 {
+                break;
+            }
+
+            let chunk = Transfer::Chunk {
+                id: *id,
+                offset: outgoing.sent,
+                data: buffer[..length].to_vec()
+            };
+
+            requests.push(Request::Transfer {
+                peer: peer.clone(),
+                transfer: chunk,
+                protocol: outgoing.protocol
+            });
+
+            outgoing.sent += length as u64;
+        }
+
+        Ok(requests)
+    }
+
+    /// Retransmits unacknowledged chunks and drops transfers whose peer stopped answering,
+    /// including offers nobody accepted in time.
+    pub async fn pump(&mut self) -> io::Result<Outcome> {
+        let mut outcome = Outcome::default();
+        let keys = self.outgoing.keys().cloned().collect::<Vec<_>>();
+
+        for key in keys {
+            outcome.requests.extend(self.pump_one(&key).await?);
+
+            let (limit, reason) = match self.outgoing[&key].file {
+                Some(_) => (MAX_RETRIES, "peer not responding"),
+                None => (MAX_OFFER_RETRIES, "not accepted")
+            };
+
+            if self.outgoing[&key].retries > limit {
+                let (peer, id) = &key;
+                let outgoing = self.outgoing.remove(&key).unwrap();
+                let failed = Stage::Failed(String::from(reason));
+
+                outcome.requests.push(control(peer, Transfer::Reject { id: *id }));
+                outcome.progress.push(outgoing.progress(peer, *id, failed));
+            }
+        }
+
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn downloads(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("chat-transfer-{test}-{}", std::process::id()))
+    }
+
+    fn offer(hash: &str) -> Transfer {
+        Transfer::Offer { id: 1, name: String::from("notes.txt"), size: 4, hash: hash.to_owned() }
+    }
+
+    /// What comes of the next file that finishes hashing.
+    async fn hashed(transfers: &mut Transfers) -> Outcome {
+        let hashed = transfers.hashed().await;
+        transfers.on_hashed(hashed).await.unwrap()
+    }
+
+    #[test]
+    fn only_hex_digests_are_hashes() {
+        assert!(is_hash(HASH));
+        assert!(!is_hash(&HASH.to_uppercase()));
+        assert!(!is_hash(&HASH[1..]));
+        assert!(!is_hash("../../.bashrc"));
+        assert!(!is_hash(&format!("../{}", &HASH[3..])));
+    }
+
+    #[tokio::test]
+    async fn offers_with_a_path_for_a_hash_are_rejected() {
+        let directory = downloads("traversal");
+        let mut transfers = Transfers::new(directory.clone(), 1024);
+
+        for hash in ["../../.bashrc", "/etc/passwd", ""] {
+            let outcome = transfers.handle("alice", offer(hash)).await.unwrap();
+
+            assert!(matches!(outcome.requests[..], [Request::Transfer { transfer: Transfer::Reject { id: 1 }, .. }]));
+            assert_eq!(outcome.progress[0].stage, Stage::Failed(String::from("invalid offer")));
+            assert!(transfers.accept("alice", 1).await.is_err());
+        }
+
+        assert!(transfers.partial_path("alice", 1, "../../.bashrc").is_err());
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    #[tokio::test]
+    async fn accepted_offers_download_into_the_directory() {
+        let directory = downloads("download");
+        let mut transfers = Transfers::new(directory.clone(), 1024);
+
+        transfers.handle("alice", offer(HASH)).await.unwrap();
+        transfers.accept("alice", 1).await.unwrap();
+
+        let chunk = Transfer::Chunk { id: 1, offset: 0, data: b"test".to_vec() };
+        let outcome = transfers.handle("alice", chunk).await.unwrap();
+
+        assert!(matches!(outcome.requests[..], [Request::Transfer { transfer: Transfer::Ack { id: 1, offset: 4 }, .. }]));
+        assert_eq!(outcome.progress[0].stage, Stage::Active);
+
+        let chunk = Transfer::Chunk { id: 1, offset: 0, data: b"test".to_vec() };
+        assert!(transfers.handle("alice", chunk).await.unwrap().requests.is_empty());
+
+        assert_eq!(hashed(&mut transfers).await.progress[0].stage, Stage::Finished);
+        assert_eq!(fs::read(directory.join("notes.txt")).await.unwrap(), b"test");
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    #[tokio::test]
+    async fn offers_of_the_same_file_download_apart() {
+        let directory = downloads("same-hash");
+        let mut transfers = Transfers::new(directory.clone(), 1024);
+        let senders = [("alice", 1), ("bob", 1), ("alice", 2)];
+
+        assert_ne!(transfers.partial_path("alice", 1, HASH).unwrap(), transfers.partial_path("bob", 1, HASH).unwrap());
+        assert_ne!(transfers.partial_path("alice", 1, HASH).unwrap(), transfers.partial_path("alice", 2, HASH).unwrap());
+
+        for (peer, id) in senders {
+            let offer = Transfer::Offer { id, name: String::from("notes.txt"), size: 4, hash: HASH.to_owned() };
+            transfers.handle(peer, offer).await.unwrap();
+            transfers.accept(peer, id).await.unwrap();
+        }
+
+        for (peer, id) in senders {
+            let chunk = Transfer::Chunk { id, offset: 0, data: b"te".to_vec() };
+            transfers.handle(peer, chunk).await.unwrap();
+        }
+
+        for (peer, id) in senders {
+            let chunk = Transfer::Chunk { id, offset: 2, data: b"st".to_vec() };
+            transfers.handle(peer, chunk).await.unwrap();
+        }
+
+        for _ in senders {
+            assert_eq!(hashed(&mut transfers).await.progress[0].stage, Stage::Finished);
+        }
+
+        for name in ["notes.txt", "notes.txt.1", "notes.txt.2"] {
+            assert_eq!(fs::read(directory.join(name)).await.unwrap(), b"test");
+        }
+
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    #[tokio::test]
+    async fn chunks_past_the_end_are_not_written() {
+        let directory = downloads("overflow");
+        let mut transfers = Transfers::new(directory.clone(), 1024);
+
+        transfers.handle("alice", offer(HASH)).await.unwrap();
+        transfers.accept("alice", 1).await.unwrap();
+
+        for offset in [u64::MAX - 1, 0] {
+            let chunk = Transfer::Chunk { id: 1, offset, data: b"tests".to_vec() };
+            let outcome = transfers.handle("alice", chunk).await.unwrap();
+
+            assert!(matches!(outcome.requests[..], [Request::Transfer { transfer: Transfer::Ack { id: 1, offset: 0 }, .. }]));
+        }
+
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    /// Offers a file of `size` bytes to alice and returns the transfers, the offer id and the directory.
+    async fn offered(test: &str, size: usize) -> (Transfers, u64, PathBuf) {
+        let directory = downloads(test);
+        fs::create_dir_all(&directory).await.unwrap();
+        let path = directory.join("data.bin");
+        fs::write(&path, vec![7u8; size]).await.unwrap();
+
+        let mut transfers = Transfers::new(directory.clone(), u64::MAX);
+        transfers.offer("alice", &path, Protocol::Udp).await.unwrap();
+        let outcome = hashed(&mut transfers).await;
+
+        let Request::Transfer { transfer: Transfer::Offer { id, .. }, .. } = &outcome.requests[0] else {
+            panic!("expected an offer, got {:?}", outcome.requests);
+        };
+
+        (transfers, *id, directory)
+    }
+
+    fn sent(outcome: &Outcome) -> u64 {
+        outcome
+            .requests
+            .iter()
+            .map(|request| match request {
+                Request::Transfer { transfer: Transfer::Chunk { data, .. }, .. } => data.len() as u64,
+                _ => 0
+            })
+            .sum()
+    }
+
+    #[tokio::test]
+    async fn acks_past_what_was_sent_are_ignored() {
+        let window = WINDOW * UDP_CHUNK_SIZE as u64;
+        let (mut transfers, id, directory) = offered("ack", 2 * window as usize).await;
+
+        let outcome = transfers.handle("alice", Transfer::Accept { id, offset: 0 }).await.unwrap();
+        assert_eq!(sent(&outcome), window);
+
+        let outcome = transfers.handle("alice", Transfer::Ack { id, offset: window + 1 }).await.unwrap();
+        assert_eq!(sent(&outcome), 0);
+        assert_eq!(outcome.progress[0].done, 0);
+
+        let outcome = transfers.handle("alice", Transfer::Ack { id, offset: window }).await.unwrap();
+        assert_eq!(sent(&outcome), window);
+        assert_eq!(outcome.progress[0].done, window);
+
+        let outcome = transfers.handle("alice", Transfer::Ack { id, offset: 2 * window }).await.unwrap();
+        assert_eq!(outcome.progress[0].stage, Stage::Finished);
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    #[tokio::test]
+    async fn corrupted_downloads_are_removed() {
+        let directory = downloads("corrupted");
+        let mut transfers = Transfers::new(directory.clone(), 1024);
+
+        transfers.handle("alice", offer(HASH)).await.unwrap();
+        transfers.accept("alice", 1).await.unwrap();
+        transfers.handle("alice", Transfer::Chunk { id: 1, offset: 0, data: b"tost".to_vec() }).await.unwrap();
+
+        let outcome = hashed(&mut transfers).await;
+
+        assert!(matches!(outcome.requests[..], [Request::Transfer { transfer: Transfer::Reject { id: 1 }, .. }]));
+        assert_eq!(outcome.progress[0].stage, Stage::Failed(String::from("checksum mismatch")));
+        assert!(!transfers.partial_path("alice", 1, HASH).unwrap().exists());
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    #[tokio::test]
+    async fn resuming_past_the_end_is_rejected() {
+        let (mut transfers, id, directory) = offered("resume", 4).await;
+        let outcome = transfers.handle("alice", Transfer::Accept { id, offset: 5 }).await.unwrap();
+
+        assert!(matches!(outcome.requests[..], [Request::Transfer { transfer: Transfer::Reject { .. }, .. }]));
+        assert_eq!(outcome.progress[0].stage, Stage::Failed(String::from("invalid resume offset")));
+        assert!(transfers.outgoing.is_empty());
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+
+    #[tokio::test]
+    async fn unanswered_offers_expire() {
+        let directory = downloads("unanswered");
+        fs::create_dir_all(&directory).await.unwrap();
+        let path = directory.join("notes.txt");
+        fs::write(&path, b"test").await.unwrap();
+
+        let mut transfers = Transfers::new(directory.clone(), 1024);
+        transfers.offer("alice", &path, Protocol::Tcp).await.unwrap();
+        transfers.offer("bob", &path, Protocol::Tcp).await.unwrap();
+        hashed(&mut transfers).await;
+        hashed(&mut transfers).await;
+
+        let lapse = |transfers: &mut Transfers| for outgoing in transfers.outgoing.values_mut() {
+            outgoing.deadline = Instant::now() - RETRANSMIT_TIMEOUT;
+        };
+
+        lapse(&mut transfers);
+        let outcome = transfers.pump().await.unwrap();
+
+        assert!(outcome.requests.is_empty() && outcome.progress.is_empty());
+        assert!(transfers.outgoing.values().all(|outgoing| outgoing.retries == 1));
+
+        transfers.outgoing.values_mut().for_each(|outgoing| outgoing.retries = MAX_OFFER_RETRIES);
+        lapse(&mut transfers);
+        let outcome = transfers.pump().await.unwrap();
+
+        assert!(transfers.outgoing.is_empty());
+        assert_eq!(outcome.requests.len(), 2);
+        assert_eq!(outcome.progress.len(), 2);
+        assert!(outcome.progress.iter().all(|progress| progress.stage == Stage::Failed(String::from("not accepted"))));
+        let _ignore = fs::remove_dir_all(directory).await;
+    }
+}
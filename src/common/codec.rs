@@ -0,0 +1,192 @@
+use std::io;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+const NEWLINE: u8 = b'\n';
+const COBS_DELIMITER: u8 = 0;
+
+fn invalid(reason: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+/// A wire format. Every encoded value is one frame that ends with `DELIMITER`
+/// and contains it nowhere else, so frames can be split off a byte stream.
+pub trait Codec {
+    const DELIMITER: u8;
+
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(frame: &mut [u8]) -> io::Result<T>;
+}
+
+/// Compact binary format, framed with COBS.
+pub struct Postcard;
+
+/// One JSON document per line.
+pub struct Json;
+
+/// CBOR (RFC 8949), framed with COBS like postcard.
+pub struct Cbor;
+
+impl Codec for Postcard {
+    const DELIMITER: u8 = COBS_DELIMITER;
+
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        postcard::to_stdvec_cobs(value).map_err(invalid)
+    }
+
+    fn decode<T: DeserializeOwned>(frame: &mut [u8]) -> io::Result<T> {
+        postcard::from_bytes_cobs(frame).map_err(invalid)
+    }
+}
+
+impl Codec for Json {
+    const DELIMITER: u8 = NEWLINE;
+
+    /// Line breaks inside strings are escaped by the serializer, so the newline stays unique.
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        let mut frame = serde_json::to_vec(value)?;
+        frame.push(NEWLINE);
+        Ok(frame)
+    }
+
+    fn decode<T: DeserializeOwned>(frame: &mut [u8]) -> io::Result<T> {
+        serde_json::from_slice(frame).map_err(invalid)
+    }
+}
+
+impl Codec for Cbor {
+    const DELIMITER: u8 = COBS_DELIMITER;
+
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes).map_err(invalid)?;
+
+        let mut frame = cobs::encode_vec(&bytes);
+        frame.push(COBS_DELIMITER);
+        Ok(frame)
+    }
+
+    fn decode<T: DeserializeOwned>(frame: &mut [u8]) -> io::Result<T> {
+        let frame = frame.strip_suffix(&[COBS_DELIMITER]).unwrap_or(frame);
+        let bytes = cobs::decode_vec(frame).or(Err(invalid("Invalid COBS frame")))?;
+
+        ciborium::from_reader(bytes.as_slice()).map_err(invalid)
+    }
+}
+
+/// The codec used on a connection, agreed on during the handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Postcard,
+    Json,
+    Cbor
+}
+
+impl Encoding {
+    pub fn from(name: &str) -> Option<Self> {
+        match name {
+            "postcard" => Some(Self::Postcard),
+            "json" => Some(Self::Json),
+            "cbor" => Some(Self::Cbor),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Postcard => "postcard",
+            Self::Json => "json",
+            Self::Cbor => "cbor"
+        }
+    }
+
+    pub fn delimiter(self) -> u8 {
+        match self {
+            Self::Postcard => Postcard::DELIMITER,
+            Self::Json => Json::DELIMITER,
+            Self::Cbor => Cbor::DELIMITER
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Postcard => Postcard::encode(value),
+            Self::Json => Json::encode(value),
+            Self::Cbor => Cbor::encode(value)
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, frame: &mut [u8]) -> io::Result<T> {
+        match self {
+            Self::Postcard => Postcard::decode(frame),
+            Self::Json => Json::decode(frame),
+            Self::Cbor => Cbor::decode(frame)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::{Message, Protocol, Response, Transfer};
+
+    use super::*;
+
+    const ENCODINGS: [Encoding; 3] = [Encoding::Postcard, Encoding::Json, Encoding::Cbor];
+
+    fn responses() -> Vec<Response> {
+        vec![
+            Response::Message(Message::new("two\nlines \u{0} and 👍", "alice", "#dev", Protocol::Tcp).replying_to(Some(7))),
+            Response::Transfer {
+                peer: String::from("bob"),
+                transfer: Transfer::Chunk { id: u64::MAX, offset: 0, data: vec![0, b'\n', 0, 255, 0] },
+                protocol: Protocol::Udp
+            },
+            Response::Deleted { id: 0 },
+            Response::Ok("[::1]:8080".parse().unwrap())
+        ]
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        for encoding in ENCODINGS {
+            for response in responses() {
+                let mut frame = encoding.encode(&response).unwrap();
+                let decoded: Response = encoding.decode(&mut frame).unwrap();
+
+                assert_eq!(decoded, response, "{}", encoding.name());
+            }
+        }
+    }
+
+    #[test]
+    fn frames_contain_the_delimiter_only_at_the_end() {
+        for encoding in ENCODINGS {
+            for response in responses() {
+                let frame = encoding.encode(&response).unwrap();
+                let delimiter = encoding.delimiter();
+
+                assert_eq!(frame.iter().position(|byte| *byte == delimiter), Some(frame.len() - 1), "{}", encoding.name());
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_is_invalid_data() {
+        for encoding in ENCODINGS {
+            let mut frame = vec![1, 255, 255, 255, encoding.delimiter()];
+            let error = encoding.decode::<Response>(&mut frame).unwrap_err();
+
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", encoding.name());
+        }
+    }
+
+    #[test]
+    fn encodings_are_named() {
+        for encoding in ENCODINGS {
+            assert_eq!(Encoding::from(encoding.name()), Some(encoding));
+        }
+
+        assert_eq!(Encoding::from("xml"), None);
+    }
+}
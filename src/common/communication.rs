@@ -1,61 +1,268 @@
-use std::io;
+use std::{io, net::SocketAddr, sync::atomic::{AtomicU64, Ordering}};
 
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        UdpSocket
-    }
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UdpSocket
 };
 
-use crate::common::message::Encode;
+use crate::common::{codec::Encoding, message::Encode};
 
 const BUFFER_SIZE: usize = 2048;
 
-pub type Reader<'a> = BufReader<ReadHalf<'a>>;
-pub type Writer<'a> = WriteHalf<'a>;
+/// Every connection starts with the client sending `chat/2 <codec>` on a line of its own.
+/// The server answers with the same line if it speaks that codec, or with `chat/2 unsupported`.
+const HANDSHAKE_PREFIX: &str = "chat/2";
+const HANDSHAKE_UNSUPPORTED: &str = "unsupported";
+const HANDSHAKE_DELIMITER: u8 = b'\n';
+const MAX_HANDSHAKE_LENGTH: usize = 64;
 
-pub async fn send_tcp<T: for<'a> Encode<'a>>(writer: &mut Writer<'_>, content: T) -> io::Result<()> {
-    match content.as_bytes() {
-        Ok(src) => writer.write_all(&src).await,
-        Err(reason) => Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            reason
-        ))
+/// Upper bound for a single TCP frame. Larger frames are skipped up to
+/// the next delimiter and reported as `InvalidData`, so that the stream stays usable.
+pub const MAX_FRAME_SIZE: usize = 256 * 1024;
+
+/// Every datagram from a client starts with the session token the server handed out at
+/// sign in. It tells the server whose datagram it is and keeps others from sending as them.
+pub const SESSION_TOKEN_LENGTH: usize = 32;
+pub const MAX_DATAGRAM_SIZE: usize = SESSION_TOKEN_LENGTH + BUFFER_SIZE;
+
+/// Bytes this process moved over each transport, reported by the server's admin endpoint.
+/// Unix socket connections carry the same frames as TCP ones and are counted with them.
+pub struct Traffic {
+    pub tcp_received: AtomicU64,
+    pub tcp_sent: AtomicU64,
+    pub udp_received: AtomicU64,
+    pub udp_sent: AtomicU64
+}
+
+pub static TRAFFIC: Traffic = Traffic {
+    tcp_received: AtomicU64::new(0),
+    tcp_sent: AtomicU64::new(0),
+    udp_received: AtomicU64::new(0),
+    udp_sent: AtomicU64::new(0)
+};
+
+fn count(counter: &AtomicU64, bytes: usize) {
+    counter.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Frame reader over the read half of a stream connection, TCP or Unix socket.
+/// Partially read frames are kept between calls, so `receive_tcp`
+/// may be safely cancelled inside `tokio::select!`.
+pub struct Reader<'a> {
+    inner: BufReader<Box<dyn AsyncRead + Send + Unpin + 'a>>,
+    buffer: Vec<u8>,
+    oversized: bool,
+    encoding: Encoding,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(inner: impl AsyncRead + Send + Unpin + 'a) -> Self {
+        let inner = BufReader::new(Box::new(inner) as Box<dyn AsyncRead + Send + Unpin + 'a>);
+        let buffer = Vec::with_capacity(BUFFER_SIZE);
+
+        Reader { inner, buffer, oversized: false, encoding: Encoding::default() }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Reads up to and including the next delimiter of the codec in use.
+    async fn read_frame(&mut self) -> io::Result<bool> {
+        self.read_until(self.encoding.delimiter(), MAX_FRAME_SIZE).await
+    }
+
+    /// Reads up to and including `delimiter`, keeping at most `limit` bytes.
+    /// Returns whether a whole frame was read, as opposed to the stream ending.
+    async fn read_until(&mut self, delimiter: u8, limit: usize) -> io::Result<bool> {
+        loop {
+            let available = self.inner.fill_buf().await?;
+
+            if available.is_empty() {
+                return Ok(false);
+            }
+
+            let (chunk, complete) = match available.iter().position(|byte| *byte == delimiter) {
+                Some(index) => (&available[..=index], true),
+                None => (available, false)
+            };
+
+            let length = chunk.len();
+
+            if self.buffer.len() + length > limit {
+                self.oversized = true;
+                self.buffer.clear();
+            }
+
+            if !self.oversized {
+                self.buffer.extend_from_slice(chunk);
+            }
+
+            self.inner.consume(length);
+            count(&TRAFFIC.tcp_received, length);
+
+            if complete {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+/// Frame writer over the write half of a stream connection, encoding with the codec
+/// agreed on for the connection.
+pub struct Writer<'a> {
+    inner: Box<dyn AsyncWrite + Send + Unpin + 'a>,
+    encoding: Encoding,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(inner: impl AsyncWrite + Send + Unpin + 'a) -> Self {
+        Writer { inner: Box::new(inner), encoding: Encoding::default() }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+async fn write(writer: &mut Writer<'_>, bytes: &[u8]) -> io::Result<()> {
+    writer.inner.write_all(bytes).await?;
+    count(&TRAFFIC.tcp_sent, bytes.len());
+
+    Ok(())
+}
+
+fn handshake_line(answer: &str) -> String {
+    format!("{HANDSHAKE_PREFIX} {answer}\n")
+}
+
+async fn read_handshake(reader: &mut Reader<'_>) -> io::Result<String> {
+    let complete = reader.read_until(HANDSHAKE_DELIMITER, MAX_HANDSHAKE_LENGTH).await?;
+    let line = std::mem::take(&mut reader.buffer);
+
+    if !complete || std::mem::take(&mut reader.oversized) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid handshake"));
+    }
+
+    Ok(String::from_utf8_lossy(&line).trim_end().to_owned())
+}
+
+/// Client side of the codec negotiation. Both halves use `encoding` once the server agreed.
+pub async fn offer_encoding(reader: &mut Reader<'_>, writer: &mut Writer<'_>, encoding: Encoding) -> io::Result<()> {
+    let offer = handshake_line(encoding.name());
+    write(writer, offer.as_bytes()).await?;
+
+    if read_handshake(reader).await? != offer.trim_end() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Server does not support the {} codec", encoding.name())
+        ));
     }
+
+    reader.encoding = encoding;
+    writer.encoding = encoding;
+
+    Ok(())
+}
+
+/// Server side of the codec negotiation, returning the codec both halves now use.
+pub async fn accept_encoding(reader: &mut Reader<'_>, writer: &mut Writer<'_>) -> io::Result<Encoding> {
+    let line = read_handshake(reader).await?;
+
+    let encoding = line
+        .strip_prefix(HANDSHAKE_PREFIX)
+        .and_then(|name| Encoding::from(name.trim()));
+
+    let Some(encoding) = encoding else {
+        write(writer, handshake_line(HANDSHAKE_UNSUPPORTED).as_bytes()).await?;
+
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unsupported handshake '{line}'")));
+    };
+
+    write(writer, handshake_line(encoding.name()).as_bytes()).await?;
+
+    reader.encoding = encoding;
+    writer.encoding = encoding;
+
+    Ok(encoding)
+}
+
+/// Whether the content is small enough to be received over UDP in one piece.
+pub fn fits_datagram<T: Encode>(encoding: Encoding, content: &T) -> bool {
+    encoding
+        .encode(content)
+        .is_ok_and(|bytes| bytes.len() <= BUFFER_SIZE)
 }
 
-pub async fn send_udp<T: for<'a> Encode<'a>>(socket: &UdpSocket, content: T) -> io::Result<()> {
-    match content.as_bytes() {
-        Ok(src) => socket
-            .send(&src)
-            .await
-            .map(|_| ()),
-        Err(reason) => Err(io::Error::new(
+pub async fn send_tcp<T: Encode>(writer: &mut Writer<'_>, content: T) -> io::Result<()> {
+    let frame = writer.encoding.encode(&content)?;
+    write(writer, &frame).await
+}
+
+/// Client side, over a socket connected to the server.
+pub async fn send_udp<T: Encode>(socket: &UdpSocket, token: &str, encoding: Encoding, content: T) -> io::Result<()> {
+    let datagram = [token.as_bytes(), &encoding.encode(&content)?].concat();
+
+    let sent = socket.send(&datagram).await?;
+    count(&TRAFFIC.udp_sent, sent);
+
+    Ok(())
+}
+
+/// Client side: a datagram of the token alone, which tells the server where datagrams
+/// for the client should go, also when the address it signed in with is behind NAT.
+pub async fn open_udp(socket: &UdpSocket, token: &str) -> io::Result<()> {
+    let sent = socket.send(token.as_bytes()).await?;
+    count(&TRAFFIC.udp_sent, sent);
+
+    Ok(())
+}
+
+/// Server side, over the one socket it shares between all clients.
+pub async fn send_udp_to<T: Encode>(socket: &UdpSocket, address: SocketAddr, encoding: Encoding, content: T) -> io::Result<()> {
+    let datagram = encoding.encode(&content)?;
+
+    let sent = socket.send_to(&datagram, address).await?;
+    count(&TRAFFIC.udp_sent, sent);
+
+    Ok(())
+}
+
+pub async fn receive_tcp<T: Encode>(reader: &mut Reader<'_>) -> io::Result<T> {
+    let complete = reader.read_frame().await?;
+
+    if !complete && reader.buffer.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Connection closed"
+        ));
+    }
+
+    if std::mem::take(&mut reader.oversized) {
+        return Err(io::Error::new(
             io::ErrorKind::InvalidData,
-            reason
-        ))
+            "Frame too large"
+        ));
     }
+
+    let mut frame = std::mem::take(&mut reader.buffer);
+
+    reader.encoding.decode(&mut frame)
 }
 
-pub async fn receive_tcp<T: for<'a> Encode<'a>>(reader: &mut Reader<'_>) -> io::Result<T> {
-    let mut buffer = Vec::<u8>::with_capacity(BUFFER_SIZE);
-    let _ = reader.read_until(b'\n', &mut buffer).await?;
+/// Server side: the next datagram from any client, token included, and where it came from.
+pub async fn receive_udp_from(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    let (length, source) = socket.recv_from(buffer).await?;
+    count(&TRAFFIC.udp_received, length);
 
-    T::from_bytes(&buffer)
-        .map_err(|reason| io::Error::new(
-            io::ErrorKind::InvalidData, 
-            reason
-        ))
+    Ok((length, source))
 }
 
-pub async fn receive_udp<T: for<'a> Encode<'a>>(socket: &UdpSocket) -> io::Result<T> {
+/// Client side, from the server the socket is connected to.
+pub async fn receive_udp<T: Encode>(socket: &UdpSocket, encoding: Encoding) -> io::Result<T> {
     let mut buffer = vec![0u8; BUFFER_SIZE];
     let length = socket.recv(&mut buffer).await?;
+    count(&TRAFFIC.udp_received, length);
 
-    T::from_bytes(&buffer[..length])
-        .map_err(|reason| io::Error::new(
-            io::ErrorKind::InvalidData, 
-            reason
-        ))
-}
\ No newline at end of file
+    encoding.decode(&mut buffer[..length])
+}
@@ -1,13 +1,27 @@
 use std::{
-    env, 
-    net::{AddrParseError, IpAddr, SocketAddr}, 
-    num::ParseIntError
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr}, 
+    num::ParseIntError,
+    path::PathBuf
 };
 
+use crate::server::is_valid_name;
+
+use super::{codec::Encoding, communication::MAX_FRAME_SIZE};
+
+const DEFAULT_MAX_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024;
+const DEFAULT_DOWNLOADS: &str = "downloads";
+const DEFAULT_INPUT_HISTORY: &str = ".chat_history";
+const DEFAULT_TRANSCRIPT_MAX_SIZE: u64 = 1024 * 1024;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Mode {
     Client,
-    Server
+    Server,
+    /// Searches local transcripts without connecting anywhere.
+    Search
 }
 
 impl Mode {
@@ -15,21 +29,248 @@ impl Mode {
         match arg {
             "client" => Ok(Self::Client),
             "server" => Ok(Self::Server),
+            "search" => Ok(Self::Search),
             _ => Err(ArgError::ModeIncorrect)
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscriptFormat {
+    Text,
+    JsonLines,
+    Markdown
+}
+
+impl TranscriptFormat {
+    fn from(arg: &str) -> Result<Self, ArgError> {
+        match arg {
+            "text" => Ok(Self::Text),
+            "jsonl" => Ok(Self::JsonLines),
+            "markdown" => Ok(Self::Markdown),
+            _ => Err(ArgError::OptionIncorrect)
+        }
+    }
+}
+
+/// How the client draws attention to mentions and direct messages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Alert {
+    Bell,
+    /// Desktop notification understood by iTerm2, kitty, WezTerm and others.
+    Osc9,
+    /// Desktop notification understood by rxvt-unicode and VTE based terminals.
+    Osc777
+}
+
+impl Alert {
+    fn from(arg: &str) -> Result<Self, ArgError> {
+        match arg {
+            "bell" => Ok(Self::Bell),
+            "osc9" => Ok(Self::Osc9),
+            "osc777" => Ok(Self::Osc777),
+            _ => Err(ArgError::OptionIncorrect)
+        }
+    }
+}
+
+/// Where the server reads operator commands from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Console {
+    Stdin,
+    /// A Unix socket only its owner can connect to, one session per connection.
+    Socket(PathBuf)
+}
+
+impl Console {
+    fn from(arg: &str) -> Result<Self, ArgError> {
+        match arg {
+            "" => Err(ArgError::OptionIncorrect),
+            "stdin" => Ok(Self::Stdin),
+            path => Ok(Self::Socket(PathBuf::from(path)))
+        }
+    }
+}
+
+/// Where to POST chat events: `http://host[:port]/path`, optionally preceded by
+/// filters such as `#ops,@ana=`. A filtered webhook only gets messages to one of
+/// the rooms or mentioning one of the names, and joins and leaves of those rooms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    pub rooms: BTreeSet<String>,
+    pub mentions: BTreeSet<String>
+}
+
+impl Webhook {
+    pub const SCHEME: &'static str = "http://";
+
+    fn from(arg: &str) -> Result<Self, ArgError> {
+        let (filters, url) = match arg.starts_with(Self::SCHEME) {
+            true => ("", arg),
+            false => arg.split_once('=').ok_or(ArgError::OptionIncorrect)?
+        };
+
+        if !url.starts_with(Self::SCHEME) {
+            return Err(ArgError::OptionIncorrect);
+        }
+
+        let mut webhook = Webhook { url: url.to_owned(), rooms: BTreeSet::new(), mentions: BTreeSet::new() };
+
+        for filter in list(filters) {
+            match filter.strip_prefix('@') {
+                Some(name) => webhook.mentions.insert(name.to_owned()),
+                None if filter.starts_with('#') => webhook.rooms.insert(filter.to_owned()),
+                None => return Err(ArgError::OptionIncorrect)
+            };
+        }
+
+        Ok(webhook)
+    }
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .filter(|item| !item.is_empty())
+}
+
+/// Secrets are read from a file, as anyone on the machine can see the command line.
+fn secret(path: &str) -> Result<String, ArgError> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|text| text.trim().to_owned())
+        .filter(|text| !text.is_empty())
+        .ok_or(ArgError::OptionIncorrect)
+}
+
+/// Accounts that sign in with a token, from a file of `name=token` lines.
+fn accounts(path: &str) -> Result<BTreeMap<String, String>, ArgError> {
+    secret(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|account| match account.split_once('=') {
+            Some((name, token)) if !name.is_empty() && !token.is_empty() => Ok((name.to_owned(), token.to_owned())),
+            _ => Err(ArgError::OptionIncorrect)
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub mode: Mode,
     pub tcp: SocketAddr,
-    pub name: String
+    /// A Unix socket the server listens on as well, or the client connects to instead.
+    pub unix: Option<PathBuf>,
+    pub name: String,
+    /// What the client signs in to its account with, if the name has one.
+    pub token: Option<String>,
+    pub max_file_size: u64,
+    pub max_message_size: usize,
+    pub downloads: PathBuf,
+    pub input_history: Option<PathBuf>,
+    pub moderators: BTreeMap<String, String>,
+    pub highlights: Vec<String>,
+    pub muted: BTreeSet<String>,
+    pub alerts: Vec<Alert>,
+    pub transcripts: Option<PathBuf>,
+    pub archive: Option<PathBuf>,
+    pub websocket: Option<SocketAddr>,
+    pub irc: Option<SocketAddr>,
+    pub webhooks: Vec<Webhook>,
+    pub incoming_webhooks: Option<SocketAddr>,
+    pub webhook_token: Option<String>,
+    pub bot: Option<String>,
+    pub bot_tokens: BTreeMap<String, String>,
+    pub admin: Option<SocketAddr>,
+    pub console: Option<Console>,
+    pub codec: Encoding,
+    pub transcript_format: TranscriptFormat,
+    pub transcript_max_size: u64,
+    pub query: Option<String>
 }
 
 impl Config {
-    fn new(mode: Mode, tcp: SocketAddr, name: String) -> Self {
-        Config { mode, tcp, name }
+    pub fn new(mode: Mode, tcp: SocketAddr, name: String) -> Self {
+        Config { 
+            mode, 
+            tcp, 
+            unix: None,
+            name,
+            token: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            downloads: PathBuf::from(DEFAULT_DOWNLOADS),
+            input_history: env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(DEFAULT_INPUT_HISTORY)),
+            moderators: BTreeMap::new(),
+            highlights: Vec::new(),
+            muted: BTreeSet::new(),
+            alerts: vec![Alert::Bell],
+            transcripts: None,
+            archive: None,
+            websocket: None,
+            irc: None,
+            webhooks: Vec::new(),
+            incoming_webhooks: None,
+            webhook_token: None,
+            bot: None,
+            bot_tokens: BTreeMap::new(),
+            admin: None,
+            console: None,
+            codec: Encoding::default(),
+            transcript_format: TranscriptFormat::Text,
+            transcript_max_size: DEFAULT_TRANSCRIPT_MAX_SIZE,
+            query: None
+        }
+    }
+
+    fn set_option(&mut self, key: &str, value: &str) -> Result<(), ArgError> {
+        match key {
+            "--max-file-size" => self.max_file_size = value
+                .parse()
+                .or(Err(ArgError::OptionIncorrect))?,
+            "--max-message-size" => self.max_message_size = value
+                .parse::<usize>()
+                .ok()
+                .filter(|size| *size < MAX_FRAME_SIZE)
+                .ok_or(ArgError::OptionIncorrect)?,
+            "--downloads" => self.downloads = PathBuf::from(value),
+            "--input-history" => self.input_history = Some(PathBuf::from(value)),
+            "--moderator-tokens" => self.moderators = accounts(value)?,
+            "--token" => self.token = Some(secret(value)?),
+            "--highlight" => self.highlights = list(value).map(str::to_lowercase).collect(),
+            "--mute" => self.muted = list(value).map(str::to_owned).collect(),
+            "--transcripts" => self.transcripts = Some(PathBuf::from(value)),
+            "--archive" => self.archive = Some(PathBuf::from(value)),
+            "--codec" => self.codec = Encoding::from(value).ok_or(ArgError::OptionIncorrect)?,
+            "--unix" => self.unix = Some(PathBuf::from(value)),
+            "--websocket" => self.websocket = Some(value.parse().or(Err(ArgError::OptionIncorrect))?),
+            "--admin" => self.admin = Some(value.parse().or(Err(ArgError::OptionIncorrect))?),
+            "--console" => self.console = Some(Console::from(value)?),
+            "--irc" => self.irc = Some(value.parse().or(Err(ArgError::OptionIncorrect))?),
+            "--webhook" => self.webhooks.push(Webhook::from(value)?),
+            "--incoming-webhooks" => self.incoming_webhooks = Some(value.parse().or(Err(ArgError::OptionIncorrect))?),
+            "--webhook-token" => self.webhook_token = Some(secret(value)?),
+            "--bot" => self.bot = Some(value)
+                .filter(|name| is_valid_name(name))
+                .map(str::to_owned)
+                .ok_or(ArgError::OptionIncorrect)
+                .map(Some)?,
+            "--bot-tokens" => self.bot_tokens = accounts(value)?,
+            "--transcript-format" => self.transcript_format = TranscriptFormat::from(value)?,
+            "--transcript-max-size" => self.transcript_max_size = value
+                .parse()
+                .or(Err(ArgError::OptionIncorrect))?,
+            "--alert" => self.alerts = match value {
+                "none" => Vec::new(),
+                value => list(value).map(Alert::from).collect::<Result<_, _>>()?
+            },
+            _ => return Err(ArgError::OptionUnknown)
+        }
+
+        Ok(())
     }
 }
 
@@ -42,6 +283,10 @@ pub enum ArgError {
     PortUnspecified,
     PortIncorrect,
     NameUnspecified,
+    QueryUnspecified,
+    OptionUnknown,
+    OptionUnspecified,
+    OptionIncorrect,
 }
 
 impl From<AddrParseError> for ArgError {
@@ -68,15 +313,8 @@ impl Args {
         Args(args)
     }
 
-    pub fn parse(self) -> Result<Config, ArgError> {
-        let args = self.0;
-        
-        let mode = args
-            .get(0)
-            .ok_or(ArgError::ModeUnspecified)?;
-
-        let mode = Mode::from(mode)?;
-
+    /// `<mode> <ip> <port> <name>` for the client and server.
+    fn connection(mode: Mode, args: &[String]) -> Result<Config, ArgError> {
         let ip = args
             .get(1)
             .ok_or(ArgError::AddressUnspecified)?
@@ -94,10 +332,50 @@ impl Args {
             .ok_or(ArgError::NameUnspecified)?
             .to_owned();
 
-        if name.len() == 0 {
+        if name.is_empty() {
             return Err(ArgError::NameUnspecified);
         }
 
         Ok(Config::new(mode, tcp, name))
     }
+
+    /// `search <query>`, which needs no address or name.
+    fn search(mode: Mode, args: &[String]) -> Result<Config, ArgError> {
+        let query = args
+            .get(1)
+            .filter(|query| !query.is_empty())
+            .ok_or(ArgError::QueryUnspecified)?;
+
+        let mut config = Config::new(mode, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), String::new());
+        config.query = Some(query.to_owned());
+
+        Ok(config)
+    }
+
+    pub fn parse(self) -> Result<Config, ArgError> {
+        let args = self.0;
+        
+        let mode = args
+            .first()
+            .ok_or(ArgError::ModeUnspecified)?;
+
+        let mode = Mode::from(mode)?;
+
+        let (mut config, positional) = match mode {
+            Mode::Search => (Self::search(mode, &args)?, 2),
+            _ => (Self::connection(mode, &args)?, 4)
+        };
+
+        let mut options = args.iter().skip(positional);
+
+        while let Some(key) = options.next() {
+            let value = options
+                .next()
+                .ok_or(ArgError::OptionUnspecified)?;
+
+            config.set_option(key, value)?;
+        }
+
+        Ok(config)
+    }
 }
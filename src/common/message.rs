@@ -1,64 +1,147 @@
 use std::{fmt::Display, net::SocketAddr};
 
-use serde::{Serialize, Deserialize};
-use postcard;
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
 
 use crate::client::parser;
 
-pub trait Encode<'a>: Serialize + Deserialize<'a> + Clone {
-    fn from_bytes(bytes: &'a [u8]) -> Result<Self, postcard::Error> {
-        postcard::from_bytes::<Self>(bytes)
-    }
+pub const SERVER_NAME: &str = "server";
 
-    fn as_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
-        let mut bytes = postcard::to_extend(&self, Vec::new())?;
-        bytes.push(b'\n');
-        Ok(bytes)
-    }
-}
+/// Anything that is sent over the wire, in whichever codec the connection uses.
+/// See `docs/protocol.md` for the schema. Postcard identifies variants and fields
+/// by position, so new ones are only ever added at the end of an enum or struct.
+pub trait Encode: Serialize + DeserializeOwned + Clone {}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     SignIn { name: String, udp: SocketAddr },
     SignOut,
-    Send { receiver: String, message: String, protocol: Protocol },
-    SendAll { message: String, protocol: Protocol }
+    Send { receiver: String, message: String, protocol: Protocol, reply_to: Option<u64> },
+    SendAll { message: String, protocol: Protocol, reply_to: Option<u64> },
+    Edit { id: u64, message: String },
+    Delete { id: u64 },
+    React { id: u64, reaction: String },
+    Join { room: String },
+    Leave { room: String },
+    Status { status: Status },
+    Typing { receiver: String },
+    Search { query: Query },
+    Transfer { peer: String, transfer: Transfer, protocol: Protocol },
+    /// Signs in to a bot account, which a plain `SignIn` cannot use.
+    SignInBot { name: String, udp: SocketAddr, token: String }
 }
 
-impl<'a> Encode<'a> for Request {}
+impl Encode for Request {}
 
 impl Request {
-    pub fn to_message(self, sender: &str) -> Option<Message> {
+    pub fn into_message(self, sender: &str) -> Option<Message> {
         match self {
-            Request::Send { receiver, message, protocol } => Some(Message::new(
+            Request::Send { receiver, message, protocol, reply_to } => Some(Message::new(
                 &message, 
                 sender, 
                 &receiver, 
                 protocol
-            )),
-            Request::SendAll { message, protocol } => Some(Message::new(
+            ).replying_to(reply_to)),
+            Request::SendAll { message, protocol, reply_to } => Some(Message::new(
                 &message,
                 sender,
                 parser::BROADCAST_NAME,
                 protocol
-            )),
+            ).replying_to(reply_to)),
+            _ => None
+        }
+    }
+
+    /// Text written by the user, for requests that carry any.
+    pub fn get_message(&self) -> Option<&str> {
+        match self {
+            Self::Send { message, .. }
+            | Self::SendAll { message, .. }
+            | Self::Edit { message, .. } => Some(message),
+            _ => None
+        }
+    }
+
+    pub fn get_protocol(&self) -> Option<Protocol> {
+        match self {
+            Self::Send { protocol, .. } 
+            | Self::SendAll { protocol, .. } 
+            | Self::Transfer { protocol, .. } => Some(*protocol),
+            Self::Typing { .. } => Some(Protocol::Udp),
             _ => None
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+    Busy
+}
+
+impl Status {
+    pub fn from(name: &str) -> Option<Self> {
+        match name {
+            "online" => Some(Self::Online),
+            "away" => Some(Self::Away),
+            "busy" => Some(Self::Busy),
+            _ => None
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Online => write!(f, "online"),
+            Status::Away => write!(f, "away"),
+            Status::Busy => write!(f, "busy")
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Protocol {
     Tcp,
     Udp
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Transfer {
+    Offer { id: u64, name: String, size: u64, hash: String },
+    Accept { id: u64, offset: u64 },
+    Reject { id: u64 },
+    Chunk { id: u64, offset: u64, data: Vec<u8> },
+    Ack { id: u64, offset: u64 }
+}
+
+impl Transfer {
+    pub fn get_id(&self) -> u64 {
+        match self {
+            Self::Offer { id, .. }
+            | Self::Accept { id, .. }
+            | Self::Reject { id }
+            | Self::Chunk { id, .. }
+            | Self::Ack { id, .. } => *id
+        }
+    }
+}
+
+/// A chat line. `id` is assigned by the server when the message is routed
+/// and stays the same across edits, so that clients can refer back to it.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
+    pub id: u64,
     message: String,
     sender: String,
     receiver: String,
-    pub protocol: Protocol
+    pub reply_to: Option<u64>,
+    pub protocol: Protocol,
+    /// Set by the server on its own announcements. Users only ever send requests,
+    /// so unlike the sender's name this cannot be taken by anyone.
+    #[serde(default)]
+    pub from_server: bool
 }
 
 #[allow(dead_code)]
@@ -68,13 +151,30 @@ impl Message {
         let sender = sender.to_owned();
         let receiver = receiver.to_owned();
 
-        Message { message, sender, receiver, protocol }
+        Message { id: 0, message, sender, receiver, reply_to: None, protocol, from_server: false }
+    }
+
+    pub fn replying_to(mut self, reply_to: Option<u64>) -> Self {
+        self.reply_to = reply_to;
+        self
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn set_message(&mut self, message: &str) {
+        self.message = message.to_owned();
     }
 
     pub fn is_broadcast(&self) -> bool {
         self.receiver == parser::BROADCAST_NAME
     }
 
+    pub fn is_room(&self) -> bool {
+        self.receiver.starts_with(parser::ROOM_PREFIX)
+    }
+
     pub fn get_sender(&self) -> &str {
         &self.sender
     }
@@ -84,13 +184,14 @@ impl Message {
     }
 }
 
-impl<'a> Encode<'a> for Message {}
+impl Encode for Message {}
 
 impl Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.receiver[..] {
             parser::BROADCAST_NAME => write!(f, "(all) [{}]: {}", self.sender, self.message),
-            _ => write!(f, "[{}]: {}", self.sender, self.message)
+            room if self.is_room() => write!(f, "({room}) [{}]: {}", self.sender, self.message),
+            receiver => write!(f, "[{} -> {receiver}]: {}", self.sender, self.message)
         }
     }
 }
@@ -99,34 +200,110 @@ impl Display for Message {
 pub enum Error {
     InvalidName,
     InvalidServerResponse,
+    UserNotFound,
+    FileTooLarge,
+    MessageTooLarge,
+    InvalidRequest,
+    MessageNotFound,
+    NotAuthorized,
+    InvalidReaction,
+    SearchFailed,
+}
 
+/// A search through the history stored by the server. Every filter that is set has to match.
+/// Times are Unix timestamps in seconds, `since` inclusive and `until` exclusive.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Query {
+    pub text: String,
+    pub sender: Option<String>,
+    pub room: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub page: u32
+}
+
+/// A stored message that matched a search. `snippet` is the part of the text around
+/// the match, with the matching words between `HIT_START` and `HIT_END`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Hit {
+    pub id: u64,
+    pub time: i64,
+    pub sender: String,
+    pub receiver: String,
+    pub snippet: String
+}
+
+pub const RESULTS_PER_PAGE: u32 = 20;
+pub const HIT_START: &str = "«";
+pub const HIT_END: &str = "»";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Room {
+    pub name: String,
+    pub members: Vec<String>
+}
+
+/// Everyone who reacted to a message with the same emoji.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    pub reaction: String,
+    pub users: Vec<String>
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    pub status: Status
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub users: Vec<User>,
+    pub rooms: Vec<Room>
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Response {
     Ok(SocketAddr),
     Message(Message),
-    Error(Error)
+    Transfer { peer: String, transfer: Transfer, protocol: Protocol },
+    Presence(Presence),
+    Typing { sender: String, receiver: String },
+    Edited { id: u64, message: String },
+    Deleted { id: u64 },
+    Reactions { id: u64, reactions: Vec<Reaction> },
+    Results { query: Query, total: u64, hits: Vec<Hit> },
+    Error(Error),
+    /// Signs in a native client: where the server takes datagrams, and the token each of them starts with.
+    Session { udp: SocketAddr, token: String }
 }
 
-impl<'a> Encode<'a> for Response {}
+impl Encode for Response {}
 
 impl Display for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Response::Ok(addr) => { write!(f, "[server] Logged in; server udp: {addr}") },
+            Response::Ok(addr) | Response::Session { udp: addr, .. } => { write!(f, "[server] Logged in; server udp: {addr}") },
             Response::Error(reason) => { write!(f, "[server] Error: {reason}") },
-            Response::Message(msg) => { write!(f, "{msg}") }
+            Response::Message(msg) => { write!(f, "{msg}") },
+            Response::Transfer { peer, transfer, .. } => { write!(f, "[{peer}] transfer {:x}", transfer.get_id()) },
+            Response::Presence(presence) => { write!(f, "[server] {} users online", presence.users.len()) },
+            Response::Typing { sender, .. } => { write!(f, "[{sender}] is typing") },
+            Response::Edited { id, message } => { write!(f, "[server] Message {id:x} edited: {message}") },
+            Response::Deleted { id } => { write!(f, "[server] Message {id:x} deleted") },
+            Response::Reactions { id, reactions } => { write!(f, "[server] Message {id:x} has {} reactions", reactions.len()) },
+            Response::Results { total, hits, .. } => { write!(f, "[server] Showing {} of {total} search results", hits.len()) }
         }
     }
 }
 
 impl Response {
     pub fn get_protocol(&self) -> Option<Protocol> {
-        if let Self::Message(Message { protocol, .. }) = self {
-            Some(*protocol)
-        } else {
-            None
+        match self {
+            Self::Message(Message { protocol, .. }) 
+            | Self::Transfer { protocol, .. } => Some(*protocol),
+            Self::Typing { .. } => Some(Protocol::Udp),
+            _ => None
         }
     }
 }
@@ -1,3 +1,4 @@
+pub mod codec;
 pub mod config;
 pub mod message;
 pub mod communication;
\ No newline at end of file
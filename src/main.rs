@@ -1,4 +1,3 @@
-use tokio;
 use std::io;
 
 #[macro_use]
@@ -21,6 +20,7 @@ async fn main() -> io::Result<()> {
 
     match config.mode {
         Mode::Client => client::run(config).await,
-        Mode::Server => server::run(config).await
+        Mode::Server => server::run(config).await,
+        Mode::Search => client::search(config)
     }
 }
@@ -0,0 +1,150 @@
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time::timeout,
+};
+
+use super::{
+    http::{self, Status, BAD_REQUEST, METHOD_NOT_ALLOWED, NOT_FOUND, OK, SERVICE_UNAVAILABLE},
+    metrics, State,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longer than this to get hold of the state means routing is stuck.
+const READY_TIMEOUT: Duration = Duration::from_secs(1);
+
+const PROMETHEUS: &str = "text/plain; version=0.0.4; charset=utf-8";
+const TEXT: &str = "text/plain; charset=utf-8";
+const JSON: &str = "application/json";
+
+/// Serves `GET /metrics`, `/health`, `/ready` and `/users`. There is no authentication,
+/// so the listener is meant to be bound to a local address.
+pub async fn listen(state: Arc<Mutex<State>>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let _ignore = serve(state, stream).await;
+        });
+    }
+}
+
+async fn read_path(stream: &mut TcpStream) -> Result<String, Status> {
+    let mut buffer = Vec::new();
+    http::read_head(stream, &mut buffer).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(&buffer).or(Err(BAD_REQUEST))?;
+
+    if request.method != Some("GET") {
+        return Err(METHOD_NOT_ALLOWED);
+    }
+
+    let path = request.path.unwrap_or_default();
+
+    Ok(path.split('?').next().unwrap_or_default().to_owned())
+}
+
+async fn serve(state: Arc<Mutex<State>>, mut stream: TcpStream) -> io::Result<()> {
+    let path = match timeout(REQUEST_TIMEOUT, read_path(&mut stream)).await {
+        Err(_) => return Ok(()),
+        Ok(Err(status)) => return http::respond(&mut stream, status, TEXT, "").await,
+        Ok(Ok(path)) => path
+    };
+
+    let (status, content_type, body) = match path.as_str() {
+        "/metrics" => (OK, PROMETHEUS, metrics::render(&*state.lock().await)),
+        "/health" => (OK, TEXT, String::from("ok\n")),
+        "/ready" => match timeout(READY_TIMEOUT, state.lock()).await {
+            Ok(_) => (OK, TEXT, String::from("ready\n")),
+            Err(_) => (SERVICE_UNAVAILABLE, TEXT, String::from("busy\n"))
+        },
+        "/users" => (OK, JSON, serde_json::to_string(&state.lock().await.sessions())?),
+        _ => (NOT_FOUND, TEXT, String::new())
+    };
+
+    http::respond(&mut stream, status, content_type, &body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{super::{state::Address, tests::state}, *};
+
+    /// Starts the admin endpoint on a free loopback port and returns it with the state it shares.
+    async fn start_server() -> (SocketAddr, Arc<Mutex<State>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(state().await);
+
+        tokio::spawn(listen(state.clone(), listener));
+
+        (address, state)
+    }
+
+    /// Sends one request and returns the status code, the content type and the body of the answer.
+    async fn request(server: SocketAddr, method: &str, path: &str) -> (u16, String, String) {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let head = format!("{method} {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(head.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        timeout(REQUEST_TIMEOUT, stream.read_to_string(&mut response)).await.unwrap().unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.lines();
+        let code = lines.next().unwrap().split_whitespace().nth(1).unwrap().parse().unwrap();
+        let content_type = lines
+            .find_map(|line| line.strip_prefix("Content-Type: "))
+            .unwrap_or_default()
+            .to_owned();
+
+        (code, content_type, body.to_owned())
+    }
+
+    #[tokio::test]
+    async fn health_and_readiness() {
+        let (server, _) = start_server().await;
+
+        assert_eq!(request(server, "GET", "/health").await, (200, TEXT.to_owned(), String::from("ok\n")));
+        assert_eq!(request(server, "GET", "/ready").await, (200, TEXT.to_owned(), String::from("ready\n")));
+    }
+
+    #[tokio::test]
+    async fn metrics_and_users() {
+        let (server, state) = start_server().await;
+        let _alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+        state.lock().await.join("alice", "#ops");
+
+        let (code, content_type, body) = request(server, "GET", "/metrics?format=text").await;
+        assert_eq!((code, content_type.as_str()), (200, PROMETHEUS));
+        assert!(body.lines().any(|line| line == "chat_connected_peers 1"));
+
+        let (code, content_type, body) = request(server, "GET", "/users").await;
+        assert_eq!((code, content_type.as_str()), (200, JSON));
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap(), json!([{
+            "name": "alice",
+            "address": "unix:1",
+            "status": "Online",
+            "rooms": ["#ops"],
+            "queued": 0
+        }]));
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_and_other_methods_are_refused() {
+        let (server, _) = start_server().await;
+
+        assert_eq!(request(server, "GET", "/secrets").await.0, 404);
+        assert_eq!(request(server, "POST", "/health").await.0, 405);
+        assert_eq!(request(server, "DELETE", "/users").await.0, 405);
+    }
+}
@@ -0,0 +1,457 @@
+use std::{collections::BTreeSet, io, path::Path, thread};
+
+use chrono::Utc;
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    client::parser,
+    common::message::{Hit, Message, Query, HIT_END, HIT_START, RESULTS_PER_PAGE},
+};
+
+const SNIPPET_TOKENS: u32 = 24;
+/// Keeps a page of results well below the frame size, however long the messages are.
+const MAX_SNIPPET_LENGTH: usize = 240;
+const SNIPPET_ELLIPSIS: &str = "…";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        time INTEGER NOT NULL,
+        sender TEXT NOT NULL,
+        receiver TEXT NOT NULL,
+        text TEXT NOT NULL,
+        reply_to INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS messages_time ON messages (time);
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+    CREATE TRIGGER IF NOT EXISTS messages_last_id AFTER INSERT ON messages BEGIN
+        INSERT INTO meta (key, value) VALUES ('last_id', new.id)
+            ON CONFLICT (key) DO UPDATE SET value = MAX(value, excluded.value);
+    END;
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_text USING fts5 (
+        text, content = 'messages', content_rowid = 'id'
+    );
+    CREATE TRIGGER IF NOT EXISTS messages_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_text (rowid, text) VALUES (new.id, new.text);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_text (messages_text, rowid, text) VALUES ('delete', old.id, old.text);
+    END;
+    CREATE TRIGGER IF NOT EXISTS messages_update AFTER UPDATE OF text ON messages BEGIN
+        INSERT INTO messages_text (messages_text, rowid, text) VALUES ('delete', old.id, old.text);
+        INSERT INTO messages_text (rowid, text) VALUES (new.id, new.text);
+    END;
+";
+
+/// Every routed message, kept in SQLite with a full-text index over the text,
+/// so that it can still be searched long after it left the in-memory history.
+pub struct Archive {
+    connection: Connection,
+}
+
+/// What one user may find in the archive.
+#[derive(Debug, Clone)]
+pub struct Scope {
+    pub name: String,
+    pub rooms: BTreeSet<String>,
+    /// Messages from or to the name only count after this id. A name without
+    /// a token may have been someone else's before.
+    pub after: u64
+}
+
+enum Command {
+    Store { message: Message, time: i64 },
+    Edit { id: u64, text: String },
+    Delete { id: u64 },
+    Search { query: Query, scope: Scope, results: oneshot::Sender<io::Result<(u64, Vec<Hit>)>> }
+}
+
+/// Runs the archive on a thread of its own, as SQLite blocks. Work is done in the
+/// order it was queued, so a search finds every message stored before it.
+#[derive(Clone)]
+pub struct Archiver {
+    commands: mpsc::UnboundedSender<Command>
+}
+
+impl Archiver {
+    pub fn start(archive: Archive) -> Self {
+        let (commands, mut queue) = mpsc::unbounded_channel();
+
+        thread::spawn(move || {
+            while let Some(command) = queue.blocking_recv() {
+                archive.execute(command);
+            }
+        });
+
+        Archiver { commands }
+    }
+
+    pub fn store(&self, message: &Message) {
+        let _ignore = self.commands.send(Command::Store { message: message.clone(), time: Utc::now().timestamp() });
+    }
+
+    pub fn edit(&self, id: u64, text: &str) {
+        let _ignore = self.commands.send(Command::Edit { id, text: text.to_owned() });
+    }
+
+    pub fn delete(&self, id: u64) {
+        let _ignore = self.commands.send(Command::Delete { id });
+    }
+
+    pub async fn search(&self, query: Query, scope: Scope) -> io::Result<(u64, Vec<Hit>)> {
+        let (results, answer) = oneshot::channel();
+
+        self.commands
+            .send(Command::Search { query, scope, results })
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        answer.await.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?
+    }
+}
+
+/// Turns free text into an FTS5 query that cannot be a syntax error: every word
+/// is quoted and has to be present. A trailing `*` keeps its meaning as a prefix match.
+fn match_expression(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(word) => (word, "*"),
+                None => (word, "")
+            };
+
+            format!("\"{}\"{prefix}", word.replace('"', "\"\""))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn shorten(mut snippet: String) -> String {
+    if let Some((index, _)) = snippet.char_indices().nth(MAX_SNIPPET_LENGTH) {
+        snippet.truncate(index);
+        snippet.push_str(SNIPPET_ELLIPSIS);
+    }
+
+    snippet
+}
+
+impl Archive {
+    /// Without a path the archive only lives as long as the server.
+    pub fn open(path: Option<&Path>) -> rusqlite::Result<Self> {
+        let connection = match path {
+            Some(path) => Connection::open(path)?,
+            None => Connection::open_in_memory()?
+        };
+
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(Archive { connection })
+    }
+
+    /// Highest id handed out so far, so that ids stay unique across restarts.
+    /// It is kept apart from the messages, as the newest of them may have been deleted.
+    /// Archives from before it was kept fall back to the highest id still stored.
+    pub fn last_id(&self) -> rusqlite::Result<u64> {
+        let id = self.connection
+            .query_row(
+                "SELECT MAX(
+                    COALESCE((SELECT value FROM meta WHERE key = 'last_id'), 0),
+                    COALESCE((SELECT MAX(id) FROM messages), 0)
+                )",
+                [],
+                |row| row.get::<_, Option<i64>>(0)
+            )
+            .optional()?
+            .flatten();
+
+        Ok(id.unwrap_or_default() as u64)
+    }
+
+    fn execute(&self, command: Command) {
+        match command {
+            Command::Store { message, time } => if let Err(reason) = self.store(&message, time) {
+                eprintln!("Failed to archive message {}: {reason}", message.id);
+            },
+            Command::Edit { id, text } => if let Err(reason) = self.edit(id, &text) {
+                eprintln!("Failed to archive edit of message {id}: {reason}");
+            },
+            Command::Delete { id } => if let Err(reason) = self.delete(id) {
+                eprintln!("Failed to remove message {id} from the archive: {reason}");
+            },
+            Command::Search { query, scope, results } => {
+                let _ignore = results.send(self.search(&query, &scope).map_err(io::Error::other));
+            }
+        }
+    }
+
+    pub fn store(&self, message: &Message, time: i64) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO messages (id, time, sender, receiver, text, reply_to)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                message.id as i64,
+                time,
+                message.get_sender(),
+                message.get_receiver(),
+                message.get_message(),
+                message.reply_to.map(|id| id as i64)
+            ]
+        )?;
+
+        Ok(())
+    }
+
+    pub fn edit(&self, id: u64, text: &str) -> rusqlite::Result<()> {
+        self.connection.execute("UPDATE messages SET text = ?2 WHERE id = ?1", params![id as i64, text])?;
+        Ok(())
+    }
+
+    pub fn delete(&self, id: u64) -> rusqlite::Result<()> {
+        self.connection.execute("DELETE FROM messages WHERE id = ?1", params![id as i64])?;
+        Ok(())
+    }
+
+    /// One page of the messages matching `query`, newest first, along with how many match in total.
+    /// Only broadcasts, the rooms of the scope and the direct messages its name sent or received are found.
+    pub fn search(&self, query: &Query, scope: &Scope) -> rusqlite::Result<(u64, Vec<Hit>)> {
+        let mut conditions = vec![String::from(
+            "(m.receiver = ? OR ((m.sender = ? OR m.receiver = ?) AND m.id > ? AND substr(m.receiver, 1, 1) <> ?)"
+        )];
+        let mut values = vec![
+            Value::from(parser::BROADCAST_NAME.to_owned()),
+            Value::from(scope.name.clone()),
+            Value::from(scope.name.clone()),
+            Value::from(i64::try_from(scope.after).unwrap_or(i64::MAX)),
+            Value::from(parser::ROOM_PREFIX.to_string())
+        ];
+
+        if !scope.rooms.is_empty() {
+            conditions[0].push_str(&format!(" OR m.receiver IN ({})", vec!["?"; scope.rooms.len()].join(", ")));
+            values.extend(scope.rooms.iter().cloned().map(Value::from));
+        }
+
+        conditions[0].push(')');
+
+        let expression = match_expression(&query.text);
+
+        if !expression.is_empty() {
+            conditions.push(String::from("messages_text MATCH ?"));
+            values.push(Value::from(expression.clone()));
+        }
+
+        if let Some(sender) = &query.sender {
+            conditions.push(String::from("m.sender = ?"));
+            values.push(Value::from(sender.clone()));
+        }
+
+        if let Some(room) = &query.room {
+            conditions.push(String::from("m.receiver = ?"));
+            values.push(Value::from(room.clone()));
+        }
+
+        if let Some(since) = query.since {
+            conditions.push(String::from("m.time >= ?"));
+            values.push(Value::from(since));
+        }
+
+        if let Some(until) = query.until {
+            conditions.push(String::from("m.time < ?"));
+            values.push(Value::from(until));
+        }
+
+        let source = match expression.is_empty() {
+            true => "messages m",
+            false => "messages m JOIN messages_text ON messages_text.rowid = m.id"
+        };
+
+        let filter = conditions.join(" AND ");
+
+        let total = self.connection.query_row(
+            &format!("SELECT COUNT(*) FROM {source} WHERE {filter}"),
+            rusqlite::params_from_iter(values.iter()),
+            |row| row.get::<_, i64>(0)
+        )?;
+
+        let snippet = match expression.is_empty() {
+            true => String::from("m.text"),
+            false => format!(
+                "snippet(messages_text, 0, '{HIT_START}', '{HIT_END}', '{SNIPPET_ELLIPSIS}', {SNIPPET_TOKENS})"
+            )
+        };
+
+        values.push(Value::from(RESULTS_PER_PAGE as i64));
+        values.push(Value::from(query.page as i64 * RESULTS_PER_PAGE as i64));
+
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT m.id, m.time, m.sender, m.receiver, {snippet} FROM {source}
+             WHERE {filter} ORDER BY m.id DESC LIMIT ? OFFSET ?"
+        ))?;
+
+        let hits = statement
+            .query_map(rusqlite::params_from_iter(values.iter()), |row| Ok(Hit {
+                id: row.get::<_, i64>(0)? as u64,
+                time: row.get(1)?,
+                sender: row.get(2)?,
+                receiver: row.get(3)?,
+                snippet: shorten(row.get(4)?)
+            }))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok((total as u64, hits))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::Protocol;
+
+    use super::*;
+
+    /// Stores each `(sender, receiver, text)` with ids counting up from 1.
+    fn archive(messages: &[(&str, &str, &str)]) -> Archive {
+        let archive = Archive::open(None).unwrap();
+
+        for (id, (sender, receiver, text)) in messages.iter().enumerate() {
+            let mut message = Message::new(text, sender, receiver, Protocol::Tcp);
+            message.id = id as u64 + 1;
+            archive.store(&message, 1_700_000_000 + id as i64).unwrap();
+        }
+
+        archive
+    }
+
+    fn query(text: &str) -> Query {
+        Query { text: text.to_owned(), sender: None, room: None, since: None, until: None, page: 0 }
+    }
+
+    fn scope(name: &str, rooms: &[&str], after: u64) -> Scope {
+        Scope { name: name.to_owned(), rooms: rooms.iter().map(|room| room.to_string()).collect(), after }
+    }
+
+    fn ids(archive: &Archive, query: &Query, scope: &Scope) -> Vec<u64> {
+        archive.search(query, scope).unwrap().1.into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn only_broadcasts_joined_rooms_and_own_direct_messages_are_found() {
+        let archive = archive(&[
+            ("ana", "all", "hello everyone"),
+            ("ana", "#ops", "hello ops"),
+            ("ana", "bob", "hello bob"),
+            ("bob", "cat", "hello cat"),
+            ("cat", "#dev", "hello dev")
+        ]);
+
+        assert_eq!(ids(&archive, &query("hello"), &scope("bob", &[], 0)), [4, 3, 1]);
+        assert_eq!(ids(&archive, &query("hello"), &scope("cat", &["#ops"], 0)), [4, 2, 1]);
+        assert_eq!(ids(&archive, &query("hello"), &scope("#ops", &[], 0)), [1]);
+    }
+
+    #[test]
+    fn direct_messages_before_the_sign_in_are_not_found() {
+        let archive = archive(&[
+            ("ana", "bob", "secret one"),
+            ("bob", "ana", "secret two"),
+            ("ana", "all", "secret three"),
+            ("ana", "bob", "secret four")
+        ]);
+
+        assert_eq!(ids(&archive, &query("secret"), &scope("bob", &[], 2)), [4, 3]);
+        assert_eq!(ids(&archive, &query("secret"), &scope("bob", &[], u64::MAX)), [3]);
+    }
+
+    #[test]
+    fn queries_cannot_break_the_match_syntax() {
+        let archive = archive(&[("ana", "all", "say \"quoted\" AND more"), ("ana", "all", "prefixed")]);
+        let everyone = scope("bob", &[], 0);
+
+        assert_eq!(match_expression("a \"b\" c*"), "\"a\" \"\"\"b\"\"\" \"c\"*");
+
+        for text in ["\"quoted\"", "AND", "NEAR(", "*", "(", "-"] {
+            assert!(archive.search(&query(text), &everyone).is_ok(), "{text}");
+        }
+
+        assert_eq!(ids(&archive, &query("prefix*"), &everyone), [2]);
+        assert_eq!(ids(&archive, &query("quoted more"), &everyone), [1]);
+    }
+
+    #[test]
+    fn filters_and_pages() {
+        let messages = (0..45).map(|_| ("ana", "all", "paged")).collect::<Vec<_>>();
+        let archive = archive(&messages);
+        let everyone = scope("bob", &[], 0);
+
+        let mut paged = query("paged");
+        paged.page = 2;
+        let (total, hits) = archive.search(&paged, &everyone).unwrap();
+
+        assert_eq!(total, 45);
+        assert_eq!(hits.iter().map(|hit| hit.id).collect::<Vec<_>>(), [5, 4, 3, 2, 1]);
+
+        let mut window = query("");
+        window.since = Some(1_700_000_010);
+        window.until = Some(1_700_000_012);
+        assert_eq!(ids(&archive, &window, &everyone), [12, 11]);
+
+        window.sender = Some(String::from("bob"));
+        assert!(ids(&archive, &window, &everyone).is_empty());
+    }
+
+    #[test]
+    fn edits_and_deletions_update_the_index() {
+        let archive = archive(&[("ana", "all", "before"), ("ana", "all", "gone")]);
+        let everyone = scope("bob", &[], 0);
+
+        archive.edit(1, "after").unwrap();
+        archive.delete(2).unwrap();
+
+        assert!(ids(&archive, &query("before"), &everyone).is_empty());
+        assert_eq!(ids(&archive, &query("after"), &everyone), [1]);
+        assert!(ids(&archive, &query("gone"), &everyone).is_empty());
+        assert_eq!(archive.last_id().unwrap(), 2);
+    }
+
+    #[test]
+    fn ids_are_not_handed_out_twice() {
+        let path = std::env::temp_dir().join(format!("chat-archive-ids-{}.db", std::process::id()));
+        let _ignore = std::fs::remove_file(&path);
+
+        {
+            let archive = Archive::open(Some(&path)).unwrap();
+            let mut message = Message::new("newest", "ana", "all", Protocol::Tcp);
+            message.id = 7;
+
+            archive.store(&message, 0).unwrap();
+            assert!(archive.store(&message, 0).is_err());
+            archive.delete(7).unwrap();
+        }
+
+        assert_eq!(Archive::open(Some(&path)).unwrap().last_id().unwrap(), 7);
+        let _ignore = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn snippets_mark_matches_and_stay_short() {
+        let long = format!("{} needle {}", "word ".repeat(200), "word ".repeat(200));
+        let archive = archive(&[("ana", "all", &long)]);
+        let (_, hits) = archive.search(&query("needle"), &scope("bob", &[], 0)).unwrap();
+
+        assert!(hits[0].snippet.contains(&format!("{HIT_START}needle{HIT_END}")));
+        assert!(hits[0].snippet.chars().count() <= MAX_SNIPPET_LENGTH + SNIPPET_ELLIPSIS.len());
+    }
+
+    #[tokio::test]
+    async fn searches_see_what_was_queued_before_them() {
+        let archiver = Archiver::start(Archive::open(None).unwrap());
+        let mut message = Message::new("queued", "ana", "all", Protocol::Tcp);
+        message.id = 1;
+
+        archiver.store(&message);
+        let (total, _) = archiver.search(query("queued"), scope("bob", &[], 0)).await.unwrap();
+
+        assert_eq!(total, 1);
+    }
+}
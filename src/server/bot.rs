@@ -0,0 +1,398 @@
+use std::{
+    collections::BTreeSet,
+    io, iter,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{mpsc::{sync_channel, SyncSender}, Arc},
+    thread,
+    time::Duration,
+};
+
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc, Mutex},
+    time::sleep,
+};
+
+use crate::{
+    client::parser,
+    common::message::{Message, Protocol, Request, Response, SERVER_NAME},
+};
+
+use super::{disconnect, handle_request, join, state::{Address, Receiver}, State};
+
+/// The built-in bot has no connection, so any address no client can have will do.
+const BOT_ADDRESS: Address = Address::Ip(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+const COMMAND_PREFIX: char = '!';
+const MAX_REMINDER_DELAY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Hooks waiting for one plugin. A plugin that falls further behind misses what comes next.
+const MAX_QUEUED_HOOKS: usize = 256;
+
+/// Lets plugins post as the bot, also later on from tasks of their own.
+#[derive(Debug, Clone)]
+pub struct Bot {
+    outbox: mpsc::UnboundedSender<Request>
+}
+
+impl Bot {
+    /// `receiver` is a user, a `#room` or `all`, as in `Request::Send`.
+    pub fn say(&self, receiver: &str, text: &str) {
+        let message = text.to_owned();
+
+        let request = match receiver == parser::BROADCAST_NAME {
+            true => Request::SendAll { message, protocol: Protocol::Tcp, reply_to: None },
+            false => Request::Send { receiver: receiver.to_owned(), message, protocol: Protocol::Tcp, reply_to: None }
+        };
+
+        let _ignore = self.outbox.send(request);
+    }
+
+    /// Answers where `message` was said: in its room, to everyone, or directly to the sender.
+    pub fn reply(&self, message: &Message, text: &str) {
+        match message.is_broadcast() || message.is_room() {
+            true => self.say(message.get_receiver(), text),
+            false => self.say(message.get_sender(), text)
+        }
+    }
+}
+
+/// A server-side extension, driven by what the bot sees. Every plugin runs on a thread
+/// of its own, so one that is slow or blocks holds up neither message routing nor the
+/// other plugins. It can still spawn tasks, as `Remind` does.
+pub trait Plugin: Send + 'static {
+    /// The `!commands` it understands, with a short description for `!help`.
+    fn commands(&self) -> Vec<(&'static str, &'static str)> {
+        Vec::new()
+    }
+
+    /// Broadcasts, direct messages to the bot and messages to rooms it is in.
+    fn message_received(&mut self, _bot: &Bot, _message: &Message) {}
+    fn user_joined(&mut self, _bot: &Bot, _name: &str) {}
+    fn user_left(&mut self, _bot: &Bot, _name: &str) {}
+}
+
+#[derive(Debug, Clone)]
+enum Hook {
+    Message(Message),
+    Joined(String),
+    Left(String)
+}
+
+/// Splits `!command arguments` into its two parts.
+pub fn command(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix(COMMAND_PREFIX)?;
+    let (command, arguments) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+
+    Some((command, arguments.trim()))
+}
+
+/// `!help`: lists the commands of every plugin.
+pub struct Help {
+    commands: Vec<(&'static str, &'static str)>
+}
+
+impl Help {
+    const COMMAND: (&'static str, &'static str) = ("help", "Lists what the bot understands");
+}
+
+impl Plugin for Help {
+    fn commands(&self) -> Vec<(&'static str, &'static str)> {
+        vec![Self::COMMAND]
+    }
+
+    fn message_received(&mut self, bot: &Bot, message: &Message) {
+        if command(message.get_message()).is_some_and(|(command, _)| command == Self::COMMAND.0) {
+            let help = self.commands
+                .iter()
+                .map(|(command, description)| format!("{COMMAND_PREFIX}{command}: {description}"))
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            bot.reply(message, &help);
+        }
+    }
+}
+
+/// `!remind 10m stretch`: sends the text back to whoever asked, directly, once the delay is over.
+pub struct Remind;
+
+impl Remind {
+    const USAGE: &'static str = "Usage: !remind <delay> <text>, with a delay such as 90s, 10m, 2h or 1d";
+
+    fn delay(delay: &str) -> Option<Duration> {
+        let unit = delay.chars().last()?;
+        let amount = delay[..delay.len() - unit.len_utf8()].parse::<u64>().ok()?;
+
+        let seconds = match unit {
+            's' => amount,
+            'm' => amount.checked_mul(60)?,
+            'h' => amount.checked_mul(60 * 60)?,
+            'd' => amount.checked_mul(24 * 60 * 60)?,
+            _ => return None
+        };
+
+        Some(Duration::from_secs(seconds)).filter(|delay| !delay.is_zero() && *delay <= MAX_REMINDER_DELAY)
+    }
+}
+
+impl Plugin for Remind {
+    fn commands(&self) -> Vec<(&'static str, &'static str)> {
+        vec![("remind <delay> <text>", "Reminds you after a delay such as 10m")]
+    }
+
+    fn message_received(&mut self, bot: &Bot, message: &Message) {
+        let Some(("remind", arguments)) = command(message.get_message()) else {
+            return;
+        };
+
+        let reminder = arguments
+            .split_once(char::is_whitespace)
+            .and_then(|(delay, text)| Some((Self::delay(delay)?, text.trim())))
+            .filter(|(_, text)| !text.is_empty());
+
+        let Some((delay, text)) = reminder else {
+            bot.reply(message, Self::USAGE);
+            return;
+        };
+
+        let bot = bot.clone();
+        let user = message.get_sender().to_owned();
+        let text = format!("Reminder: {text}");
+
+        tokio::spawn(async move {
+            sleep(delay).await;
+            bot.say(&user, &text);
+        });
+    }
+}
+
+/// The plugins the built-in bot runs, with `!help` covering all of them.
+pub fn plugins() -> Vec<Box<dyn Plugin>> {
+    let mut plugins: Vec<Box<dyn Plugin>> = vec![Box::new(Remind)];
+
+    let commands = iter::once(Help::COMMAND)
+        .chain(plugins.iter().flat_map(|plugin| plugin.commands()))
+        .collect();
+
+    plugins.push(Box::new(Help { commands }));
+    plugins
+}
+
+fn spawn(mut plugin: Box<dyn Plugin>, bot: Bot) -> io::Result<SyncSender<Hook>> {
+    let (hooks, pending) = sync_channel(MAX_QUEUED_HOOKS);
+    let runtime = Handle::current();
+
+    thread::Builder::new()
+        .name(String::from("bot plugin"))
+        .spawn(move || {
+            let _runtime = runtime.enter();
+
+            while let Ok(hook) = pending.recv() {
+                match hook {
+                    Hook::Message(message) => plugin.message_received(&bot, &message),
+                    Hook::Joined(name) => plugin.user_joined(&bot, &name),
+                    Hook::Left(name) => plugin.user_left(&bot, &name)
+                }
+            }
+        })?;
+
+    Ok(hooks)
+}
+
+/// Signs the bot in like any other user, before anyone can take its name, and
+/// hands what it sees to the plugins. An account's name is refused, as the bot
+/// would otherwise act with that account's rights without its token.
+pub async fn start(state: Arc<Mutex<State>>, name: String, plugins: Vec<Box<dyn Plugin>>) -> io::Result<()> {
+    let (internal_rx, users) = {
+        let mut state = state.lock().await;
+
+        if state.is_account(&name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The --bot name belongs to an account"
+            ));
+        }
+
+        let users = state.presence().users.into_iter().map(|user| user.name).collect();
+
+        (state.register(BOT_ADDRESS, &name), users)
+    };
+
+    let (outbox, requests) = mpsc::unbounded_channel();
+    let bot = Bot { outbox };
+
+    let hooks = plugins
+        .into_iter()
+        .map(|plugin| spawn(plugin, bot.clone()))
+        .collect::<io::Result<_>>()?;
+
+    join(&state, &name).await?;
+
+    tokio::spawn(run(state, name, internal_rx, requests, hooks, users));
+
+    Ok(())
+}
+
+async fn run(
+    state: Arc<Mutex<State>>,
+    name: String,
+    mut internal_rx: Receiver,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+    hooks: Vec<SyncSender<Hook>>,
+    mut users: BTreeSet<String>
+) {
+    users.insert(name.clone());
+
+    loop {
+        tokio::select! {
+            response = internal_rx.recv() => {
+                let Some(response) = response else {
+                    break;
+                };
+
+                let fired = match response {
+                    Response::Message(message) if message.get_sender() != name && message.get_sender() != SERVER_NAME => {
+                        vec![Hook::Message(message)]
+                    }
+                    Response::Presence(presence) => {
+                        let now = presence.users.into_iter().map(|user| user.name).collect::<BTreeSet<_>>();
+
+                        let fired = now
+                            .difference(&users)
+                            .cloned()
+                            .map(Hook::Joined)
+                            .chain(users.difference(&now).cloned().map(Hook::Left))
+                            .collect();
+
+                        users = now;
+                        fired
+                    }
+                    _ => Vec::new()
+                };
+
+                let mut dropped = 0;
+
+                for hook in fired {
+                    for plugin in &hooks {
+                        if plugin.try_send(hook.clone()).is_err() {
+                            dropped += 1;
+                        }
+                    }
+                }
+
+                if dropped > 0 {
+                    eprintln!("Bot plugins are falling behind, dropping {dropped} hooks");
+                    state.lock().await.metrics.dropped_hooks += dropped;
+                }
+            }
+
+            Some(request) = requests.recv() => {
+                let _ignore = handle_request(&state, &name, request).await;
+            }
+        }
+    }
+
+    let _ignore = disconnect(&state, &name, &BOT_ADDRESS).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc as blocking;
+
+    use tokio::time::timeout;
+
+    use super::{super::tests::state, *};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Never gets past the first hook until the test is over.
+    struct Stuck(blocking::Receiver<()>);
+
+    impl Plugin for Stuck {
+        fn message_received(&mut self, _bot: &Bot, _message: &Message) {
+            let _ignore = self.0.recv();
+        }
+    }
+
+    struct Recorder(mpsc::UnboundedSender<String>);
+
+    impl Plugin for Recorder {
+        fn message_received(&mut self, _bot: &Bot, message: &Message) {
+            let _ignore = self.0.send(message.get_message().to_owned());
+        }
+    }
+
+    #[test]
+    fn commands_are_split_from_their_arguments() {
+        assert_eq!(command("!remind 10m  tea "), Some(("remind", "10m  tea")));
+        assert_eq!(command("!help"), Some(("help", "")));
+        assert_eq!(command("help"), None);
+    }
+
+    #[test]
+    fn reminder_delays() {
+        assert_eq!(Remind::delay("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(Remind::delay("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(Remind::delay("7d"), Some(MAX_REMINDER_DELAY));
+
+        for delay in ["8d", "0m", "10", "m", "10w", "-1s", "99999999999999999999d", "1é"] {
+            assert_eq!(Remind::delay(delay), None, "{delay}");
+        }
+    }
+
+    /// A plugin stuck in a blocking call misses hooks, and the others carry on.
+    #[tokio::test]
+    async fn a_stuck_plugin_holds_up_nobody_else() {
+        let state = Arc::new(state().await);
+        let (_release, stuck) = blocking::channel();
+        let (seen, mut recorded) = mpsc::unbounded_channel();
+
+        let plugins: Vec<Box<dyn Plugin>> = vec![Box::new(Stuck(stuck)), Box::new(Recorder(seen))];
+        start(state.clone(), String::from("bot"), plugins).await.unwrap();
+        let _alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+
+        for count in 0..MAX_QUEUED_HOOKS + 2 {
+            let text = count.to_string();
+            state.lock().await.broadcast(Message::new(&text, "alice", "all", Protocol::Tcp)).await.unwrap();
+
+            assert_eq!(timeout(TIMEOUT, recorded.recv()).await.unwrap(), Some(text));
+        }
+
+        timeout(TIMEOUT, async {
+            while state.lock().await.metrics.dropped_hooks == 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn accounts_cannot_be_taken_by_the_bot() {
+        let state = Arc::new(state().await);
+
+        let error = start(state.clone(), String::from("mod"), Vec::new()).await.unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(!state.lock().await.is_signed_in("mod"));
+    }
+
+    /// Plugins run outside the runtime, yet can spawn tasks on it.
+    #[tokio::test]
+    async fn reminders_come_back() {
+        let state = Arc::new(state().await);
+
+        start(state.clone(), String::from("bot"), plugins()).await.unwrap();
+        let mut alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+        state.lock().await.send(Message::new("!remind 1s tea", "alice", "bot", Protocol::Tcp)).await.unwrap();
+
+        timeout(TIMEOUT, async {
+            loop {
+                match alice.recv().await {
+                    Some(Response::Message(message)) if message.get_sender() == "bot" => {
+                        return assert_eq!(message.get_message(), "Reminder: tea");
+                    }
+                    Some(_) => continue,
+                    None => panic!("alice was signed out")
+                }
+            }
+        }).await.unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+use std::{
+    io,
+    sync::{atomic::Ordering, Arc},
+};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::UnixListener,
+    sync::{Mutex, Notify},
+};
+
+use crate::common::{communication::TRAFFIC, message::SERVER_NAME};
+
+use super::{announce, metrics::Metrics, send_server_announcement, State};
+
+/// Only the user running the server can connect to the console socket.
+pub const SOCKET_MODE: u32 = 0o600;
+
+const COMMANDS: [(&str, &str); 8] = [
+    ("users", "Lists who is signed in"),
+    ("kick <name>", "Signs a user out"),
+    ("ban <name>", "Signs a user out and keeps the name from signing in again"),
+    ("unban <name>", "Lets a banned name sign in again"),
+    ("announce <text>", "Says something to everyone as the server"),
+    ("stats", "Shows what the server has been doing"),
+    ("shutdown", "Tells everyone, signs them out and stops the server"),
+    ("help", "Lists these commands")
+];
+
+pub async fn listen(state: Arc<Mutex<State>>, listener: UnixListener, shutdown: Arc<Notify>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (reader, writer) = stream.into_split();
+
+        tokio::spawn(serve(state.clone(), BufReader::new(reader), writer, shutdown.clone()));
+    }
+}
+
+/// Runs operator commands, one per line, until the input ends or the server is shut down.
+pub async fn serve(
+    state: Arc<Mutex<State>>,
+    input: impl AsyncBufRead + Unpin,
+    mut output: impl AsyncWrite + Unpin,
+    shutdown: Arc<Notify>
+) -> io::Result<()> {
+    let mut lines = input.lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+
+        let (command, argument) = line
+            .split_once(char::is_whitespace)
+            .map(|(command, argument)| (command, argument.trim()))
+            .unwrap_or((line, ""));
+
+        if command.is_empty() {
+            continue;
+        }
+
+        output.write_all(execute(&state, command, argument).await.as_bytes()).await?;
+        output.flush().await?;
+
+        if command == "shutdown" {
+            shutdown.notify_one();
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn usage(command: &str) -> String {
+    COMMANDS
+        .iter()
+        .find(|(usage, _)| usage.split(' ').next() == Some(command))
+        .map(|(usage, _)| format!("Usage: {usage}\n"))
+        .unwrap_or_else(|| String::from("Unknown command, try help\n"))
+}
+
+/// Tells `name` why before ending the connection.
+async fn remove(state: &Mutex<State>, name: &str, reason: &str) -> bool {
+    let _ignore = announce(state, SERVER_NAME, name, reason).await;
+    state.lock().await.kick(name)
+}
+
+async fn execute(state: &Mutex<State>, command: &str, argument: &str) -> String {
+    match (command, argument) {
+        ("help", _) => COMMANDS
+            .iter()
+            .map(|(usage, description)| format!("{usage:<16} {description}\n"))
+            .collect(),
+        ("users", _) => users(&*state.lock().await),
+        ("stats", _) => stats(&*state.lock().await),
+        ("shutdown", _) => String::from("Shutting down\n"),
+        (_, "") => usage(command),
+        ("kick", name) => match remove(state, name, "You have been removed from the chat").await {
+            true => format!("Kicked {name}\n"),
+            false => format!("{name} is not signed in\n")
+        },
+        ("ban", name) => {
+            let banned = state.lock().await.ban(name);
+            let kicked = remove(state, name, "You have been banned from the chat").await;
+
+            match (banned, kicked) {
+                (_, true) => format!("Banned and kicked {name}\n"),
+                (true, false) => format!("Banned {name}\n"),
+                (false, false) => format!("{name} is already banned\n")
+            }
+        }
+        ("unban", name) => match state.lock().await.unban(name) {
+            true => format!("Unbanned {name}\n"),
+            false => format!("{name} is not banned\n")
+        },
+        ("announce", text) => match send_server_announcement(state, text).await {
+            Ok(()) => String::from("Announced\n"),
+            Err(reason) => format!("Failed to announce: {reason}\n")
+        },
+        _ => usage(command)
+    }
+}
+
+fn users(state: &State) -> String {
+    let sessions = state.sessions();
+
+    if sessions.is_empty() {
+        return String::from("Nobody is signed in\n");
+    }
+
+    sessions
+        .into_iter()
+        .map(|session| format!(
+            "{:<16} {:<24} {:<7} {:>5} queued  {}\n",
+            session.name,
+            session.address.to_string(),
+            session.status.to_string(),
+            session.queued,
+            session.rooms.join(" ")
+        ))
+        .collect()
+}
+
+fn stats(state: &State) -> String {
+    let Metrics { tcp_messages, udp_messages, decode_errors, dropped_sends, dropped_hooks } = state.metrics;
+    let (queued, deepest) = state.queue_depths();
+    let presence = state.presence();
+    let traffic = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+
+    format!(
+        "Users: {}, rooms: {}\n\
+         Messages: {tcp_messages} over TCP, {udp_messages} over UDP\n\
+         Decode errors: {decode_errors}, dropped sends: {dropped_sends}\n\
+         Queued responses: {queued}, at most {deepest} for one peer\n\
+         Webhook events: {} queued, {} dropped\n\
+         Bot plugin hooks dropped: {dropped_hooks}\n\
+         Bytes over TCP: {} received, {} sent\n\
+         Bytes over UDP: {} received, {} sent\n",
+        presence.users.len(),
+        presence.rooms.len(),
+        state.webhooks().queued(),
+        state.webhooks().dropped(),
+        traffic(&TRAFFIC.tcp_received),
+        traffic(&TRAFFIC.tcp_sent),
+        traffic(&TRAFFIC.udp_received),
+        traffic(&TRAFFIC.udp_sent)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::Response;
+
+    use super::{super::{state::{Address, Receiver, SignInError}, tests::state}, *};
+
+    /// Runs the commands of `input` and returns what the console answered.
+    async fn run(state: &Arc<Mutex<State>>, input: &str) -> String {
+        let mut output = Vec::new();
+        serve(state.clone(), input.as_bytes(), &mut output, Arc::new(Notify::new())).await.unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    /// The text of every message left for `receiver`, once its connection is closed.
+    async fn messages_until_closed(receiver: &mut Receiver) -> Vec<String> {
+        let mut messages = Vec::new();
+
+        while let Some(response) = receiver.recv().await {
+            if let Response::Message(message) = response {
+                messages.push(message.get_message().to_owned());
+            }
+        }
+
+        messages
+    }
+
+    #[tokio::test]
+    async fn commands_are_parsed_with_their_arguments() {
+        let state = Arc::new(state().await);
+
+        let output = run(&state, "  \n kick \nfrobnicate alice\nunban   bob  \nhelp\n").await;
+        let mut lines = output.lines();
+
+        assert_eq!(lines.next(), Some("Usage: kick <name>"));
+        assert_eq!(lines.next(), Some("Unknown command, try help"));
+        assert_eq!(lines.next(), Some("bob is not banned"));
+        assert_eq!(lines.count(), COMMANDS.len());
+    }
+
+    #[tokio::test]
+    async fn kicked_users_are_told_and_their_connection_ends() {
+        let state = Arc::new(state().await);
+        let mut alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+
+        assert_eq!(run(&state, "kick alice\nkick bob\n").await, "Kicked alice\nbob is not signed in\n");
+        assert_eq!(messages_until_closed(&mut alice).await, ["You have been removed from the chat"]);
+    }
+
+    #[tokio::test]
+    async fn banned_names_cannot_sign_in_until_unbanned() {
+        let state = Arc::new(state().await);
+        let mut alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+
+        let output = run(&state, "ban alice\nban bob\nban bob\n").await;
+        assert_eq!(output, "Banned and kicked alice\nBanned bob\nbob is already banned\n");
+        assert_eq!(messages_until_closed(&mut alice).await, ["You have been banned from the chat"]);
+
+        state.lock().await.remove("alice", &Address::Unix(1));
+        assert_eq!(state.lock().await.may_sign_in("alice", None), Err(SignInError::Banned));
+        assert_eq!(state.lock().await.may_sign_in("bob", None), Err(SignInError::Banned));
+
+        assert_eq!(run(&state, "unban bob\n").await, "Unbanned bob\n");
+        assert_eq!(state.lock().await.may_sign_in("bob", None), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn announcements_and_stats() {
+        let state = Arc::new(state().await);
+        let mut alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+
+        assert_eq!(run(&state, "announce Back in five minutes\n").await, "Announced\n");
+
+        match alice.recv().await {
+            Some(Response::Message(message)) => {
+                assert!(message.from_server);
+                assert_eq!(message.get_message(), "Back in five minutes");
+            }
+            response => panic!("expected the announcement, got {response:?}")
+        }
+
+        let output = run(&state, "stats\n").await;
+        assert!(output.starts_with("Users: 1, rooms: 0\nMessages: 1 over TCP, 0 over UDP\n"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_reading_and_notifies_the_server() {
+        let state = Arc::new(state().await);
+        let shutdown = Arc::new(Notify::new());
+        let mut output = Vec::new();
+
+        serve(state.clone(), "shutdown\nannounce too late\n".as_bytes(), &mut output, shutdown.clone()).await.unwrap();
+
+        assert_eq!(String::from_utf8(output).unwrap(), "Shutting down\n");
+        shutdown.notified().await;
+    }
+}
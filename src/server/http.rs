@@ -0,0 +1,71 @@
+use std::io;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Just enough HTTP/1.1 for the webhook and admin endpoints: one request per connection.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+pub const MAX_HEADERS: usize = 32;
+const CHUNK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Status(pub u16, pub &'static str);
+
+pub const OK: Status = Status(200, "OK");
+pub const BAD_REQUEST: Status = Status(400, "Bad Request");
+pub const UNAUTHORIZED: Status = Status(401, "Unauthorized");
+pub const NOT_FOUND: Status = Status(404, "Not Found");
+pub const METHOD_NOT_ALLOWED: Status = Status(405, "Method Not Allowed");
+pub const CONFLICT: Status = Status(409, "Conflict");
+pub const LENGTH_REQUIRED: Status = Status(411, "Length Required");
+pub const PAYLOAD_TOO_LARGE: Status = Status(413, "Payload Too Large");
+pub const SERVICE_UNAVAILABLE: Status = Status(503, "Service Unavailable");
+
+/// Reads until `buffer` holds the whole request head, returning its length.
+/// Whatever follows it, such as the start of the body, stays in `buffer`.
+pub async fn read_head(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Result<usize, Status> {
+    loop {
+        read_more(stream, buffer).await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+
+        match httparse::Request::new(&mut headers).parse(buffer).or(Err(BAD_REQUEST))? {
+            httparse::Status::Complete(head) => return Ok(head),
+            httparse::Status::Partial if buffer.len() < MAX_REQUEST_HEAD => continue,
+            httparse::Status::Partial => return Err(BAD_REQUEST)
+        }
+    }
+}
+
+pub async fn read_more(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Result<(), Status> {
+    let mut chunk = [0; CHUNK_SIZE];
+    let read = stream.read(&mut chunk).await.or(Err(BAD_REQUEST))?;
+
+    if read == 0 {
+        return Err(BAD_REQUEST);
+    }
+
+    buffer.extend_from_slice(&chunk[..read]);
+    Ok(())
+}
+
+pub fn header<'a>(request: &httparse::Request<'_, 'a>, name: &str) -> Option<&'a [u8]> {
+    request.headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value)
+}
+
+pub async fn respond(stream: &mut TcpStream, status: Status, content_type: &str, body: &str) -> io::Result<()> {
+    let Status(code, reason) = status;
+
+    let response = format!(
+        "HTTP/1.1 {code} {reason}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
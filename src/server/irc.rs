@@ -0,0 +1,824 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io, iter,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{mpsc, Mutex},
+};
+
+use crate::common::message::{Message, Presence, Protocol, Request, Response, Status, SERVER_NAME};
+
+use super::{disconnect, handle_request, join, state::{is_valid_name, Receiver, SignInError}, State};
+
+/// Broadcasts go to this channel, which every IRC user is on and cannot leave.
+const BROADCAST_CHANNEL: &str = "#all";
+
+/// More lenient than the 512 bytes of RFC 1459, as modern clients send longer lines.
+const MAX_LINE_LENGTH: u64 = 8 * 1024;
+/// Lines read ahead of the one being handled. Beyond that the socket is left unread.
+const MAX_QUEUED_LINES: usize = 64;
+/// Keeps relayed lines within the 512 bytes that IRC clients expect.
+const MAX_TEXT_LENGTH: usize = 400;
+
+/// Members of each channel a user is on.
+type Channels = BTreeMap<String, BTreeSet<String>>;
+
+/// One line from the client, without its prefix.
+struct Command {
+    name: String,
+    params: Vec<String>
+}
+
+impl Command {
+    fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_start();
+
+        if rest.starts_with(':') {
+            rest = rest.split_once(' ')?.1.trim_start();
+        }
+
+        let mut params = Vec::new();
+
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_owned());
+                break;
+            }
+
+            let (param, next) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_owned());
+            rest = next.trim_start();
+        }
+
+        if params.is_empty() {
+            return None;
+        }
+
+        let name = params.remove(0).to_uppercase();
+
+        Some(Command { name, params })
+    }
+
+    fn param(&self, index: usize) -> Option<&str> {
+        self.params.get(index).map(String::as_str)
+    }
+
+    /// A comma-separated list of channels or nicks.
+    fn targets(&self, index: usize) -> impl Iterator<Item = &str> {
+        self.param(index)
+            .unwrap_or_default()
+            .split(',')
+            .filter(|target| !target.is_empty())
+    }
+}
+
+/// Accepts IRC clients. They share the state with native clients: nicks are user names,
+/// `#all` carries broadcasts and every other channel is a room.
+pub async fn listen(state: Arc<Mutex<State>>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let _ignore = process(state, stream, address).await;
+        });
+    }
+}
+
+/// Feeds complete lines into `lines` until the connection closes. A line longer than
+/// `MAX_LINE_LENGTH` is dropped whole, so that its tail is not taken for a command.
+async fn read_lines(read: OwnedReadHalf, lines: mpsc::Sender<String>) -> io::Result<()> {
+    let mut reader = BufReader::new(read);
+
+    loop {
+        let mut line = Vec::new();
+        let mut oversized = false;
+
+        loop {
+            if (&mut reader).take(MAX_LINE_LENGTH).read_until(b'\n', &mut line).await? == 0 {
+                return Ok(());
+            }
+
+            if line.ends_with(b"\n") {
+                break;
+            }
+
+            oversized = true;
+            line.clear();
+        }
+
+        if oversized {
+            continue;
+        }
+
+        let line = String::from_utf8_lossy(&line);
+
+        if lines.send(line.trim_end_matches(['\r', '\n']).to_owned()).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+async fn send(writer: &mut OwnedWriteHalf, lines: &[String]) -> io::Result<()> {
+    let mut bytes = Vec::new();
+
+    for line in lines {
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.extend_from_slice(b"\r\n");
+    }
+
+    writer.write_all(&bytes).await
+}
+
+fn reply(nick: &str, code: &str, text: &str) -> String {
+    format!(":{SERVER_NAME} {code} {nick} {text}")
+}
+
+fn notice(nick: &str, text: &str) -> String {
+    format!(":{SERVER_NAME} NOTICE {nick} :{text}")
+}
+
+fn source(user: &str) -> String {
+    format!(":{user}!{user}@{SERVER_NAME}")
+}
+
+/// IRC lines cannot hold line breaks and are kept short, so text is spread over as many as it takes.
+fn split(text: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+
+    for mut line in text.lines().map(|line| line.trim_end_matches('\r')) {
+        while line.len() > MAX_TEXT_LENGTH {
+            let mut end = MAX_TEXT_LENGTH;
+
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+
+            let (head, tail) = line.split_at(end);
+            parts.push(head.replace('\r', ""));
+            line = tail;
+        }
+
+        if !line.is_empty() {
+            parts.push(line.replace('\r', ""));
+        }
+    }
+
+    parts
+}
+
+/// Everyone on `channel`, or `None` if there is no such channel.
+fn members(presence: &Presence, channel: &str) -> Option<BTreeSet<String>> {
+    if channel == BROADCAST_CHANNEL {
+        return Some(presence.users.iter().map(|user| user.name.clone()).collect());
+    }
+
+    presence.rooms
+        .iter()
+        .find(|room| room.name == channel)
+        .map(|room| room.members.iter().cloned().collect())
+}
+
+fn channels(presence: &Presence, nick: &str) -> Channels {
+    iter::once(BROADCAST_CHANNEL)
+        .chain(presence.rooms.iter().map(|room| room.name.as_str()))
+        .filter_map(|channel| Some((channel.to_owned(), members(presence, channel)?)))
+        .filter(|(_, members)| members.contains(nick))
+        .collect()
+}
+
+fn names(nick: &str, channel: &str, members: &BTreeSet<String>) -> Vec<String> {
+    let members = members.iter().cloned().collect::<Vec<_>>().join(" ");
+
+    vec![
+        reply(nick, "353", &format!("= {channel} :{members}")),
+        reply(nick, "366", &format!("{channel} :End of /NAMES list"))
+    ]
+}
+
+fn who(nick: &str, mask: &str, presence: &Presence) -> Vec<String> {
+    let (channel, users) = match mask.starts_with('#') {
+        true => (mask, members(presence, mask).unwrap_or_default()),
+        false => ("*", BTreeSet::from([mask.to_owned()]))
+    };
+
+    presence.users
+        .iter()
+        .filter(|user| users.contains(&user.name))
+        .map(|user| {
+            let here = match user.status {
+                Status::Online => 'H',
+                Status::Away | Status::Busy => 'G'
+            };
+
+            let name = &user.name;
+            reply(nick, "352", &format!("{channel} {name} {SERVER_NAME} {SERVER_NAME} {name} {here} :0 {name}"))
+        })
+        .chain(iter::once(reply(nick, "315", &format!("{mask} :End of /WHO list"))))
+        .collect()
+}
+
+/// IRC clients keep their own member lists, so presence updates are replayed to them
+/// as the JOIN, PART and QUIT lines that lead from `before` to `after`.
+fn changes(nick: &str, before: &Channels, after: &Channels, presence: &Presence) -> Vec<String> {
+    let online = presence.users.iter().map(|user| &user.name).collect::<BTreeSet<_>>();
+    let mut lines = Vec::new();
+
+    for (channel, members) in after {
+        let Some(previous) = before.get(channel) else {
+            lines.push(format!("{} JOIN {channel}", source(nick)));
+            lines.extend(names(nick, channel, members));
+            continue;
+        };
+
+        for user in members.difference(previous) {
+            lines.push(format!("{} JOIN {channel}", source(user)));
+        }
+
+        for user in previous.difference(members).filter(|user| online.contains(user)) {
+            lines.push(format!("{} PART {channel}", source(user)));
+        }
+    }
+
+    for channel in before.keys().filter(|channel| !after.contains_key(*channel)) {
+        lines.push(format!("{} PART {channel}", source(nick)));
+    }
+
+    let gone = before
+        .values()
+        .flatten()
+        .filter(|user| !online.contains(user))
+        .collect::<BTreeSet<_>>();
+
+    for user in gone {
+        lines.push(format!("{} QUIT :Signed out", source(user)));
+    }
+
+    lines
+}
+
+/// What `nick` sees of a response. Its own messages are not echoed, as IRC clients show
+/// them as soon as they are sent. There is nothing to map typing, transfers and search to.
+fn translate(nick: &str, response: &Response) -> Vec<String> {
+    match response {
+        Response::Message(message) => {
+            let sender = message.get_sender();
+
+            if sender == nick && message.get_receiver() != nick {
+                return Vec::new();
+            }
+
+            let target = target(message, nick);
+
+            let (source, command) = match message.from_server {
+                true => (format!(":{SERVER_NAME}"), "NOTICE"),
+                false => (source(sender), "PRIVMSG")
+            };
+
+            split(message.get_message())
+                .into_iter()
+                .map(|line| format!("{source} {command} {target} :{line}"))
+                .collect()
+        }
+        Response::Edited { .. } | Response::Deleted { .. } | Response::Reactions { .. } | Response::Error(_) => {
+            split(&response.to_string())
+                .into_iter()
+                .map(|line| notice(nick, &line))
+                .collect()
+        }
+        _ => Vec::new()
+    }
+}
+
+/// The channel a message went to, or `nick` for direct messages.
+fn target<'a>(message: &'a Message, nick: &'a str) -> &'a str {
+    if message.is_broadcast() {
+        BROADCAST_CHANNEL
+    } else if message.is_room() {
+        message.get_receiver()
+    } else {
+        nick
+    }
+}
+
+fn send_request(target: &str, text: &str) -> Request {
+    let message = text.to_owned();
+
+    match target == BROADCAST_CHANNEL {
+        true => Request::SendAll { message, protocol: Protocol::Tcp, reply_to: None },
+        false => Request::Send { receiver: target.to_owned(), message, protocol: Protocol::Tcp, reply_to: None }
+    }
+}
+
+/// Waits for NICK and USER. The nick is only claimed once both arrived, so that a client
+/// told it is taken can still pick another one, as IRC clients do. Bots give their token with PASS.
+async fn register(
+    state: &Mutex<State>,
+    lines: &mut mpsc::Receiver<String>,
+    writer: &mut OwnedWriteHalf,
+    address: SocketAddr
+) -> io::Result<(String, Receiver)> {
+    let mut nick = None::<String>;
+    let mut user = false;
+    let mut password = None::<String>;
+
+    while let Some(line) = lines.recv().await {
+        let Some(command) = Command::parse(&line) else {
+            continue;
+        };
+
+        let current = nick.as_deref().unwrap_or("*");
+
+        let replies = match (command.name.as_str(), command.param(0)) {
+            ("NICK", Some(name)) if is_valid_name(name) => {
+                nick = Some(name.to_owned());
+                Vec::new()
+            }
+            ("NICK", Some(name)) => vec![reply(current, "432", &format!("{name} :Erroneous nickname"))],
+            ("NICK", None) => vec![reply(current, "431", ":No nickname given")],
+            ("USER", _) => {
+                user = true;
+                Vec::new()
+            }
+            ("CAP", Some("LS")) => vec![format!(":{SERVER_NAME} CAP * LS :")],
+            ("PASS", Some(token)) => {
+                password = Some(token.to_owned());
+                Vec::new()
+            }
+            ("CAP" | "PASS", _) => Vec::new(),
+            ("PING", token) => vec![format!(":{SERVER_NAME} PONG {SERVER_NAME} :{}", token.unwrap_or_default())],
+            ("QUIT", _) => break,
+            _ => vec![reply(current, "451", ":You have not registered")]
+        };
+
+        send(writer, &replies).await?;
+
+        let Some(name) = nick.clone().filter(|_| user) else {
+            continue;
+        };
+
+        // Clients often send a PASS meant for a bouncer, so it only counts for accounts.
+        let registered = {
+            let mut state = state.lock().await;
+            let password = password.as_deref().filter(|_| state.is_account(&name));
+
+            state.sign_in(address.into(), &name, password)
+        };
+
+        match registered {
+            Ok(internal_rx) => return Ok((name, internal_rx)),
+            Err(SignInError::InUse) => {
+                send(writer, &[reply("*", "433", &format!("{name} :Nickname is already in use"))]).await?;
+                nick = None;
+            }
+            Err(SignInError::InvalidName) => {
+                send(writer, &[reply("*", "432", &format!("{name} :Erroneous nickname"))]).await?;
+                nick = None;
+            }
+            Err(SignInError::Banned) => {
+                send(writer, &[reply("*", "465", ":You are banned from this server")]).await?;
+                break;
+            }
+            Err(SignInError::WrongToken) => {
+                send(writer, &[reply("*", "464", ":Password incorrect")]).await?;
+                break;
+            }
+            Err(SignInError::Closing) => {
+                send(writer, &[String::from("ERROR :Closing link: the server is shutting down")]).await?;
+                break;
+            }
+        }
+    }
+
+    Err(io::ErrorKind::ConnectionAborted.into())
+}
+
+fn welcome(nick: &str) -> Vec<String> {
+    vec![
+        reply(nick, "001", &format!(":Welcome to the chat, {nick}")),
+        reply(nick, "002", &format!(":Your host is {SERVER_NAME}")),
+        reply(nick, "004", &format!("{SERVER_NAME} chat - -")),
+        reply(nick, "422", ":MOTD File is missing")
+    ]
+}
+
+/// Carries out one command and returns the replies, or `None` once the client quits.
+async fn execute(
+    state: &Mutex<State>,
+    nick: &str,
+    presence: &Presence,
+    channels: &Channels,
+    command: Command
+) -> Option<Vec<String>> {
+    let mut replies = Vec::new();
+
+    match command.name.as_str() {
+        "PRIVMSG" | "NOTICE" => match command.param(1) {
+            _ if command.param(0).is_none() => replies.push(reply(nick, "411", ":No recipient given")),
+            None | Some("") => replies.push(reply(nick, "412", ":No text to send")),
+            Some(text) => {
+                for target in command.targets(0) {
+                    if target.starts_with('#') && !channels.contains_key(target) {
+                        replies.push(reply(nick, "404", &format!("{target} :Cannot send to channel")));
+                    } else if !target.starts_with('#') && !presence.users.iter().any(|user| user.name == target) {
+                        replies.push(reply(nick, "401", &format!("{target} :No such nick")));
+                    } else {
+                        let _ignore = handle_request(state, nick, send_request(target, text)).await;
+                    }
+                }
+            }
+        },
+        "JOIN" => {
+            for channel in command.targets(0).filter(|channel| *channel != BROADCAST_CHANNEL) {
+                match channel.starts_with('#') {
+                    true => {
+                        let _ignore = handle_request(state, nick, Request::Join { room: channel.to_owned() }).await;
+                    }
+                    false => replies.push(reply(nick, "403", &format!("{channel} :No such channel")))
+                }
+            }
+        }
+        "PART" => {
+            for channel in command.targets(0) {
+                match channel == BROADCAST_CHANNEL {
+                    true => replies.push(notice(nick, &format!("Everyone stays on {BROADCAST_CHANNEL}"))),
+                    false => {
+                        let _ignore = handle_request(state, nick, Request::Leave { room: channel.to_owned() }).await;
+                    }
+                }
+            }
+        }
+        "NAMES" => {
+            let requested = command.targets(0).map(str::to_owned).collect::<Vec<_>>();
+
+            let requested = match requested.is_empty() {
+                true => channels.keys().cloned().collect(),
+                false => requested
+            };
+
+            for channel in requested {
+                replies.extend(names(nick, &channel, &members(presence, &channel).unwrap_or_default()));
+            }
+        }
+        "WHO" => replies.extend(who(nick, command.param(0).unwrap_or(BROADCAST_CHANNEL), presence)),
+        "AWAY" => {
+            let (status, code, text) = match command.param(0).unwrap_or_default().is_empty() {
+                true => (Status::Online, "305", ":You are no longer marked as being away"),
+                false => (Status::Away, "306", ":You have been marked as being away")
+            };
+
+            let _ignore = handle_request(state, nick, Request::Status { status }).await;
+            replies.push(reply(nick, code, text));
+        }
+        "MODE" => {
+            if let Some(channel) = command.param(0).filter(|target| target.starts_with('#')) {
+                replies.push(reply(nick, "324", &format!("{channel} +")));
+            }
+        }
+        "PING" => replies.push(format!(":{SERVER_NAME} PONG {SERVER_NAME} :{}", command.param(0).unwrap_or_default())),
+        "NICK" => replies.push(notice(nick, "Nicknames cannot be changed")),
+        "USER" | "PASS" => replies.push(reply(nick, "462", ":You may not reregister")),
+        "PONG" | "CAP" => {}
+        "QUIT" => return None,
+        name => replies.push(reply(nick, "421", &format!("{name} :Unknown command")))
+    }
+
+    Some(replies)
+}
+
+async fn process(state: Arc<Mutex<State>>, stream: TcpStream, address: SocketAddr) -> io::Result<()> {
+    let (read, mut writer) = stream.into_split();
+    let (lines_tx, mut lines) = mpsc::channel(MAX_QUEUED_LINES);
+    let reader = tokio::spawn(read_lines(read, lines_tx));
+
+    let (nick, mut internal_rx) = match register(&state, &mut lines, &mut writer, address).await {
+        Ok(registered) => registered,
+        Err(reason) => {
+            reader.abort();
+            return Err(reason);
+        }
+    };
+
+    println!("{} @ {} connected over IRC", nick, address);
+
+    let mut presence = Presence::default();
+    let mut joined = Channels::new();
+
+    let result = async {
+        send(&mut writer, &welcome(&nick)).await?;
+        join(&state, &nick).await?;
+
+        loop {
+            tokio::select! {
+                response = internal_rx.recv() => {
+                    let Some(response) = response else {
+                        break;
+                    };
+
+                    let replies = match response {
+                        Response::Presence(update) => {
+                            let channels = channels(&update, &nick);
+                            let replies = changes(&nick, &joined, &channels, &update);
+
+                            joined = channels;
+                            presence = update;
+                            replies
+                        }
+                        response => translate(&nick, &response)
+                    };
+
+                    send(&mut writer, &replies).await?;
+                }
+
+                line = lines.recv() => {
+                    let Some(line) = line else {
+                        break;
+                    };
+
+                    let Some(command) = Command::parse(&line) else {
+                        continue;
+                    };
+
+                    match execute(&state, &nick, &presence, &joined, command).await {
+                        Some(replies) => send(&mut writer, &replies).await?,
+                        None => break
+                    }
+                }
+            }
+        }
+
+        io::Result::Ok(())
+    }.await;
+
+    reader.abort();
+    internal_rx.close();
+    disconnect(&state, &nick, &address.into()).await?;
+
+    println!("{} @ {} disconnected", nick, address);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::Lines, time::timeout};
+
+    use crate::{client::parser, common::message::{Error, Room, User}};
+
+    use super::{super::tests::state, *};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn presence(users: &[&str], rooms: &[(&str, &[&str])]) -> Presence {
+        let users = users
+            .iter()
+            .map(|name| User { name: name.to_string(), status: Status::Online })
+            .collect();
+
+        let rooms = rooms
+            .iter()
+            .map(|(name, members)| Room {
+                name: name.to_string(),
+                members: members.iter().map(|member| member.to_string()).collect()
+            })
+            .collect();
+
+        Presence { users, rooms }
+    }
+
+    /// Starts an IRC listener on a free loopback port and returns it with the fresh state it shares.
+    async fn start_server() -> (SocketAddr, Arc<Mutex<State>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(state().await);
+
+        tokio::spawn(listen(state.clone(), listener));
+
+        (address, state)
+    }
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf
+    }
+
+    impl Client {
+        async fn connect(server: SocketAddr) -> Self {
+            let (read, writer) = TcpStream::connect(server).await.unwrap().into_split();
+
+            Client { lines: BufReader::new(read).lines(), writer }
+        }
+
+        async fn send(&mut self, line: &str) {
+            self.writer.write_all(format!("{line}\r\n").as_bytes()).await.unwrap();
+        }
+
+        /// Skips lines until one contains `text`.
+        async fn expect(&mut self, text: &str) -> String {
+            let found = timeout(TIMEOUT, async {
+                loop {
+                    match self.lines.next_line().await.unwrap() {
+                        Some(line) if line.contains(text) => return line,
+                        Some(_) => continue,
+                        None => panic!("connection closed while waiting for {text}")
+                    }
+                }
+            });
+
+            found.await.unwrap_or_else(|_| panic!("timed out waiting for {text}"))
+        }
+
+        /// Sends `lines` and then NICK and USER for `nick`, and waits for the reply with `text`.
+        async fn try_register(server: SocketAddr, lines: &[&str], nick: &str, text: &str) -> String {
+            let mut client = Client::connect(server).await;
+
+            for line in lines {
+                client.send(line).await;
+            }
+
+            client.send(&format!("NICK {nick}")).await;
+            client.send(&format!("USER {nick} 0 * :{nick}")).await;
+            client.expect(text).await
+        }
+
+        async fn register(server: SocketAddr, nick: &str) -> Self {
+            let mut client = Client::connect(server).await;
+
+            client.send(&format!("NICK {nick}")).await;
+            client.send(&format!("USER {nick} 0 * :{nick}")).await;
+            client.expect(&format!("001 {nick}")).await;
+            client.expect(&format!("{} JOIN {BROADCAST_CHANNEL}", source(nick))).await;
+
+            client
+        }
+    }
+
+    #[test]
+    fn commands_are_parsed_with_prefix_and_trailing_parameter() {
+        let command = Command::parse(":ana!ana@host privmsg  #ops,bob :hello there ").unwrap();
+
+        assert_eq!(command.name, "PRIVMSG");
+        assert_eq!(command.params, ["#ops,bob", "hello there "]);
+        assert_eq!(command.targets(0).collect::<Vec<_>>(), ["#ops", "bob"]);
+        assert_eq!(command.param(2), None);
+
+        assert_eq!(Command::parse("QUIT").unwrap().params, Vec::<String>::new());
+        assert!(Command::parse("").is_none());
+        assert!(Command::parse(":prefix").is_none());
+    }
+
+    #[tokio::test]
+    async fn lines_are_only_read_as_fast_as_they_are_handled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (read, _write) = listener.accept().await.unwrap().0.into_split();
+        let (lines_tx, mut lines) = mpsc::channel(2);
+
+        tokio::spawn(read_lines(read, lines_tx.clone()));
+        client.write_all("PING x\r\n".repeat(100).as_bytes()).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(lines_tx.capacity(), 0);
+
+        for _ in 0..100 {
+            assert_eq!(timeout(TIMEOUT, lines.recv()).await.unwrap().unwrap(), "PING x");
+        }
+    }
+
+    #[test]
+    fn text_is_split_into_short_lines() {
+        assert_eq!(split("one\r\ntwo\n\nthree"), ["one", "two", "three"]);
+
+        let long = "é".repeat(MAX_TEXT_LENGTH);
+        let parts = split(&long);
+
+        assert!(parts.iter().all(|part| part.len() <= MAX_TEXT_LENGTH));
+        assert_eq!(parts.concat(), long);
+    }
+
+    #[test]
+    fn responses_are_translated() {
+        let direct = Message::new("hi", "bob", "ana", Protocol::Tcp);
+        let room = Message::new("one\ntwo", "bob", "#ops", Protocol::Tcp);
+        let own = Message::new("mine", "ana", "#ops", Protocol::Tcp);
+        let mut announcement = Message::new("bob has joined the chat", SERVER_NAME, parser::BROADCAST_NAME, Protocol::Tcp);
+        announcement.from_server = true;
+
+        assert_eq!(translate("ana", &Response::Message(direct)), [":bob!bob@server PRIVMSG ana :hi"]);
+        assert_eq!(
+            translate("ana", &Response::Message(room)),
+            [":bob!bob@server PRIVMSG #ops :one", ":bob!bob@server PRIVMSG #ops :two"]
+        );
+        assert!(translate("ana", &Response::Message(own)).is_empty());
+        assert_eq!(
+            translate("ana", &Response::Message(announcement)),
+            [":server NOTICE #all :bob has joined the chat"]
+        );
+        assert_eq!(
+            translate("ana", &Response::Error(Error::NotAuthorized)),
+            [notice("ana", &Response::Error(Error::NotAuthorized).to_string())]
+        );
+        assert!(translate("ana", &Response::Typing { sender: String::from("bob"), receiver: String::from("ana") }).is_empty());
+    }
+
+    #[test]
+    fn presence_changes_become_joins_parts_and_quits() {
+        let before = presence(&["ana", "bob", "cat"], &[("#ops", &["ana", "bob"]), ("#dev", &["ana"])]);
+        let after = presence(&["ana", "bob", "dan"], &[("#ops", &["ana", "dan"]), ("#new", &["ana"])]);
+        let lines = changes("ana", &channels(&before, "ana"), &channels(&after, "ana"), &after);
+
+        assert_eq!(lines, [
+            ":dan!dan@server JOIN #all",
+            ":ana!ana@server JOIN #new",
+            ":server 353 ana = #new :ana",
+            ":server 366 ana #new :End of /NAMES list",
+            ":dan!dan@server JOIN #ops",
+            ":bob!bob@server PART #ops",
+            ":ana!ana@server PART #dev",
+            ":cat!cat@server QUIT :Signed out"
+        ]);
+    }
+
+    #[tokio::test]
+    async fn registration_welcomes_and_joins_everyone() {
+        let (server, _) = start_server().await;
+        let mut ana = Client::register(server, "ana").await;
+
+        ana.send("WHO #all").await;
+        ana.expect("352 ana #all ana server server ana H").await;
+        ana.expect("315 ana #all").await;
+    }
+
+    #[tokio::test]
+    async fn a_nick_in_use_can_be_retried() {
+        let (server, _) = start_server().await;
+        let _ana = Client::register(server, "ana").await;
+        let mut other = Client::connect(server).await;
+
+        other.send("NICK ana").await;
+        other.send("USER ana 0 * :ana").await;
+        other.expect("433 * ana :Nickname is already in use").await;
+
+        other.send("NICK bob").await;
+        other.expect("001 bob").await;
+    }
+
+    #[tokio::test]
+    async fn refused_registrations_say_why() {
+        let (server, state) = start_server().await;
+        state.lock().await.ban("eve");
+
+        assert_eq!(Client::try_register(server, &[], "eve", "465").await, ":server 465 * :You are banned from this server");
+        assert_eq!(Client::try_register(server, &[], "mod", "464").await, ":server 464 * :Password incorrect");
+        assert_eq!(Client::try_register(server, &["PASS wrong"], "mod", "464").await, ":server 464 * :Password incorrect");
+        Client::try_register(server, &["PASS secret"], "mod", "001 mod").await;
+        Client::try_register(server, &["PASS bouncer"], "ana", "001 ana").await;
+
+        state.lock().await.close();
+        Client::try_register(server, &[], "bob", "ERROR :Closing link").await;
+    }
+
+    #[tokio::test]
+    async fn channel_messages_reach_the_other_members() {
+        let (server, _) = start_server().await;
+        let mut ana = Client::register(server, "ana").await;
+        let mut bob = Client::register(server, "bob").await;
+
+        ana.send("JOIN #ops").await;
+        ana.expect(&format!("{} JOIN #ops", source("ana"))).await;
+        bob.send("JOIN #ops").await;
+        bob.expect(&format!("{} JOIN #ops", source("bob"))).await;
+        ana.expect(&format!("{} JOIN #ops", source("bob"))).await;
+
+        ana.send("PRIVMSG #ops :hello ops").await;
+        bob.expect(&format!("{} PRIVMSG #ops :hello ops", source("ana"))).await;
+
+        bob.send("PRIVMSG #nowhere :hello").await;
+        bob.expect("404 bob #nowhere :Cannot send to channel").await;
+    }
+
+    #[tokio::test]
+    async fn quitting_signs_out() {
+        let (server, _) = start_server().await;
+        let mut ana = Client::register(server, "ana").await;
+        let mut bob = Client::register(server, "bob").await;
+
+        ana.expect(&format!("{} JOIN {BROADCAST_CHANNEL}", source("bob"))).await;
+        bob.send("QUIT :bye").await;
+        ana.expect(&format!("{} QUIT :Signed out", source("bob"))).await;
+
+        let mut again = Client::register(server, "bob").await;
+        again.send("PING x").await;
+        again.expect("PONG server :x").await;
+    }
+}
@@ -0,0 +1,135 @@
+use std::{fmt::Write, sync::atomic::Ordering};
+
+use crate::common::{communication::TRAFFIC, message::Protocol};
+
+use super::State;
+
+/// Counters for the admin endpoint. They live in `State`, so they are updated under its lock.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    pub tcp_messages: u64,
+    pub udp_messages: u64,
+    pub decode_errors: u64,
+    /// Responses that could not be queued because the connection was already gone.
+    pub dropped_sends: u64,
+    /// What the built-in bot saw but could not pass on to plugins that fell behind.
+    pub dropped_hooks: u64
+}
+
+impl Metrics {
+    pub fn count(&mut self, protocol: Protocol) {
+        match protocol {
+            Protocol::Tcp => self.tcp_messages += 1,
+            Protocol::Udp => self.udp_messages += 1
+        }
+    }
+}
+
+fn metric(output: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    let _ignore = writeln!(output, "# HELP chat_{name} {help}");
+    let _ignore = writeln!(output, "# TYPE chat_{name} {kind}");
+
+    for (labels, value) in samples {
+        let _ignore = writeln!(output, "chat_{name}{labels} {value}");
+    }
+}
+
+/// Everything in the Prometheus text format. Message rates are left to `rate()` over the counters.
+pub fn render(state: &State) -> String {
+    let Metrics { tcp_messages, udp_messages, decode_errors, dropped_sends, dropped_hooks } = state.metrics;
+    let (queued, deepest) = state.queue_depths();
+    let traffic = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+
+    let mut output = String::new();
+
+    metric(&mut output, "connected_peers", "gauge", "Users signed in over any transport.", &[
+        ("", state.user_count() as u64)
+    ]);
+    metric(&mut output, "messages_total", "counter", "Messages routed, by protocol.", &[
+        ("{protocol=\"tcp\"}", tcp_messages),
+        ("{protocol=\"udp\"}", udp_messages)
+    ]);
+    metric(&mut output, "decode_errors_total", "counter", "Frames that could not be decoded.", &[
+        ("", decode_errors)
+    ]);
+    metric(&mut output, "dropped_sends_total", "counter", "Responses for peers whose connection was gone.", &[
+        ("", dropped_sends)
+    ]);
+    metric(&mut output, "queued_responses", "gauge", "Responses waiting to be written, over all peers.", &[
+        ("", queued as u64)
+    ]);
+    metric(&mut output, "queued_responses_max", "gauge", "Responses waiting for the peer furthest behind.", &[
+        ("", deepest as u64)
+    ]);
+    metric(&mut output, "webhook_queued_events", "gauge", "Events waiting to be POSTed to webhooks.", &[
+        ("", state.webhooks().queued() as u64)
+    ]);
+    metric(&mut output, "webhook_dropped_events_total", "counter", "Events dropped for webhooks that fell behind.", &[
+        ("", state.webhooks().dropped())
+    ]);
+    metric(&mut output, "bot_dropped_hooks_total", "counter", "Hooks dropped for bot plugins that fell behind.", &[
+        ("", dropped_hooks)
+    ]);
+    metric(&mut output, "bytes_total", "counter", "Bytes on native connections, by transport and direction.", &[
+        ("{transport=\"tcp\",direction=\"received\"}", traffic(&TRAFFIC.tcp_received)),
+        ("{transport=\"tcp\",direction=\"sent\"}", traffic(&TRAFFIC.tcp_sent)),
+        ("{transport=\"udp\",direction=\"received\"}", traffic(&TRAFFIC.udp_received)),
+        ("{transport=\"udp\",direction=\"sent\"}", traffic(&TRAFFIC.udp_sent))
+    ]);
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::message::Message;
+
+    use super::{super::{state::Address, tests::state}, *};
+
+    /// The value of the sample that starts with `name`, labels included.
+    fn sample(output: &str, name: &str) -> u64 {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no sample for {name}"))
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn metrics_are_in_the_prometheus_text_format() {
+        let state = state().await;
+        let mut state = state.lock().await;
+        let _alice = state.sign_in(Address::Unix(1), "alice", None).unwrap();
+
+        state.broadcast(Message::new("hello", "alice", "all", Protocol::Udp)).await.unwrap();
+        state.decode_failed("alice", Protocol::Udp);
+
+        let output = render(&state);
+
+        assert!(output.starts_with(concat!(
+            "# HELP chat_connected_peers Users signed in over any transport.\n",
+            "# TYPE chat_connected_peers gauge\n",
+            "chat_connected_peers 1\n"
+        )));
+        assert_eq!(sample(&output, "chat_messages_total{protocol=\"tcp\"}"), 0);
+        assert_eq!(sample(&output, "chat_messages_total{protocol=\"udp\"}"), 1);
+        assert_eq!(sample(&output, "chat_decode_errors_total"), 1);
+        assert_eq!(sample(&output, "chat_queued_responses"), 1);
+        assert!(output.lines().all(|line| line.starts_with('#') || line.starts_with("chat_")));
+    }
+
+    #[tokio::test]
+    async fn kicked_users_count_until_they_are_signed_out() {
+        let state = state().await;
+        let mut state = state.lock().await;
+        let _alice = state.sign_in(Address::Unix(1), "alice", None).unwrap();
+        let _bob = state.sign_in(Address::Unix(2), "bob", None).unwrap();
+
+        state.kick("alice");
+        assert_eq!(sample(&render(&state), "chat_connected_peers"), 2);
+
+        state.remove("alice", &Address::Unix(1));
+        assert_eq!(sample(&render(&state), "chat_connected_peers"), 1);
+    }
+}
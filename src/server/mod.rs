@@ -1,59 +1,207 @@
-use std::{io, net::SocketAddr, sync::Arc};
+use std::{
+    fs, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
+use socket2::{Domain, Socket, Type};
 use tokio::{
-    io::BufReader,
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpListener, TcpStream, UdpSocket,
-    },
-    sync::Mutex,
+    io::{stdin, stdout, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{Mutex, Notify},
+    time::{sleep, Instant},
 };
 
 use crate::{
     client::parser,
     common::{
-        config::Config,
-        message::{self, Message, Protocol, Request, Response},
+        config::{Config, Console},
+        message::{self, Message, Protocol, Query, Request, Response, Transfer},
         communication::*
     },
 };
 
+mod admin;
+mod archive;
+mod bot;
+mod console;
+mod http;
+mod irc;
+mod metrics;
 mod state;
-use state::State;
+mod udp;
+mod unix;
+mod webhook;
+mod websocket;
+pub use state::is_valid_name;
+use archive::Archive;
+use state::{Address, Peer, Receiver, SendError, State};
+use webhook::{Event, Webhooks};
 
-use self::state::Peer;
+/// How long a shutdown waits for connections to write what is queued and sign out.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const LISTEN_BACKLOG: i32 = 1024;
 
 pub async fn run(config: Config) -> io::Result<()> {
-    let Config { tcp, .. } = config;
+    let Config {
+        tcp, websocket, irc, webhooks, incoming_webhooks, webhook_token, bot, bot_tokens, admin,
+        unix, console, max_file_size, max_message_size, moderators, archive, ..
+    } = config;
 
-    let listener = TcpListener::bind(tcp).await?;
+    let listener = bind(tcp)?;
+    let udp = Arc::new(udp::bind(tcp)?);
 
-    let state = Arc::new(Mutex::new(State::new().await?));
+    let archive = Archive::open(archive.as_deref()).map_err(io::Error::other)?;
+    let webhooks = Webhooks::start(webhooks);
+    let state = State::new(max_file_size, max_message_size, moderators, archive, webhooks, bot_tokens).await?;
+    let state = Arc::new(Mutex::new(state));
 
-    loop {
-        let udp = UdpSocket::bind("0.0.0.0:0").await?;
+    tokio::spawn(udp::listen(state.clone(), udp.clone()));
+
+    if let Some(name) = bot {
+        bot::start(state.clone(), name, bot::plugins()).await?;
+    }
+
+    if let Some(path) = &unix {
+        let listener = unix::bind(path, unix::SOCKET_MODE)?;
+        tokio::spawn(unix::listen(state.clone(), listener, udp.clone()));
+    }
+
+    if let Some(address) = websocket {
+        let listener = bind(address)?;
+        tokio::spawn(websocket::listen(state.clone(), listener));
+    }
+
+    if let Some(address) = irc {
+        let listener = bind(address)?;
+        tokio::spawn(irc::listen(state.clone(), listener));
+    }
+
+    if let Some(address) = admin {
+        let listener = bind(address)?;
+        tokio::spawn(admin::listen(state.clone(), listener));
+    }
 
+    if let Some(address) = incoming_webhooks {
+        let token = webhook_token.ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Incoming webhooks need a --webhook-token"
+        ))?;
+
+        let listener = bind(address)?;
+        tokio::spawn(webhook::listen(state.clone(), listener, token));
+    }
+
+    let shutdown = Arc::new(Notify::new());
+
+    match &console {
+        Some(Console::Stdin) => {
+            tokio::spawn(console::serve(state.clone(), BufReader::new(stdin()), stdout(), shutdown.clone()));
+        }
+        Some(Console::Socket(path)) => {
+            let listener = unix::bind(path, console::SOCKET_MODE)?;
+            tokio::spawn(console::listen(state.clone(), listener, shutdown.clone()));
+        }
+        None => {}
+    }
+
+    loop {
         let state = state.clone();
-        let (stream, address) = listener.accept().await?;
+        let udp = udp.clone();
+
+        let (stream, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.notified() => break
+        };
 
         tokio::spawn(async move {
-            let _ignore = process(state, stream, udp, address).await;
+            let _ignore = serve(state, stream, udp, address).await;
         });
     }
+
+    shut_down(&state).await;
+
+    let sockets = unix
+        .into_iter()
+        .chain(console.and_then(|console| match console {
+            Console::Socket(path) => Some(path),
+            Console::Stdin => None
+        }));
+
+    for path in sockets {
+        let _ignore = fs::remove_file(path);
+    }
+
+    Ok(())
+}
+
+/// A non-blocking socket for `address`. On the IPv6 unspecified address it takes IPv4
+/// traffic as well, whatever the system default for dual-stack sockets is.
+fn socket(address: SocketAddr, kind: Type) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), kind, None)?;
+
+    if address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+
+    socket.set_nonblocking(true)?;
+
+    Ok(socket)
+}
+
+fn bind(address: SocketAddr) -> io::Result<TcpListener> {
+    let socket = socket(address, Type::STREAM)?;
+
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// IPv4 peers of a dual-stack listener show up with IPv4-mapped IPv6 addresses.
+fn canonical(address: SocketAddr) -> SocketAddr {
+    SocketAddr::new(address.ip().to_canonical(), address.port())
+}
+
+/// Serves a TCP client. It is told to send datagrams to the address it connected to,
+/// so that they have the same address family and are routed the same way.
+async fn serve(state: Arc<Mutex<State>>, stream: TcpStream, udp: Arc<UdpSocket>, address: SocketAddr) -> io::Result<()> {
+    let local = canonical(stream.local_addr()?);
+    let udp_address = SocketAddr::new(local.ip(), udp.local_addr()?.port());
+    let (reader, writer) = stream.into_split();
+
+    process(state, Reader::new(reader), Writer::new(writer), udp, udp_address, canonical(address).into()).await
+}
+
+/// Tells everyone, ends every connection and gives them a moment to sign out.
+async fn shut_down(state: &Mutex<State>) {
+    let _ignore = send_server_announcement(state, "The server is shutting down").await;
+    state.lock().await.close();
+
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+
+    while state.lock().await.user_count() > 0 && Instant::now() < deadline {
+        sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
 }
 
+/// Signs the client in at `address` and returns its name, the token of its datagrams
+/// and the channel of what is sent to it.
 async fn get_user_info_and_respond(
-    reader: &mut BufReader<ReadHalf<'_>>,
-    writer: &mut WriteHalf<'_>,
+    state: &Mutex<State>,
+    reader: &mut Reader<'_>,
+    writer: &mut Writer<'_>,
+    address: Address,
     local_udp: SocketAddr
-) -> io::Result<(String, SocketAddr)> {
+) -> io::Result<(String, String, Receiver)> {
     let request = receive_tcp::<Request>(reader).await;
 
-    match request {
-        Ok(Request::SignIn { name, udp }) => {
-            send_tcp(writer, Response::Ok(local_udp)).await?;
-            Ok((name, udp))
-        }
+    let (name, udp, token) = match request {
+        Ok(Request::SignIn { name, udp }) => (name, udp, None),
+        Ok(Request::SignInBot { name, udp, token }) => (name, udp, Some(token)),
         Err(reason) => {
             let err = io::Error::new(
                 io::ErrorKind::InvalidInput, 
@@ -62,25 +210,93 @@ async fn get_user_info_and_respond(
 
             let response = Response::Error(message::Error::InvalidName);
             send_tcp(writer, response).await?;
-            Err(err)
+            return Err(err);
         },
-        _ => Err(io::Error::new(
+        _ => return Err(io::Error::new(
             io::ErrorKind::ConnectionAborted,
             "Invalid handshake request format"
         ))
+    };
+
+    let Ok(internal_rx) = state.lock().await.sign_in(address, &name, token.as_deref()) else {
+        send_tcp(writer, Response::Error(message::Error::InvalidName)).await?;
+
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            message::Error::InvalidName
+        ));
+    };
+
+    let session = state.lock().await.open_session(&name, udp, writer.encoding());
+
+    let session = match session {
+        Ok(session) => send_tcp(writer, Response::Session { udp: local_udp, token: session.clone() })
+            .await
+            .map(|()| session),
+        Err(reason) => Err(reason)
+    };
+
+    match session {
+        Ok(session) => Ok((name, session, internal_rx)),
+        Err(reason) => {
+            state.lock().await.remove(&name, &address);
+            Err(reason)
+        }
     }
 }
 
-async fn disconnect_and_remove(state: Arc<Mutex<State>>, mut user: Peer<'_>, address: &SocketAddr) {
-    state.lock().await.remove(&user.name, address);
-    user.internal_rx.close();
+/// Compares without stopping at the first difference, so that response times give nothing away.
+fn is_token(given: &[u8], token: &str) -> bool {
+    given.len() == token.len() && given
+        .iter()
+        .zip(token.bytes())
+        .fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Tells everyone about a user who just signed in, over any kind of connection.
+async fn join(state: &Mutex<State>, name: &str) -> io::Result<()> {
+    state.lock().await.notify(Event::Join { user: name.to_owned(), room: None });
+    publish_presence(state).await;
+    send_server_announcement(state, &format!("{name} has joined the chat")).await
+}
+
+/// Removes a user who signed out or lost the connection, and tells everyone else.
+async fn disconnect(state: &Mutex<State>, name: &str, address: &Address) -> io::Result<()> {
+    let announced = send_server_announcement(state, &format!("{name} has left the chat")).await;
+
+    {
+        let mut state = state.lock().await;
+        state.remove(name, address);
+        state.notify(Event::Leave { user: name.to_owned(), room: None });
+    }
+
+    publish_presence(state).await;
+
+    announced
 }
 
+/// Tells the sender when the message could not go anywhere.
 async fn send_internally(state: &Mutex<State>, message: Message) -> io::Result<()> {
+    let sender = message.get_sender().to_owned();
+    let mut state = state.lock().await;
+    let result = state.send(message).await;
+
+    result.map_err(|reason| {
+        let error = match reason {
+            SendError::NotRoomMember => message::Error::NotAuthorized,
+            SendError::UserNotFound | SendError::InternalChannelFailed => message::Error::UserNotFound
+        };
+
+        state.report(&sender, error);
+        io::Error::new(io::ErrorKind::BrokenPipe, reason)
+    })
+}
+
+async fn broadcast_internally(state: &Mutex<State>, message: Message) -> io::Result<()> {
     state
         .lock()
         .await
-        .send(message)
+        .broadcast(message)
         .await
         .map_err(|reason| io::Error::new(
             io::ErrorKind::BrokenPipe,
@@ -88,11 +304,35 @@ async fn send_internally(state: &Mutex<State>, message: Message) -> io::Result<(
         ))
 }
 
-async fn broadcast_internally(state: &Mutex<State>, message: Message) -> io::Result<()> {
+async fn transfer_internally(
+    state: &Mutex<State>, 
+    sender: &str, 
+    receiver: &str, 
+    transfer: Transfer, 
+    protocol: Protocol
+) -> io::Result<()> {
+    let mut state = state.lock().await;
+
+    let too_large = match &transfer {
+        Transfer::Offer { size, .. } => *size > state.max_file_size,
+        Transfer::Chunk { offset, data, .. } => offset
+            .checked_add(data.len() as u64)
+            .is_none_or(|end| end > state.max_file_size),
+        _ => false
+    };
+
+    if too_large {
+        let rejection = Transfer::Reject { id: transfer.get_id() };
+        let _ignore = state.transfer(receiver, sender, rejection, Protocol::Tcp).await;
+
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            message::Error::FileTooLarge
+        ));
+    }
+
     state
-        .lock()
-        .await
-        .broadcast(message)
+        .transfer(sender, receiver, transfer, protocol)
         .await
         .map_err(|reason| io::Error::new(
             io::ErrorKind::BrokenPipe,
@@ -100,84 +340,419 @@ async fn broadcast_internally(state: &Mutex<State>, message: Message) -> io::Res
         ))
 }
 
+/// Edits the message when `text` is given and deletes it otherwise.
+/// Refusals are reported back to the requesting user.
+async fn amend_internally(state: &Mutex<State>, name: &str, id: u64, text: Option<String>) -> io::Result<()> {
+    let mut state = state.lock().await;
+
+    let result = match text {
+        Some(text) => state.edit(name, id, &text),
+        None => state.delete(name, id)
+    };
+
+    result.map_err(|reason| {
+        state.report(name, reason);
+        io::Error::new(io::ErrorKind::PermissionDenied, reason)
+    })
+}
+
+/// Searches the archive without holding on to the state in the meantime.
+async fn search_internally(state: &Mutex<State>, name: &str, query: Query) -> io::Result<()> {
+    let (archive, scope) = state.lock().await.search_scope(name);
+    let result = archive.search(query.clone(), scope).await;
+    let mut state = state.lock().await;
+
+    match result {
+        Ok((total, hits)) => {
+            state.results(name, query, total, hits);
+            Ok(())
+        }
+        Err(reason) => {
+            eprintln!("Search by {name} failed: {reason}");
+            state.report(name, message::Error::SearchFailed);
+            Err(reason)
+        }
+    }
+}
+
+async fn publish_presence(state: &Mutex<State>) {
+    state.lock().await.publish_presence();
+}
+
+async fn handle_request(state: &Mutex<State>, name: &str, request: Request) -> io::Result<()> {
+    let too_large = {
+        let limit = state.lock().await.max_message_size;
+        request.get_message().is_some_and(|text| text.len() > limit)
+    };
+
+    if too_large {
+        state.lock().await.report(name, message::Error::MessageTooLarge);
+
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            message::Error::MessageTooLarge
+        ));
+    }
+
+    match request {
+        Request::Join { room } | Request::Leave { room } if !state::is_valid_room(&room) => {
+            state.lock().await.report(name, message::Error::InvalidName);
+
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                message::Error::InvalidName
+            ))
+        }
+        Request::Join { room } => {
+            {
+                let mut state = state.lock().await;
+
+                if state.join(name, &room) {
+                    state.notify(Event::Join { user: name.to_owned(), room: Some(room) });
+                }
+            }
+
+            publish_presence(state).await;
+            Ok(())
+        }
+        Request::Leave { room } => {
+            {
+                let mut state = state.lock().await;
+
+                if state.leave(name, &room) {
+                    state.notify(Event::Leave { user: name.to_owned(), room: Some(room) });
+                }
+            }
+
+            publish_presence(state).await;
+            Ok(())
+        }
+        Request::Status { status } => {
+            state.lock().await.set_status(name, status);
+            publish_presence(state).await;
+            Ok(())
+        }
+        Request::Typing { receiver } => {
+            state.lock().await.typing(name, &receiver);
+            Ok(())
+        }
+        Request::Edit { id, message } => amend_internally(state, name, id, Some(message)).await,
+        Request::Delete { id } => amend_internally(state, name, id, None).await,
+        Request::React { id, reaction } => {
+            let mut state = state.lock().await;
+
+            state.react(name, id, &reaction).map_err(|reason| {
+                state.report(name, reason);
+                io::Error::new(io::ErrorKind::PermissionDenied, reason)
+            })
+        }
+        Request::Search { query } => search_internally(state, name, query).await,
+        Request::Transfer { peer, transfer, protocol } => {
+            transfer_internally(state, name, &peer, transfer, protocol).await
+        }
+        request => match request.into_message(name) {
+            Some(message) if message.is_broadcast() => broadcast_internally(state, message).await,
+            Some(message) => send_internally(state, message).await,
+            None => Ok(())
+        }
+    }
+}
+
 async fn send_server_announcement(state: &Mutex<State>, text: &str) -> io::Result<()> {
-    let message = Message::new(
-        &text, 
-        "server", 
-        parser::BROADCAST_NAME, 
-        Protocol::Tcp
-    );
+    announce(state, message::SERVER_NAME, parser::BROADCAST_NAME, text).await
+}
+
+/// Posts on behalf of the server or an integration rather than a user.
+async fn announce(state: &Mutex<State>, sender: &str, receiver: &str, text: &str) -> io::Result<()> {
+    let mut message = Message::new(text, sender, receiver, Protocol::Tcp);
+    message.from_server = sender == message::SERVER_NAME;
 
-    broadcast_internally(state, message).await
+    state
+        .lock()
+        .await
+        .announce(message)
+        .map_err(|reason| io::Error::new(
+            io::ErrorKind::NotFound,
+            reason
+        ))
 }
 
+/// Serves a native client over any stream transport. Its datagrams arrive at the shared
+/// UDP socket, which the client is told to reach at `udp_address`.
 async fn process(
     state: Arc<Mutex<State>>,
-    mut stream: TcpStream,
-    udp: UdpSocket,
-    address: SocketAddr,
+    mut reader: Reader<'_>,
+    mut writer: Writer<'_>,
+    udp: Arc<UdpSocket>,
+    udp_address: SocketAddr,
+    address: Address,
 ) -> io::Result<()> {
-    let (reader, mut writer) = stream.split();
-    let mut reader = BufReader::new(reader);
+    accept_encoding(&mut reader, &mut writer).await?;
     
-    let (name, udp_address) = get_user_info_and_respond(
+    let (name, session, internal_rx) = get_user_info_and_respond(
+        &state,
         &mut reader, 
         &mut writer, 
-        udp.local_addr()?
+        address,
+        udp_address
     ).await?;
 
-    udp.connect(udp_address).await?;
-
-    let mut user = state
-        .lock()
-        .await
-        .add(reader, writer, udp, address, &name);
+    let mut user = Peer { name: name.clone(), reader, writer, internal_rx };
 
     println!("{} @ {} connected", name, address);
 
-    send_server_announcement(
-        &state, 
-        &format!("{name} has joined the chat")
-    ).await?;
+    join(&state, &name).await?;
+
+    let encoding = user.reader.encoding();
 
     loop {
         tokio::select! {
-            Some(msg) = user.internal_rx.recv() => {
-                let _ignore = match msg.get_protocol().unwrap() {
-                    Protocol::Udp => send_udp(&user.udp, msg).await,
-                    Protocol::Tcp => send_tcp(&mut user.writer, msg).await
+            msg = user.internal_rx.recv() => {
+                let Some(msg) = msg else {
+                    break;
                 };
-            }
 
-            Ok(request) = receive_udp::<Request>(&user.udp) => {
-                let message = request.to_message(&name).unwrap();
-                let _ignore = match message.is_broadcast() {
-                    true => broadcast_internally(&state, message).await,
-                    false => send_internally(&state, message).await
+                let target = match msg.get_protocol() {
+                    Some(Protocol::Udp) if fits_datagram(encoding, &msg) => state.lock().await.udp_address(&session),
+                    _ => None
+                };
+
+                let _ignore = match target {
+                    Some(target) => udp::send(&udp, target, encoding, msg).await,
+                    None => send_tcp(&mut user.writer, msg).await
                 };
             }
 
             result = receive_tcp::<Request>(&mut user.reader) => match result {
-                Ok(Request::SignOut) | Err(_) => break,
+                Ok(Request::SignOut) => break,
+                Err(reason) if reason.kind() == io::ErrorKind::InvalidData => {
+                    state.lock().await.decode_failed(&name, Protocol::Tcp);
+                }
+                Err(_) => break,
                 Ok(request) => {
-                    let message = request.to_message(&name).unwrap();
-                    let _ignore = match message.is_broadcast() {
-                        true => broadcast_internally(&state, message).await,
-                        false => send_internally(&state, message).await
-                    };
+                    let _ignore = handle_request(&state, &name, request).await;
                 }
             }
         }
     }
 
-    send_server_announcement(
-        &state, 
-        &format!("{name} has left the chat")
-    ).await?;
-
-    disconnect_and_remove(state, user, &address).await;
+    user.internal_rx.close();
+    disconnect(&state, &user.name, &address).await?;
 
     println!("{} @ {} disconnected", name, address);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{state::SignInError, *};
+
+    pub(super) async fn state() -> Mutex<State> {
+        let archive = Archive::open(None).unwrap();
+        let moderators = BTreeMap::from([(String::from("mod"), String::from("secret"))]);
+        let state = State::new(1024, 1024, moderators, archive, Webhooks::start(vec![]), BTreeMap::new());
+
+        Mutex::new(state.await.unwrap())
+    }
+
+    /// Sends a message from `name` to itself and returns its id.
+    async fn post(state: &mut State, internal_rx: &mut Receiver, name: &str) -> u64 {
+        state.send(Message::new("hello", name, name, Protocol::Tcp)).await.unwrap();
+
+        match internal_rx.recv().await {
+            Some(Response::Message(message)) => message.id,
+            response => panic!("expected a message, got {response:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_ending_past_u64_are_too_large() {
+        let state = state().await;
+        let chunk = Transfer::Chunk { id: 1, offset: u64::MAX - 1, data: vec![0; 4] };
+        let result = transfer_internally(&state, "alice", "bob", chunk, Protocol::Udp).await;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn messages_to_nobody_are_reported_and_get_no_id() {
+        let state = state().await;
+        let mut internal_rx = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+        let request = Request::Send {
+            receiver: String::from("nobody"),
+            message: String::from("hello"),
+            protocol: Protocol::Tcp,
+            reply_to: None
+        };
+
+        assert!(handle_request(&state, "alice", request).await.is_err());
+        assert!(matches!(internal_rx.recv().await, Some(Response::Error(message::Error::UserNotFound))));
+
+        let request = Request::Send {
+            receiver: String::from("#nowhere"),
+            message: String::from("hello"),
+            protocol: Protocol::Tcp,
+            reply_to: None
+        };
+
+        assert!(handle_request(&state, "alice", request).await.is_err());
+        assert!(matches!(internal_rx.recv().await, Some(Response::Error(message::Error::UserNotFound))));
+        assert_eq!(post(&mut *state.lock().await, &mut internal_rx, "alice").await, 1);
+    }
+
+    #[tokio::test]
+    async fn a_name_is_signed_in_only_once() {
+        let state = state().await;
+        let mut state = state.lock().await;
+        let first = Address::Unix(1);
+
+        assert!(state.sign_in(first, "alice", None).is_ok());
+        assert_eq!(state.sign_in(Address::Unix(2), "alice", None).err(), Some(SignInError::InUse));
+        assert_eq!(state.user_count(), 1);
+
+        state.remove("alice", &first);
+        assert!(state.sign_in(Address::Unix(2), "alice", None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn names_of_rooms_everyone_and_the_server_are_refused() {
+        let state = state().await;
+        let mut state = state.lock().await;
+
+        for name in ["#room", "all", "server", "", "two words", "a:b"] {
+            assert_eq!(state.sign_in(Address::Unix(1), name, None).err(), Some(SignInError::InvalidName), "{name}");
+        }
+
+        assert_eq!(state.user_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn rooms_need_a_short_name_with_the_prefix() {
+        let state = state().await;
+        let mut alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+        let long = format!("#{}", "a".repeat(256 * 1024));
+
+        for room in ["bob", "all", "", "#", "#two words", "#a\r\nQUIT", "#a,#b", long.as_str()] {
+            let join = Request::Join { room: room.to_owned() };
+            assert!(handle_request(&state, "alice", join).await.is_err(), "{room}");
+            assert!(matches!(alice.recv().await, Some(Response::Error(message::Error::InvalidName))), "{room}");
+        }
+
+        assert!(state.lock().await.presence().rooms.is_empty());
+        assert!(handle_request(&state, "alice", Request::Join { room: String::from("#ops") }).await.is_ok());
+        assert_eq!(state.lock().await.presence().rooms.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_name_taken_again_does_not_own_earlier_messages() {
+        let state = state().await;
+        let mut state = state.lock().await;
+
+        let mut internal_rx = state.sign_in(Address::Unix(1), "alice", None).unwrap();
+        let id = post(&mut state, &mut internal_rx, "alice").await;
+        assert!(state.edit("alice", id, "edited").is_ok());
+
+        state.remove("alice", &Address::Unix(1));
+        state.sign_in(Address::Unix(2), "alice", None).unwrap();
+
+        assert!(matches!(state.edit("alice", id, "taken over"), Err(message::Error::NotAuthorized)));
+        assert!(matches!(state.delete("alice", id), Err(message::Error::NotAuthorized)));
+    }
+
+    #[tokio::test]
+    async fn moderators_sign_in_with_their_token() {
+        let state = state().await;
+        let mut state = state.lock().await;
+
+        let mut internal_rx = state.sign_in(Address::Unix(1), "alice", None).unwrap();
+        let id = post(&mut state, &mut internal_rx, "alice").await;
+
+        assert_eq!(state.sign_in(Address::Unix(2), "mod", None).err(), Some(SignInError::WrongToken));
+        assert_eq!(state.sign_in(Address::Unix(2), "mod", Some("wrong")).err(), Some(SignInError::WrongToken));
+        assert!(state.sign_in(Address::Unix(2), "mod", Some("secret")).is_ok());
+
+        assert!(state.edit("mod", id, "moderated").is_ok());
+        assert!(state.delete("mod", id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn only_moderators_change_announcements() {
+        let state = state().await;
+        let mut alice = state.lock().await.sign_in(Address::Unix(1), "alice", None).unwrap();
+
+        send_server_announcement(&state, "maintenance at noon").await.unwrap();
+
+        let id = match alice.recv().await {
+            Some(Response::Message(message)) => message.id,
+            response => panic!("expected the announcement, got {response:?}")
+        };
+
+        let mut state = state.lock().await;
+
+        assert!(matches!(state.edit("alice", id, "no maintenance"), Err(message::Error::NotAuthorized)));
+        assert!(matches!(state.delete("alice", id), Err(message::Error::NotAuthorized)));
+
+        state.sign_in(Address::Unix(2), "mod", Some("secret")).unwrap();
+        assert!(state.delete("mod", id).is_ok());
+    }
+
+    #[tokio::test]
+    async fn a_name_taken_again_does_not_find_earlier_direct_messages() {
+        let state = state().await;
+        let query = Query { text: String::from("secret"), ..Query::default() };
+
+        let mut bob = {
+            let mut state = state.lock().await;
+
+            state.sign_in(Address::Unix(1), "alice", None).unwrap();
+            let mut bob = state.sign_in(Address::Unix(2), "bob", None).unwrap();
+            state.send(Message::new("secret", "alice", "bob", Protocol::Tcp)).await.unwrap();
+            let _message = bob.recv().await;
+
+            state.remove("bob", &Address::Unix(2));
+            state.sign_in(Address::Unix(3), "bob", None).unwrap()
+        };
+
+        search_internally(&state, "bob", query.clone()).await.unwrap();
+        assert!(matches!(bob.recv().await, Some(Response::Results { total: 0, .. })));
+
+        state.lock().await.send(Message::new("secret", "alice", "bob", Protocol::Tcp)).await.unwrap();
+        let _message = bob.recv().await;
+
+        search_internally(&state, "bob", query).await.unwrap();
+        assert!(matches!(bob.recv().await, Some(Response::Results { total: 1, .. })));
+    }
+
+    #[tokio::test]
+    async fn joining_a_name_does_not_find_its_direct_messages() {
+        let state = state().await;
+        let query = Query { text: String::from("secret"), ..Query::default() };
+
+        let mut eve = {
+            let mut state = state.lock().await;
+
+            state.sign_in(Address::Unix(1), "alice", None).unwrap();
+            let mut bob = state.sign_in(Address::Unix(2), "bob", None).unwrap();
+            let eve = state.sign_in(Address::Unix(3), "eve", None).unwrap();
+
+            state.send(Message::new("secret", "alice", "bob", Protocol::Tcp)).await.unwrap();
+            let _message = bob.recv().await;
+
+            state.join("eve", "bob");
+            eve
+        };
+
+        search_internally(&state, "eve", query).await.unwrap();
+
+        loop {
+            match eve.recv().await {
+                Some(Response::Results { total, .. }) => break assert_eq!(total, 0),
+                Some(_) => continue,
+                None => panic!("eve was signed out")
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -1,84 +1,448 @@
 use std::{
     io,
-    collections::HashMap,
-    net::SocketAddr
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display, Formatter},
+    net::SocketAddr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc}
 };
 
+use serde::{Serialize, Serializer};
 use tokio::{
-    net::{UdpSocket, tcp::{WriteHalf, ReadHalf}},
+    net::UdpSocket,
     sync::mpsc::{self, UnboundedSender, UnboundedReceiver},
-    io::BufReader,
 };
 
 use bimap::BiMap;
+use chrono::Utc;
 
-use crate::common::message::{Response, Message};
+use crate::{
+    client::parser,
+    common::{
+        codec::Encoding,
+        communication::{Reader, Writer, SESSION_TOKEN_LENGTH},
+        message::{Error, Hit, Response, Message, Presence, Protocol, Query, Reaction, Room, Status, Transfer, User, SERVER_NAME}
+    }
+};
+
+use super::{archive::{Archive, Archiver, Scope}, is_token, metrics::Metrics, webhook::{Event, Webhooks}};
+
+const MAX_TRACKED_MESSAGES: usize = 10_000;
+const MAX_REACTION_LENGTH: usize = 32;
+const MAX_ROOM_LENGTH: usize = 64;
+
+/// Whether users can go by `name` on every transport. Names must not pass for the server,
+/// everyone or a room, nor contain what separates the parts of an IRC line.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != SERVER_NAME
+        && name != parser::BROADCAST_NAME
+        && !name.starts_with(parser::ROOM_PREFIX)
+        && !name.contains(char::is_whitespace)
+        && !name.contains([':', ',', '!', '@', '*', '?'])
+}
+
+/// Whether `room` can be created. Room names go into every presence update and
+/// into IRC lines, so they are short and hold nothing that could split them.
+pub fn is_valid_room(room: &str) -> bool {
+    room.len() > parser::ROOM_PREFIX.len_utf8()
+        && room.len() <= MAX_ROOM_LENGTH
+        && room.starts_with(parser::ROOM_PREFIX)
+        && !room.contains(|c: char| c.is_whitespace() || c.is_control() || c == ',')
+}
+
+/// Where a peer is connected from. Unix socket peers have no address of their
+/// own, so they are told apart by the order in which they connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Unix(u64)
+}
+
+impl From<SocketAddr> for Address {
+    fn from(address: SocketAddr) -> Self {
+        Self::Ip(address)
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(address) => write!(f, "{address}"),
+            Self::Unix(id) => write!(f, "unix:{id}")
+        }
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The sending end of a peer's queue of responses. Both ends keep count of what
+/// is waiting, so that the admin endpoint can show how far behind each peer is.
+pub struct Sender {
+    inner: UnboundedSender<Response>,
+    depth: Arc<AtomicUsize>
+}
 
-pub type Sender = UnboundedSender<Response>;
-pub type Receiver = UnboundedReceiver<Response>;
+pub struct Receiver {
+    inner: UnboundedReceiver<Response>,
+    depth: Arc<AtomicUsize>
+}
+
+fn queue() -> (Sender, Receiver) {
+    let (inner_tx, inner_rx) = mpsc::unbounded_channel();
+    let depth = Arc::new(AtomicUsize::new(0));
+
+    (Sender { inner: inner_tx, depth: depth.clone() }, Receiver { inner: inner_rx, depth })
+}
+
+impl Sender {
+    pub fn send(&self, response: Response) -> Result<(), SendError> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+
+        self.inner.send(response).map_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            SendError::InternalChannelFailed
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+impl Receiver {
+    /// Cancel safe, like the channel underneath.
+    pub async fn recv(&mut self) -> Option<Response> {
+        let response = self.inner.recv().await?;
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+
+        Some(response)
+    }
+
+    pub fn close(&mut self) {
+        self.inner.close();
+    }
+}
+
+/// A signed-in user, as listed by the admin endpoint.
+#[derive(Debug, Serialize)]
+pub struct Session {
+    pub name: String,
+    pub address: Address,
+    pub status: Status,
+    pub rooms: Vec<String>,
+    /// Responses waiting to be written to the connection.
+    pub queued: usize
+}
 
 pub struct Peer<'a> {
     pub name: String,
-    pub reader: BufReader<ReadHalf<'a>>,
-    pub writer: WriteHalf<'a>,
-    pub udp: UdpSocket,
+    pub reader: Reader<'a>,
+    pub writer: Writer<'a>,
     pub internal_rx: Receiver,
 }
 
+/// Where the shared UDP socket sends a native client's datagrams, and in which codec.
+struct UdpSession {
+    name: String,
+    address: SocketAddr,
+    encoding: Encoding
+}
+
+/// Who may change a message besides the moderators. A name with a token always
+/// belongs to the same account. Any other name can be taken by someone else once
+/// it is free, so it only owns what was sent while that sign-in lasted.
+#[derive(Debug, Clone, PartialEq)]
+enum Owner {
+    Account(String),
+    SignIn(u64)
+}
+
+/// One sign-in of a name, told apart from the earlier ones.
+#[derive(Debug, Clone, Copy)]
+struct SignIn {
+    serial: u64,
+    /// The last message id when it began.
+    after: u64
+}
+
 #[allow(dead_code)]
 pub struct State {
-    pub peers: HashMap<SocketAddr, Sender>,
-    names: BiMap<String, SocketAddr>,
+    pub peers: HashMap<Address, Sender>,
+    names: BiMap<String, Address>,
+    rooms: BTreeMap<String, BTreeSet<String>>,
+    statuses: HashMap<String, Status>,
+    history: BTreeMap<u64, Message>,
+    owners: HashMap<u64, Owner>,
+    reactions: HashMap<u64, BTreeMap<String, BTreeSet<String>>>,
+    next_id: u64,
+    archive: Archiver,
+    webhooks: Webhooks,
+    bot_tokens: BTreeMap<String, String>,
+    /// Moderators by name, with the token they sign in with.
+    moderators: BTreeMap<String, String>,
+    /// The current sign-in of each name, counted up from `next_sign_in`.
+    sign_ins: HashMap<String, SignIn>,
+    next_sign_in: u64,
+    /// Native clients by the session token their datagrams start with.
+    udp_sessions: HashMap<String, UdpSession>,
+    banned: BTreeSet<String>,
+    /// Set once the server shuts down, after which nobody can sign in.
+    closing: bool,
     broadcast: UdpSocket,
+    pub max_file_size: u64,
+    pub max_message_size: usize,
+    pub metrics: Metrics,
+}
+
+/// Why a name could not sign in.
+#[derive(Debug, Clone, Copy, PartialEq, Error)]
+pub enum SignInError {
+    /// The server is shutting down.
+    Closing,
+    InvalidName,
+    InUse,
+    Banned,
+    /// An account was signed in to without its token, or a token was given for a name
+    /// that has none.
+    WrongToken
 }
 
 #[derive(Debug, Clone, Copy, Error)]
 pub enum SendError {
     UserNotFound,
+    NotRoomMember,
     InternalChannelFailed,
 }
 
 #[allow(dead_code)]
 impl State {
-    pub async fn new() -> io::Result<Self> {
+    pub async fn new(
+        max_file_size: u64,
+        max_message_size: usize,
+        moderators: BTreeMap<String, String>,
+        archive: Archive,
+        webhooks: Webhooks,
+        bot_tokens: BTreeMap<String, String>
+    ) -> io::Result<Self> {
         let broadcast = UdpSocket::bind("0.0.0.0:0").await?;
 
         broadcast.set_broadcast(true)?;
 
+        let next_id = archive.last_id().map_err(io::Error::other)?;
+        let archive = Archiver::start(archive);
+
         Ok(State {
             peers: HashMap::new(),
             names: BiMap::new(),
+            rooms: BTreeMap::new(),
+            statuses: HashMap::new(),
+            history: BTreeMap::new(),
+            owners: HashMap::new(),
+            reactions: HashMap::new(),
+            next_id,
+            archive,
+            webhooks,
+            bot_tokens,
+            moderators,
+            sign_ins: HashMap::new(),
+            next_sign_in: 0,
+            udp_sessions: HashMap::new(),
+            banned: BTreeSet::new(),
+            closing: false,
             broadcast,
+            max_file_size,
+            max_message_size,
+            metrics: Metrics::default(),
         })
     }
 
-    pub fn add<'a>(
-        &mut self, 
-        reader: BufReader<ReadHalf<'a>>,
-        writer: WriteHalf<'a>,
-        udp: UdpSocket,
-        address: SocketAddr,
-        name: &str
-    ) -> Peer<'a> {
-        let (internal_tx, internal_rx) = mpsc::unbounded_channel();
+    /// Hands out the token for the datagrams of `name`, which are sent to `address`
+    /// until one of them comes from somewhere else.
+    pub fn open_session(&mut self, name: &str, address: SocketAddr, encoding: Encoding) -> io::Result<String> {
+        let mut bytes = [0; SESSION_TOKEN_LENGTH / 2];
+        getrandom::fill(&mut bytes).map_err(io::Error::other)?;
+
+        let token = bytes.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+        self.udp_sessions.insert(token.clone(), UdpSession { name: name.to_owned(), address, encoding });
+
+        Ok(token)
+    }
+
+    /// Who sent a datagram starting with `token`, and in which codec. Datagrams that
+    /// get through also tell where the client is, which is where its datagrams go from then on.
+    pub fn authenticate(&mut self, token: &[u8], source: SocketAddr) -> Option<(String, Encoding)> {
+        let session = self.udp_sessions.get_mut(std::str::from_utf8(token).ok()?)?;
+        session.address = source;
+
+        Some((session.name.clone(), session.encoding))
+    }
+
+    pub fn udp_address(&self, token: &str) -> Option<SocketAddr> {
+        self.udp_sessions.get(token).map(|session| session.address)
+    }
+
+    /// Makes `name` reachable through the returned channel, whatever connection it uses.
+    pub fn register(&mut self, address: Address, name: &str) -> Receiver {
+        let (internal_tx, internal_rx) = queue();
 
         self.peers.insert(address, internal_tx);
         self.names.insert(name.to_owned(), address);
+        self.statuses.insert(name.to_owned(), Status::Online);
+
+        self.next_sign_in += 1;
+        self.sign_ins.insert(name.to_owned(), SignIn { serial: self.next_sign_in, after: self.next_id });
+
+        internal_rx
+    }
+
+    /// Registers `name` if `may_sign_in` allows it. Both happen under the same lock,
+    /// so that two connections cannot sign in with one name.
+    pub fn sign_in(&mut self, address: Address, name: &str, token: Option<&str>) -> Result<Receiver, SignInError> {
+        self.may_sign_in(name, token)?;
+        Ok(self.register(address, name))
+    }
 
-        let name = name.to_owned();
+    pub fn is_signed_in(&self, name: &str) -> bool {
+        self.names.contains_left(name)
+    }
 
-        Peer { name, reader, writer, udp, internal_rx }
+    pub fn user_count(&self) -> usize {
+        self.names.len()
     }
 
-    pub fn remove(&mut self, name: &str, address: &SocketAddr) {
+    /// Nobody can take a name that is invalid, in use or banned, and the accounts of bots
+    /// and moderators need their token.
+    pub fn may_sign_in(&self, name: &str, token: Option<&str>) -> Result<(), SignInError> {
+        if self.closing {
+            return Err(SignInError::Closing);
+        }
+
+        if !is_valid_name(name) {
+            return Err(SignInError::InvalidName);
+        }
+
+        if self.is_signed_in(name) {
+            return Err(SignInError::InUse);
+        }
+
+        if self.banned.contains(name) {
+            return Err(SignInError::Banned);
+        }
+
+        match (self.bot_tokens.get(name).or_else(|| self.moderators.get(name)), token) {
+            (Some(expected), Some(token)) if is_token(token.as_bytes(), expected) => Ok(()),
+            (None, None) => Ok(()),
+            _ => Err(SignInError::WrongToken)
+        }
+    }
+
+    pub fn remove(&mut self, name: &str, address: &Address) {
         self.peers.remove(address);
         self.names.remove_by_left(name);
+        self.statuses.remove(name);
+        self.sign_ins.remove(name);
+        self.udp_sessions.retain(|_, session| session.name != name);
+
+        for members in self.rooms.values_mut() {
+            members.remove(name);
+        }
+
+        self.rooms.retain(|_, members| !members.is_empty());
     }
 
-    pub async fn send(&mut self, message: Message) -> Result<(), SendError> {
+    /// Ends the connection of `name` once what is queued for it has been written.
+    /// The connection signs the user out as usual. Whether `name` was signed in.
+    pub fn kick(&mut self, name: &str) -> bool {
+        self.names
+            .get_by_left(name)
+            .and_then(|address| self.peers.remove(address))
+            .is_some()
+    }
+
+    /// Keeps `name` from signing in again. Whether it was not banned yet.
+    pub fn ban(&mut self, name: &str) -> bool {
+        self.banned.insert(name.to_owned())
+    }
+
+    pub fn unban(&mut self, name: &str) -> bool {
+        self.banned.remove(name)
+    }
+
+    /// Ends every connection, as `kick` does, and refuses new ones.
+    pub fn close(&mut self) {
+        self.closing = true;
+        self.peers.clear();
+    }
+
+    /// Whether `name` was not a member yet.
+    pub fn join(&mut self, name: &str, room: &str) -> bool {
+        self.rooms
+            .entry(room.to_owned())
+            .or_default()
+            .insert(name.to_owned())
+    }
+
+    /// Whether `name` was a member.
+    pub fn leave(&mut self, name: &str, room: &str) -> bool {
+        let Some(members) = self.rooms.get_mut(room) else {
+            return false;
+        };
+
+        let removed = members.remove(name);
+
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+
+        removed
+    }
+
+    pub fn set_status(&mut self, name: &str, status: Status) {
+        if let Some(current) = self.statuses.get_mut(name) {
+            *current = status;
+        }
+    }
+
+    pub fn presence(&self) -> Presence {
+        let mut users = self.names
+            .left_values()
+            .map(|name| User {
+                name: name.clone(),
+                status: self.statuses.get(name).copied().unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let rooms = self.rooms
+            .iter()
+            .map(|(name, members)| Room {
+                name: name.clone(),
+                members: members.iter().cloned().collect()
+            })
+            .collect();
+
+        Presence { users, rooms }
+    }
+
+    pub fn publish_presence(&mut self) {
+        let presence = Response::Presence(self.presence());
+
+        for tx in self.peers.values() {
+            if tx.send(presence.clone()).is_err() {
+                self.metrics.dropped_sends += 1;
+            }
+        }
+    }
+
+    fn deliver(&mut self, name: &str, response: Response) -> Result<(), SendError> {
         let receiver = self
             .names
-            .get_by_left(message.get_receiver())
+            .get_by_left(name)
             .ok_or(SendError::UserNotFound)?;
 
         let receiver = self
@@ -86,25 +450,367 @@ impl State {
             .get_mut(receiver)
             .ok_or(SendError::UserNotFound)?;
 
-        let message = Response::Message(message);
+        if receiver.send(response).is_err() {
+            self.metrics.dropped_sends += 1;
+            return Err(SendError::InternalChannelFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Gives the message its id and remembers it, so that it can be edited or replied to later.
+    /// Only the most recent `MAX_TRACKED_MESSAGES` are kept in memory; all of them are archived.
+    fn track(&mut self, mut message: Message) -> Message {
+        self.next_id += 1;
+        message.id = self.next_id;
+        self.metrics.count(message.protocol);
+
+        if message.reply_to.is_some_and(|id| !self.history.contains_key(&id)) {
+            message.reply_to = None;
+        }
+
+        self.archive.store(&message);
+
+        // Only what users say in public, so that integrations never see direct
+        // messages and cannot feed their own posts back to themselves.
+        if (message.is_broadcast() || message.is_room()) && self.names.contains_left(message.get_sender()) {
+            self.webhooks.notify(Event::Message {
+                id: message.id,
+                time: Utc::now().timestamp(),
+                sender: message.get_sender().to_owned(),
+                receiver: message.get_receiver().to_owned(),
+                message: message.get_message().to_owned(),
+                reply_to: message.reply_to
+            });
+        }
+
+        self.history.insert(message.id, message.clone());
+
+        // What the server says belongs to nobody, whoever goes by its name.
+        let owner = match message.get_sender() {
+            SERVER_NAME => None,
+            sender => self.owner(sender)
+        };
+
+        if let Some(owner) = owner {
+            self.owners.insert(message.id, owner);
+        }
+
+        while self.history.len() > MAX_TRACKED_MESSAGES {
+            if let Some((id, _)) = self.history.pop_first() {
+                self.reactions.remove(&id);
+                self.owners.remove(&id);
+            }
+        }
+
+        message
+    }
+
+    /// Everyone who currently gets to see the message.
+    fn audience(&self, message: &Message) -> Vec<String> {
+        if message.is_broadcast() {
+            return self.names.left_values().cloned().collect();
+        }
+
+        if message.is_room() {
+            return self.rooms
+                .get(message.get_receiver())
+                .map(|members| members.iter().cloned().collect())
+                .unwrap_or_default();
+        }
+
+        let mut audience = vec![message.get_sender().to_owned(), message.get_receiver().to_owned()];
+        audience.dedup();
+        audience
+    }
+
+    pub fn is_account(&self, name: &str) -> bool {
+        self.bot_tokens.contains_key(name) || self.moderators.contains_key(name)
+    }
+
+    fn owner(&self, name: &str) -> Option<Owner> {
+        match self.is_account(name) {
+            true => Some(Owner::Account(name.to_owned())),
+            false => self.sign_ins.get(name).map(|sign_in| Owner::SignIn(sign_in.serial))
+        }
+    }
+
+    /// Moderators have signed in with their token, as `may_sign_in` lets nobody else
+    /// use their names.
+    fn authorize(&mut self, name: &str, id: u64) -> Result<&mut Message, Error> {
+        let allowed = self.moderators.contains_key(name)
+            || self.owners.get(&id).is_some_and(|owner| self.owner(name).as_ref() == Some(owner));
+        let message = self.history.get_mut(&id).ok_or(Error::MessageNotFound)?;
+
+        match allowed {
+            true => Ok(message),
+            false => Err(Error::NotAuthorized)
+        }
+    }
+
+    /// Changes the text of a message for its whole audience.
+    /// Allowed for the original sender and for moderators.
+    pub fn edit(&mut self, name: &str, id: u64, text: &str) -> Result<(), Error> {
+        let message = self.authorize(name, id)?;
+        message.set_message(text);
+
+        let message = message.clone();
+
+        self.archive.edit(id, text);
+
+        let edited = Response::Edited { id, message: text.to_owned() };
+
+        for member in self.audience(&message) {
+            let _ignore = self.deliver(&member, edited.clone());
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(&mut self, name: &str, id: u64) -> Result<(), Error> {
+        self.authorize(name, id)?;
+
+        let message = self.history.remove(&id).ok_or(Error::MessageNotFound)?;
+        self.reactions.remove(&id);
+        self.owners.remove(&id);
+
+        self.archive.delete(id);
+
+        for member in self.audience(&message) {
+            let _ignore = self.deliver(&member, Response::Deleted { id });
+        }
+
+        Ok(())
+    }
+
+    /// Adds the reaction of `name` to a message, or takes it back if it was already there,
+    /// and sends the new totals to everyone who can see the message.
+    pub fn react(&mut self, name: &str, id: u64, reaction: &str) -> Result<(), Error> {
+        if reaction.is_empty() || reaction.len() > MAX_REACTION_LENGTH || reaction.contains(char::is_whitespace) {
+            return Err(Error::InvalidReaction);
+        }
+
+        let message = self.history.get(&id).ok_or(Error::MessageNotFound)?;
+        let audience = self.audience(message);
+
+        if !audience.iter().any(|member| member == name) {
+            return Err(Error::NotAuthorized);
+        }
+
+        let reactions = self.reactions.entry(id).or_default();
+        let users = reactions.entry(reaction.to_owned()).or_default();
+
+        if !users.remove(name) {
+            users.insert(name.to_owned());
+        }
+
+        reactions.retain(|_, users| !users.is_empty());
+
+        let reactions = reactions
+            .iter()
+            .map(|(reaction, users)| Reaction {
+                reaction: reaction.clone(),
+                users: users.iter().cloned().collect()
+            })
+            .collect();
+
+        let update = Response::Reactions { id, reactions };
+
+        for member in audience {
+            let _ignore = self.deliver(&member, update.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Where `name` can search and what it may find. Rooms count as visible while it is a
+    /// member, and only names of rooms do, so that joining a user's name finds nothing.
+    /// Without a token, what was sent to or from the name before it signed in belongs
+    /// to whoever had the name then.
+    pub fn search_scope(&self, name: &str) -> (Archiver, Scope) {
+        let rooms = self.rooms
+            .iter()
+            .filter(|(room, members)| room.starts_with(parser::ROOM_PREFIX) && members.contains(name))
+            .map(|(room, _)| room.clone())
+            .collect();
+
+        let after = match self.is_account(name) {
+            true => 0,
+            false => self.sign_ins.get(name).map_or(u64::MAX, |sign_in| sign_in.after)
+        };
+
+        (self.archive.clone(), Scope { name: name.to_owned(), rooms, after })
+    }
+
+    pub fn results(&mut self, name: &str, query: Query, total: u64, hits: Vec<Hit>) {
+        let _ignore = self.deliver(name, Response::Results { query, total, hits });
+    }
+
+    pub fn report(&mut self, name: &str, error: Error) {
+        let _ignore = self.deliver(name, Response::Error(error));
+    }
+
+    /// A frame from `name` that could not be decoded. Over UDP nobody is told.
+    pub fn decode_failed(&mut self, name: &str, protocol: Protocol) {
+        self.metrics.decode_errors += 1;
+
+        if protocol == Protocol::Tcp {
+            self.report(name, Error::InvalidRequest);
+        }
+    }
+
+    pub fn sessions(&self) -> Vec<Session> {
+        let mut sessions = self.names
+            .iter()
+            .map(|(name, address)| Session {
+                name: name.clone(),
+                address: *address,
+                status: self.statuses.get(name).copied().unwrap_or_default(),
+                rooms: self.rooms
+                    .iter()
+                    .filter(|(_, members)| members.contains(name))
+                    .map(|(room, _)| room.clone())
+                    .collect(),
+                queued: self.peers.get(address).map(Sender::depth).unwrap_or_default()
+            })
+            .collect::<Vec<_>>();
+
+        sessions.sort_by(|a, b| a.name.cmp(&b.name));
+        sessions
+    }
+
+    /// Responses waiting to be written, over all peers, and for the peer furthest behind.
+    pub fn queue_depths(&self) -> (usize, usize) {
+        self.peers
+            .values()
+            .map(Sender::depth)
+            .fold((0, 0), |(total, deepest), depth| (total + depth, deepest.max(depth)))
+    }
+
+    pub fn webhooks(&self) -> &Webhooks {
+        &self.webhooks
+    }
+
+    /// Direct messages are echoed back to the sender so that it learns the message id.
+    /// A message to nobody gets no id and goes nowhere, not even to the archive.
+    pub async fn send(&mut self, message: Message) -> Result<(), SendError> {
+        if message.is_room() {
+            return self.send_to_room(message);
+        }
+
+        if !self.names.contains_left(message.get_receiver()) {
+            return Err(SendError::UserNotFound);
+        }
+
+        let receiver = message.get_receiver().to_owned();
+        let sender = message.get_sender().to_owned();
+        let message = self.track(message);
+
+        self.deliver(&receiver, Response::Message(message.clone()))?;
+
+        if sender != receiver {
+            let _ignore = self.deliver(&sender, Response::Message(message));
+        }
+
+        Ok(())
+    }
+
+    fn send_to_room(&mut self, message: Message) -> Result<(), SendError> {
+        let members = self.rooms
+            .get(message.get_receiver())
+            .ok_or(SendError::UserNotFound)?;
+
+        if !members.contains(message.get_sender()) {
+            return Err(SendError::NotRoomMember);
+        }
+
+        let members = members.clone();
+        let message = self.track(message);
 
-        receiver
-            .send(message)
-            .or(Err(SendError::InternalChannelFailed))?;
+        for member in members {
+            let _ignore = self.deliver(&member, Response::Message(message.clone()));
+        }
 
         Ok(())
     }
 
+    pub async fn transfer(
+        &mut self, 
+        sender: &str, 
+        receiver: &str, 
+        transfer: Transfer, 
+        protocol: Protocol
+    ) -> Result<(), SendError> {
+        let response = Response::Transfer { 
+            peer: sender.to_owned(), 
+            transfer, 
+            protocol 
+        };
+
+        self.deliver(receiver, response)
+    }
+
+    /// Typing notifications are best effort, so delivery failures are not reported.
+    pub fn typing(&mut self, sender: &str, receiver: &str) {
+        let notification = Response::Typing {
+            sender: sender.to_owned(),
+            receiver: receiver.to_owned()
+        };
+
+        let receivers = match receiver {
+            parser::BROADCAST_NAME => self.names.left_values().cloned().collect(),
+            room if room.starts_with(parser::ROOM_PREFIX) => match self.rooms.get(room) {
+                Some(members) if members.contains(sender) => members.iter().cloned().collect(),
+                _ => vec![]
+            },
+            user => vec![user.to_owned()]
+        };
+
+        for name in receivers.iter().filter(|name| *name != sender) {
+            let _ignore = self.deliver(name, notification.clone());
+        }
+    }
+
+    /// Messages from the server or an integration, which is not a user and so
+    /// reaches rooms without being a member.
+    pub fn announce(&mut self, message: Message) -> Result<(), SendError> {
+        let receiver = message.get_receiver();
+
+        let known = match message.is_broadcast() || message.is_room() {
+            true => message.is_broadcast() || self.rooms.contains_key(receiver),
+            false => self.names.contains_left(receiver)
+        };
+
+        if !known {
+            return Err(SendError::UserNotFound);
+        }
+
+        let audience = self.audience(&message);
+        let message = Response::Message(self.track(message));
+
+        for name in audience {
+            let _ignore = self.deliver(&name, message.clone());
+        }
+
+        Ok(())
+    }
+
+    pub fn notify(&self, event: Event) {
+        self.webhooks.notify(event);
+    }
+
     pub async fn broadcast(&mut self, message: Message) -> Result<(), SendError> {
         // let sender = self.names
         //     .get_by_left(message.get_sender())
         //     .ok_or(SendError::UserNotFound)?
         //     .clone();
 
-        let message = Response::Message(message);
+        let message = Response::Message(self.track(message));
 
-        for (_, tx) in self.peers.iter_mut() {
-            let _ignore = tx.send(message.clone());
+        for tx in self.peers.values() {
+            if tx.send(message.clone()).is_err() {
+                self.metrics.dropped_sends += 1;
+            }
         }
 
         Ok(())
@@ -0,0 +1,87 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use socket2::Type;
+use tokio::{net::UdpSocket, sync::{Mutex, Semaphore}};
+
+use crate::common::{
+    codec::Encoding,
+    communication::{receive_udp_from, send_udp_to, MAX_DATAGRAM_SIZE, SESSION_TOKEN_LENGTH},
+    message::{Encode, Protocol, Request},
+};
+
+use super::{handle_request, socket, State};
+
+/// Requests from datagrams that are handled at once. Datagrams beyond that are dropped,
+/// as UDP may drop them anyway, rather than piling up tasks.
+const MAX_PENDING_REQUESTS: usize = 1024;
+
+/// The one UDP socket of the server, on the same address and port as the TCP listener.
+pub fn bind(address: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket(address, Type::DGRAM)?;
+    socket.bind(&address.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// Hands every datagram to the session whose token it starts with, in a task of its own.
+/// Anything else, such as datagrams with a forged sender address, is dropped unread
+/// before any task is started.
+pub async fn listen(state: Arc<Mutex<State>>, socket: Arc<UdpSocket>) -> io::Result<()> {
+    let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+    let pending = Arc::new(Semaphore::new(MAX_PENDING_REQUESTS));
+
+    loop {
+        // Errors about single datagrams, such as ICMP replies on some systems, concern one client only.
+        let Ok((length, source)) = receive_udp_from(&socket, &mut buffer).await else {
+            continue;
+        };
+
+        if length < SESSION_TOKEN_LENGTH {
+            continue;
+        }
+
+        let (token, frame) = buffer[..length].split_at_mut(SESSION_TOKEN_LENGTH);
+
+        let Some((name, encoding)) = state.lock().await.authenticate(token, source) else {
+            continue;
+        };
+
+        // The token alone only tells where the client is.
+        if frame.is_empty() {
+            continue;
+        }
+
+        let Ok(permit) = pending.clone().try_acquire_owned() else {
+            continue;
+        };
+
+        match encoding.decode::<Request>(frame) {
+            Ok(request) => {
+                let state = state.clone();
+
+                // However long a request takes, the next datagram is read right away.
+                tokio::spawn(async move {
+                    let _ignore = handle_request(&state, &name, request).await;
+                    drop(permit);
+                });
+            }
+            Err(_) => state.lock().await.decode_failed(&name, Protocol::Udp)
+        }
+    }
+}
+
+/// A dual-stack socket reaches IPv4 clients at their IPv4-mapped IPv6 address.
+pub async fn send<T: Encode>(socket: &UdpSocket, address: SocketAddr, encoding: Encoding, content: T) -> io::Result<()> {
+    let address = match (socket.local_addr()?, address) {
+        (SocketAddr::V6(_), SocketAddr::V4(address)) => {
+            SocketAddr::new(IpAddr::V6(address.ip().to_ipv6_mapped()), address.port())
+        }
+        _ => address
+    };
+
+    send_udp_to(socket, address, encoding, content).await
+}
@@ -0,0 +1,99 @@
+use std::{
+    fs::{self, DirBuilder, Permissions},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::{fs::{DirBuilderExt, FileTypeExt, PermissionsExt}, net::UnixStream},
+    path::Path,
+    sync::Arc,
+};
+
+use tokio::{
+    net::{UdpSocket, UnixListener},
+    sync::Mutex,
+};
+
+use crate::common::communication::{Reader, Writer};
+
+use super::{process, state::Address, State};
+
+/// The owner and group of the server can connect to the client socket,
+/// so access is granted by adding users to the group.
+pub const SOCKET_MODE: u32 = 0o660;
+
+/// Only the server can reach a socket while it is bound in here.
+const PRIVATE_DIRECTORY_MODE: u32 = 0o700;
+
+/// Binds a Unix socket that only those allowed by `mode` can connect to. A socket
+/// left behind by a server that did not shut down cleanly is replaced.
+///
+/// A socket is created with whatever mode the umask leaves, so it is bound in a
+/// private directory first and only moved to `path` once `mode` is set.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let stale = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+
+    if stale {
+        fs::remove_file(path)?;
+    }
+
+    let name = path.file_name().ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "The socket path has no file name"
+    ))?;
+
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    DirBuilder::new().mode(PRIVATE_DIRECTORY_MODE).create(&private)?;
+
+    let hidden = private.join("socket");
+    let bound = UnixListener::bind(&hidden)
+        .and_then(|listener| fs::set_permissions(&hidden, Permissions::from_mode(mode)).map(|()| listener))
+        .and_then(|listener| fs::rename(&hidden, path).map(|()| listener));
+
+    let _ignore = fs::remove_file(&hidden);
+    fs::remove_dir(&private)?;
+
+    bound
+}
+
+/// Serves native clients on the same host exactly as over TCP. They reach the
+/// UDP socket over the loopback interface, unless it is bound to one address only.
+pub async fn listen(state: Arc<Mutex<State>>, listener: UnixListener, udp: Arc<UdpSocket>) -> io::Result<()> {
+    let udp_address = match udp.local_addr()? {
+        address if address.ip().is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), address.port()),
+        address => address
+    };
+
+    for id in 0.. {
+        let (stream, _) = listener.accept().await?;
+
+        let state = state.clone();
+        let udp = udp.clone();
+        let (reader, writer) = stream.into_split();
+
+        tokio::spawn(async move {
+            let _ignore = process(state, Reader::new(reader), Writer::new(writer), udp, udp_address, Address::Unix(id)).await;
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sockets_get_their_mode_before_they_appear() {
+        let directory = std::env::temp_dir().join(format!("chat-unix-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("console.sock");
+
+        let _listener = bind(&path, 0o600).unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        assert!(UnixStream::connect(&path).is_ok());
+
+        let _ignore = fs::remove_dir_all(directory);
+    }
+}
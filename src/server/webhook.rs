@@ -0,0 +1,444 @@
+use std::{
+    io,
+    sync::{atomic::{AtomicU64, Ordering}, Arc},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, Mutex},
+    time::timeout,
+};
+
+use crate::{
+    client::{notification, parser},
+    common::{config::Webhook, message::SERVER_NAME},
+};
+
+use super::{
+    announce,
+    state::is_valid_name,
+    http::{self, Status, BAD_REQUEST, CONFLICT, LENGTH_REQUIRED, METHOD_NOT_ALLOWED, NOT_FOUND, OK, PAYLOAD_TOO_LARGE, UNAUTHORIZED},
+    is_token, State
+};
+
+/// Events waiting for a slow or unreachable webhook. Newer ones are dropped beyond that.
+const MAX_QUEUED_EVENTS: usize = 1024;
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_STATUS_LINE: u64 = 1024;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_BODY: usize = 256 * 1024;
+const BEARER: &str = "Bearer ";
+
+/// Something that happened in the chat, as POSTed to outgoing webhooks.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    Message {
+        id: u64,
+        time: i64,
+        sender: String,
+        receiver: String,
+        message: String,
+        reply_to: Option<u64>
+    },
+    /// `room` is `None` for signing in to the chat itself.
+    Join { user: String, room: Option<String> },
+    Leave { user: String, room: Option<String> }
+}
+
+struct Hook {
+    config: Webhook,
+    queue: mpsc::Sender<Vec<u8>>
+}
+
+impl Hook {
+    fn wants(&self, event: &Event) -> bool {
+        let Webhook { rooms, mentions, .. } = &self.config;
+
+        if rooms.is_empty() && mentions.is_empty() {
+            return true;
+        }
+
+        match event {
+            Event::Message { receiver, message, .. } => {
+                rooms.contains(receiver) || mentions.iter().any(|name| notification::mentions(message, name))
+            }
+            Event::Join { room, .. } | Event::Leave { room, .. } => {
+                room.as_ref().is_some_and(|room| rooms.contains(room))
+            }
+        }
+    }
+}
+
+/// The outgoing webhooks. Each one is served by its own task, so a slow endpoint
+/// neither holds up message routing nor the other webhooks, and gets events in order.
+pub struct Webhooks {
+    hooks: Vec<Hook>,
+    dropped: AtomicU64
+}
+
+impl Webhooks {
+    pub fn start(webhooks: Vec<Webhook>) -> Self {
+        let hooks = webhooks
+            .into_iter()
+            .map(|config| {
+                let (queue, events) = mpsc::channel(MAX_QUEUED_EVENTS);
+                tokio::spawn(deliver(config.url.clone(), events));
+
+                Hook { config, queue }
+            })
+            .collect();
+
+        Webhooks { hooks, dropped: AtomicU64::new(0) }
+    }
+
+    pub fn notify(&self, event: Event) {
+        let mut hooks = self.hooks.iter().filter(|hook| hook.wants(&event)).peekable();
+
+        if hooks.peek().is_none() {
+            return;
+        }
+
+        let Ok(body) = serde_json::to_vec(&event) else {
+            return;
+        };
+
+        for hook in hooks {
+            if hook.queue.try_send(body.clone()).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Webhook {} is falling behind, dropping an event", hook.config.url);
+            }
+        }
+    }
+
+    /// Events waiting to be POSTed, over all webhooks.
+    pub fn queued(&self) -> usize {
+        self.hooks
+            .iter()
+            .map(|hook| hook.queue.max_capacity() - hook.queue.capacity())
+            .sum()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn deliver(url: String, mut events: mpsc::Receiver<Vec<u8>>) {
+    while let Some(body) = events.recv().await {
+        match timeout(POST_TIMEOUT, post(&url, &body)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(reason)) => eprintln!("Webhook {url} failed: {reason}"),
+            Err(_) => eprintln!("Webhook {url} timed out")
+        }
+    }
+}
+
+/// A bare HTTP/1.1 POST, which is all a webhook needs. Only the status code of the reply matters.
+async fn post(url: &str, body: &[u8]) -> io::Result<()> {
+    let rest = url.strip_prefix(Webhook::SCHEME).unwrap_or(url);
+
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "/")
+    };
+
+    let address = match authority.ends_with(']') || !authority.contains(':') {
+        true => format!("{authority}:80"),
+        false => authority.to_owned()
+    };
+
+    let mut stream = TcpStream::connect(address).await?;
+
+    let head = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nUser-Agent: chat\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut status = String::new();
+    BufReader::new(stream).take(MAX_STATUS_LINE).read_line(&mut status).await?;
+
+    let code = status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP response"))?;
+
+    match code {
+        200..=299 => Ok(()),
+        code => Err(io::Error::other(format!("HTTP status {code}")))
+    }
+}
+
+/// A message injected through the incoming endpoint. Without a sender it comes from
+/// the server, and without a receiver it goes to everyone.
+#[derive(Debug, Deserialize)]
+struct Post {
+    text: String,
+    sender: Option<String>,
+    receiver: Option<String>
+}
+
+/// Accepts `POST`s carrying `Authorization: Bearer <token>` and a JSON body such as
+/// `{"text": "Build 42 failed", "sender": "ci", "receiver": "#ops"}`.
+pub async fn listen(state: Arc<Mutex<State>>, listener: TcpListener, token: String) -> io::Result<()> {
+    let token = Arc::new(token);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let state = state.clone();
+        let token = token.clone();
+
+        tokio::spawn(async move {
+            let _ignore = serve(state, stream, &token).await;
+        });
+    }
+}
+
+async fn serve(state: Arc<Mutex<State>>, mut stream: TcpStream, token: &str) -> io::Result<()> {
+    let status = match timeout(REQUEST_TIMEOUT, read_post(&mut stream, token)).await {
+        Err(_) => return Ok(()),
+        Ok(Err(status)) => status,
+        Ok(Ok(post)) => inject(&state, post).await
+    };
+
+    http::respond(&mut stream, status, "text/plain", "").await
+}
+
+async fn read_post(stream: &mut TcpStream, token: &str) -> Result<Post, Status> {
+    let mut buffer = Vec::new();
+    let head = http::read_head(stream, &mut buffer).await?;
+
+    let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    request.parse(&buffer).or(Err(BAD_REQUEST))?;
+
+    if request.method != Some("POST") {
+        return Err(METHOD_NOT_ALLOWED);
+    }
+
+    let authorized = http::header(&request, "authorization")
+        .and_then(|value| value.strip_prefix(BEARER.as_bytes()))
+        .is_some_and(|given| is_token(given, token));
+
+    if !authorized {
+        return Err(UNAUTHORIZED);
+    }
+
+    let length = http::header(&request, "content-length")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| value.trim().parse::<usize>().ok())
+        .ok_or(LENGTH_REQUIRED)?;
+
+    if length > MAX_REQUEST_BODY {
+        return Err(PAYLOAD_TOO_LARGE);
+    }
+
+    let mut body = buffer[head..].to_vec();
+
+    while body.len() < length {
+        http::read_more(stream, &mut body).await?;
+    }
+
+    body.truncate(length);
+
+    serde_json::from_slice(&body).or(Err(BAD_REQUEST))
+}
+
+/// Posts the message like a server announcement. An integration may not speak as
+/// someone who is signed in, nor as an account, which would then own the message.
+async fn inject(state: &Mutex<State>, post: Post) -> Status {
+    let sender = post.sender.as_deref().unwrap_or(SERVER_NAME);
+    let receiver = post.receiver.as_deref().unwrap_or(parser::BROADCAST_NAME);
+
+    if post.text.trim().is_empty() || (sender != SERVER_NAME && !is_valid_name(sender)) {
+        return BAD_REQUEST;
+    }
+
+    {
+        let state = state.lock().await;
+
+        if post.text.len() > state.max_message_size {
+            return PAYLOAD_TOO_LARGE;
+        }
+
+        if state.is_signed_in(sender) || state.is_account(sender) {
+            return CONFLICT;
+        }
+    }
+
+    match announce(state, sender, receiver, &post.text).await {
+        Ok(()) => OK,
+        Err(_) => NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        net::SocketAddr,
+    };
+
+    use serde_json::{json, Value};
+
+    use crate::common::message::Response;
+
+    use super::{super::{state::Address, tests::state}, *};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    const TOKEN: &str = "secret";
+
+    fn message(sender: &str, receiver: &str, message: &str) -> Event {
+        Event::Message {
+            id: 1,
+            time: 1_700_000_000,
+            sender: sender.to_owned(),
+            receiver: receiver.to_owned(),
+            message: message.to_owned(),
+            reply_to: None
+        }
+    }
+
+    /// Answers one POST with `200 OK` and returns its path and JSON body.
+    async fn receive(listener: &TcpListener) -> (String, Value) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        reader.read_line(&mut line).await.unwrap();
+        let path = line.split_whitespace().nth(1).unwrap().to_owned();
+        let mut length = 0;
+
+        loop {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+
+            match line.trim_end().split_once(':') {
+                Some((name, value)) if name.eq_ignore_ascii_case("content-length") => length = value.trim().parse().unwrap(),
+                Some(_) => continue,
+                None => break
+            }
+        }
+
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).await.unwrap();
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+
+        (path, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// Sends a request to the incoming endpoint and returns the status code of the answer.
+    async fn request(server: SocketAddr, token: &str, length: usize, body: &str) -> u16 {
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let head = format!("POST / HTTP/1.1\r\nAuthorization: Bearer {token}\r\nContent-Length: {length}\r\n\r\n");
+
+        stream.write_all(head.as_bytes()).await.unwrap();
+        let _ignore = stream.write_all(body.as_bytes()).await;
+
+        let mut status = String::new();
+        timeout(TIMEOUT, BufReader::new(stream).read_line(&mut status)).await.unwrap().unwrap();
+
+        status.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn events_are_posted_to_the_webhooks_that_want_them() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let webhooks = Webhooks::start(vec![
+            Webhook { url: format!("{url}/all"), rooms: BTreeSet::new(), mentions: BTreeSet::new() },
+            Webhook {
+                url: format!("{url}/ops"),
+                rooms: BTreeSet::from([String::from("#ops")]),
+                mentions: BTreeSet::from([String::from("ana")])
+            }
+        ]);
+
+        webhooks.notify(message("bob", "#dev", "hello"));
+        webhooks.notify(message("bob", "#dev", "hey @ana"));
+        webhooks.notify(Event::Join { user: String::from("cat"), room: Some(String::from("#ops")) });
+        webhooks.notify(Event::Leave { user: String::from("cat"), room: None });
+
+        let mut posted = BTreeMap::<String, Vec<Value>>::new();
+
+        for _ in 0..6 {
+            let (path, body) = timeout(TIMEOUT, receive(&listener)).await.unwrap();
+            posted.entry(path).or_default().push(body);
+        }
+
+        assert_eq!(posted["/all"][0], json!({
+            "event": "message",
+            "id": 1,
+            "time": 1_700_000_000,
+            "sender": "bob",
+            "receiver": "#dev",
+            "message": "hello",
+            "reply_to": null
+        }));
+        assert_eq!(posted["/all"][3], json!({ "event": "leave", "user": "cat", "room": null }));
+        assert_eq!(posted["/ops"], [
+            serde_json::to_value(message("bob", "#dev", "hey @ana")).unwrap(),
+            json!({ "event": "join", "user": "cat", "room": "#ops" })
+        ]);
+    }
+
+    #[tokio::test]
+    async fn posts_need_the_token_a_small_body_and_a_free_sender() {
+        let state = Arc::new(state().await);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+        let mut alice = state.lock().await.register(Address::Unix(1), "alice");
+
+        tokio::spawn(listen(state.clone(), listener, TOKEN.to_owned()));
+
+        let body = r#"{"text": "Build 42 failed", "sender": "ci", "receiver": "alice"}"#;
+        assert_eq!(request(server, "wrong", body.len(), body).await, 401);
+        assert_eq!(request(server, TOKEN, MAX_REQUEST_BODY + 1, "").await, 413);
+
+        let taken = r#"{"text": "hi", "sender": "alice"}"#;
+        assert_eq!(request(server, TOKEN, taken.len(), taken).await, 409);
+
+        let invalid = r#"{"text": "hi", "sender": "a:b"}"#;
+        assert_eq!(request(server, TOKEN, invalid.len(), invalid).await, 400);
+
+        assert_eq!(request(server, TOKEN, body.len(), body).await, 200);
+
+        match alice.recv().await {
+            Some(Response::Message(message)) => {
+                assert_eq!((message.get_sender(), message.get_message()), ("ci", "Build 42 failed"));
+            }
+            response => panic!("expected the post, got {response:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_cannot_speak_for_an_offline_account() {
+        let state = Arc::new(state().await);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = listener.local_addr().unwrap();
+
+        let mut alice = state.lock().await.register(Address::Unix(1), "alice");
+
+        tokio::spawn(listen(state.clone(), listener, TOKEN.to_owned()));
+
+        let forged = r#"{"text": "You are all banned", "sender": "mod"}"#;
+        assert_eq!(request(server, TOKEN, forged.len(), forged).await, 409);
+
+        let genuine = r#"{"text": "Build 42 passed", "sender": "ci"}"#;
+        assert_eq!(request(server, TOKEN, genuine.len(), genuine).await, 200);
+
+        match alice.recv().await {
+            Some(Response::Message(message)) => assert_eq!(message.get_sender(), "ci"),
+            response => panic!("expected the genuine post, got {response:?}")
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    time::sleep,
+};
+use tokio_tungstenite::{
+    accept_async_with_config,
+    tungstenite::{protocol::WebSocketConfig, Message as Frame},
+    WebSocketStream,
+};
+
+use crate::common::{
+    communication::MAX_FRAME_SIZE,
+    message::{self, Protocol, Request, Response},
+};
+
+use super::{disconnect, handle_request, http, join, state::Receiver, State};
+
+/// The web client, served to plain HTTP requests on the WebSocket listener.
+const PAGE: &str = include_str!("web/index.html");
+
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const HEAD_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const HEAD_POLL_ATTEMPTS: usize = 100;
+
+type Socket = WebSocketStream<TcpStream>;
+
+/// Accepts browser connections. Requests and responses are the same as over TCP,
+/// encoded as JSON text frames exactly like the `json` codec, minus the newline.
+pub async fn listen(state: Arc<Mutex<State>>, listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let _ignore = serve(state, stream, address).await;
+        });
+    }
+}
+
+/// Looks at the HTTP request head without consuming it, so that the WebSocket
+/// handshake can still read it. Waits a little for heads split over several packets.
+async fn is_upgrade(stream: &TcpStream) -> io::Result<bool> {
+    let mut buffer = vec![0; MAX_REQUEST_HEAD];
+
+    for _ in 0..HEAD_POLL_ATTEMPTS {
+        let read = stream.peek(&mut buffer).await?;
+
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+        let mut request = httparse::Request::new(&mut headers);
+
+        match request.parse(&buffer[..read]).map_err(|reason| io::Error::new(io::ErrorKind::InvalidData, reason))? {
+            httparse::Status::Complete(_) => return Ok(wants_websocket(&request)),
+            httparse::Status::Partial if read == buffer.len() => return Err(io::ErrorKind::InvalidData.into()),
+            httparse::Status::Partial => sleep(HEAD_POLL_INTERVAL).await
+        }
+    }
+
+    Err(io::ErrorKind::TimedOut.into())
+}
+
+/// Whether `websocket` is one of the protocols in the `Upgrade` header.
+fn wants_websocket(request: &httparse::Request) -> bool {
+    http::header(request, "upgrade")
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .is_some_and(|value| value.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket")))
+}
+
+async fn send_page(mut stream: TcpStream) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{PAGE}",
+        PAGE.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn serve(state: Arc<Mutex<State>>, stream: TcpStream, address: SocketAddr) -> io::Result<()> {
+    if !is_upgrade(&stream).await? {
+        return send_page(stream).await;
+    }
+
+    let config = WebSocketConfig::default().max_message_size(Some(MAX_FRAME_SIZE));
+    let local = stream.local_addr()?;
+
+    let socket = accept_async_with_config(stream, Some(config))
+        .await
+        .map_err(io::Error::other)?;
+
+    process(state, socket, address, local).await
+}
+
+async fn send(socket: &mut Socket, response: &Response) -> io::Result<()> {
+    let text = serde_json::to_string(response)?;
+    socket.send(Frame::text(text)).await.map_err(io::Error::other)
+}
+
+/// Next request from the browser. `None` once the connection is closed or broken.
+async fn receive(socket: &mut Socket) -> Option<Result<Request, serde_json::Error>> {
+    loop {
+        match socket.next().await? {
+            Ok(Frame::Text(text)) => return Some(serde_json::from_str(text.as_str())),
+            Ok(Frame::Close(_)) | Err(_) => return None,
+            Ok(_) => continue
+        }
+    }
+}
+
+/// The first frame has to be a `SignIn` or `SignInBot`. Its UDP address is ignored, as
+/// everything goes over the WebSocket; the reply carries the address of the listener instead.
+async fn sign_in(
+    state: &Mutex<State>,
+    socket: &mut Socket,
+    address: SocketAddr,
+    local: SocketAddr
+) -> io::Result<(String, Receiver)> {
+    let (name, token) = match receive(socket).await {
+        Some(Ok(Request::SignIn { name, .. })) => (name, None),
+        Some(Ok(Request::SignInBot { name, token, .. })) => (name, Some(token)),
+        Some(_) => {
+            send(socket, &Response::Error(message::Error::InvalidName)).await?;
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid handshake request"));
+        }
+        None => return Err(io::ErrorKind::ConnectionAborted.into())
+    };
+
+    let Ok(internal_rx) = state.lock().await.sign_in(address.into(), &name, token.as_deref()) else {
+        send(socket, &Response::Error(message::Error::InvalidName)).await?;
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message::Error::InvalidName));
+    };
+
+    if let Err(reason) = send(socket, &Response::Ok(local)).await {
+        state.lock().await.remove(&name, &address.into());
+        return Err(reason);
+    }
+
+    Ok((name, internal_rx))
+}
+
+async fn process(
+    state: Arc<Mutex<State>>,
+    mut socket: Socket,
+    address: SocketAddr,
+    local: SocketAddr
+) -> io::Result<()> {
+    let (name, mut internal_rx) = sign_in(&state, &mut socket, address, local).await?;
+
+    println!("{} @ {} connected over WebSocket", name, address);
+
+    join(&state, &name).await?;
+
+    loop {
+        tokio::select! {
+            response = internal_rx.recv() => {
+                let Some(response) = response else {
+                    break;
+                };
+
+                if send(&mut socket, &response).await.is_err() {
+                    break;
+                }
+            }
+
+            request = receive(&mut socket) => match request {
+                Some(Ok(Request::SignOut)) | None => break,
+                Some(Err(_)) => {
+                    state.lock().await.decode_failed(&name, Protocol::Tcp);
+                }
+                Some(Ok(request)) => {
+                    let _ignore = handle_request(&state, &name, request).await;
+                }
+            }
+        }
+    }
+
+    internal_rx.close();
+    disconnect(&state, &name, &address.into()).await?;
+
+    let _ignore = socket.close(None).await;
+
+    println!("{} @ {} disconnected", name, address);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, BufReader},
+        time::timeout,
+    };
+    use tokio_tungstenite::client_async;
+
+    use crate::common::message::Message;
+
+    use super::{super::{state::Address, tests::state}, *};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Starts a WebSocket listener on a free loopback port and returns it with the state it shares.
+    async fn start_server() -> (SocketAddr, Arc<Mutex<State>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let state = Arc::new(state().await);
+
+        tokio::spawn(listen(state.clone(), listener));
+
+        (address, state)
+    }
+
+    async fn connect(server: SocketAddr) -> Socket {
+        let stream = TcpStream::connect(server).await.unwrap();
+        let (socket, _) = client_async(format!("ws://{server}/"), stream).await.unwrap();
+
+        socket
+    }
+
+    async fn request(socket: &mut Socket, request: &Request) {
+        socket.send(Frame::text(serde_json::to_string(request).unwrap())).await.unwrap();
+    }
+
+    /// Skips responses until one satisfies `wanted`.
+    async fn expect(socket: &mut Socket, wanted: impl Fn(&Response) -> bool) -> Response {
+        let found = timeout(TIMEOUT, async {
+            loop {
+                match socket.next().await {
+                    Some(Ok(Frame::Text(text))) => {
+                        let response = serde_json::from_str(text.as_str()).unwrap();
+
+                        if wanted(&response) {
+                            return response;
+                        }
+                    }
+                    Some(Ok(_)) => continue,
+                    frame => panic!("connection ended with {frame:?}")
+                }
+            }
+        });
+
+        found.await.expect("timed out waiting for a response")
+    }
+
+    async fn sign_in(server: SocketAddr, name: &str) -> Socket {
+        let mut socket = connect(server).await;
+        let udp = "0.0.0.0:0".parse().unwrap();
+
+        request(&mut socket, &Request::SignIn { name: name.to_owned(), udp }).await;
+        assert_eq!(expect(&mut socket, |_| true).await, Response::Ok(server));
+
+        socket
+    }
+
+    #[test]
+    fn only_the_upgrade_header_asks_for_a_websocket() {
+        let wants = |head: &str| {
+            let mut headers = [httparse::EMPTY_HEADER; http::MAX_HEADERS];
+            let mut request = httparse::Request::new(&mut headers);
+            request.parse(head.as_bytes()).unwrap();
+
+            wants_websocket(&request)
+        };
+
+        assert!(wants("GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"));
+        assert!(wants("GET / HTTP/1.1\r\nUpgrade:websocket\r\n\r\n"));
+        assert!(wants("GET / HTTP/1.1\r\nupgrade: WebSocket, foo\r\n\r\n"));
+        assert!(!wants("GET / HTTP/1.1\r\nUpgrade: h2c\r\n\r\n"));
+        assert!(!wants("GET / HTTP/1.1\r\nReferer: http://a/?upgrade: websocket\r\n\r\n"));
+        assert!(!wants("GET /upgrade:%20websocket HTTP/1.1\r\nHost: a\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn plain_requests_get_the_page() {
+        let (server, _) = start_server().await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        let mut rest = String::new();
+
+        timeout(TIMEOUT, reader.read_line(&mut status)).await.unwrap().unwrap();
+        timeout(TIMEOUT, reader.read_to_string(&mut rest)).await.unwrap().unwrap();
+
+        assert_eq!(status.trim_end(), "HTTP/1.1 200 OK");
+        assert!(rest.ends_with(PAGE));
+    }
+
+    #[tokio::test]
+    async fn the_first_frame_has_to_sign_in() {
+        let (server, state) = start_server().await;
+        let mut socket = connect(server).await;
+
+        request(&mut socket, &Request::SignOut).await;
+        assert_eq!(expect(&mut socket, |_| true).await, Response::Error(message::Error::InvalidName));
+        assert_eq!(state.lock().await.user_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn messages_go_between_web_and_native_clients() {
+        let (server, state) = start_server().await;
+        let mut native = state.lock().await.sign_in(Address::Unix(1), "bob", None).unwrap();
+        let mut web = sign_in(server, "web").await;
+
+        let send = Request::Send {
+            receiver: String::from("bob"),
+            message: String::from("from the browser"),
+            protocol: Protocol::Tcp,
+            reply_to: None
+        };
+        request(&mut web, &send).await;
+
+        let received = timeout(TIMEOUT, async {
+            loop {
+                match native.recv().await {
+                    Some(Response::Message(message)) if message.get_sender() == "web" => return message,
+                    Some(_) => continue,
+                    None => panic!("bob was signed out")
+                }
+            }
+        });
+        assert_eq!(received.await.unwrap().get_message(), "from the browser");
+
+        let reply = Message::new("from the terminal", "bob", "web", Protocol::Tcp);
+        state.lock().await.send(reply).await.unwrap();
+
+        let from_bob = |response: &Response| matches!(response, Response::Message(message) if message.get_sender() == "bob");
+        let Response::Message(message) = expect(&mut web, from_bob).await else {
+            unreachable!();
+        };
+        assert_eq!(message.get_message(), "from the terminal");
+    }
+
+    #[tokio::test]
+    async fn malformed_json_is_reported_and_the_connection_stays_up() {
+        let (server, state) = start_server().await;
+        let mut web = sign_in(server, "web").await;
+
+        web.send(Frame::text("{\"Send\": {\"receiver\": ")).await.unwrap();
+        let error = expect(&mut web, |response| matches!(response, Response::Error(_))).await;
+        assert_eq!(error, Response::Error(message::Error::InvalidRequest));
+
+        request(&mut web, &Request::Join { room: String::from("#web") }).await;
+        expect(&mut web, |response| matches!(response, Response::Presence(presence) if !presence.rooms.is_empty())).await;
+        assert_eq!(state.lock().await.user_count(), 1);
+    }
+}